@@ -22,14 +22,15 @@ use mio_extras::channel::{
     channel as async_channel, Receiver as AsyncReceiver, Sender as AsyncSender,
 };
 use std::io;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::os::unix::io::RawFd;
 use std::sync::Arc;
-use utp::{UtpCallbackArgs, UtpCallbackType, UtpContext, UtpError, UtpSocket, UtpState};
+use std::time::Duration;
+use utp::{addr_for_socket, UtpCallbackArgs, UtpCallbackType, UtpContext, UtpSocket, UtpState};
 
 #[derive(Debug)]
 struct CliArgs {
-    listen_mode: bool,
+    ipv6: bool,
     port: Option<u16>,
     buffer_size: usize,
     target_addr: Option<SocketAddr>,
@@ -44,10 +45,11 @@ fn main() -> io::Result<()> {
     };
 
     if let Some(listen_port) = args.port {
-        run_server(listen_port, args.buffer_size)?;
+        run_server(listen_port, args.ipv6, args.buffer_size)?;
     } else {
-        let mut client = UtpClient::new(args.buffer_size)?;
-        client.run(unwrap!(args.target_addr))?;
+        let target_addr = unwrap!(args.target_addr);
+        let mut client = UtpClient::new(args.buffer_size, target_addr.is_ipv6())?;
+        client.run(target_addr)?;
     }
 
     Ok(())
@@ -56,27 +58,33 @@ fn main() -> io::Result<()> {
 struct MioEventHandlers {
     connected_rx: AsyncReceiver<()>,
     utp_writable_rx: AsyncReceiver<()>,
+    destroyed_rx: AsyncReceiver<bool>,
 }
 
 struct ClientData {
     udp_socket: Arc<UdpSocket>,
     connected_tx: AsyncSender<()>,
     utp_writable_tx: AsyncSender<()>,
+    /// Tells if all data was delivered, once the closed socket is destroyed.
+    destroyed_tx: AsyncSender<bool>,
 }
 
 impl ClientData {
     fn new(udp_socket: Arc<UdpSocket>) -> (Self, MioEventHandlers) {
         let (connected_tx, connected_rx) = async_channel();
         let (utp_writable_tx, utp_writable_rx) = async_channel();
+        let (destroyed_tx, destroyed_rx) = async_channel();
         (
             Self {
                 udp_socket,
                 connected_tx,
                 utp_writable_tx,
+                destroyed_tx,
             },
             MioEventHandlers {
                 connected_rx,
                 utp_writable_rx,
+                destroyed_rx,
             },
         )
     }
@@ -87,6 +95,7 @@ const STDIN_TOKEN: Token = Token(0);
 const SOCKET_TOKEN: Token = Token(1);
 const CONNECTED_RX_TOKEN: Token = Token(2);
 const UTP_WRITABLE_TOKEN: Token = Token(3);
+const DESTROYED_TOKEN: Token = Token(4);
 
 struct UtpClient {
     evloop: Poll,
@@ -94,21 +103,27 @@ struct UtpClient {
     utp: UtpContext<ClientData>,
     event_handlers: MioEventHandlers,
     buf: Vec<u8>,
-    buf_bytes_sent: usize,
-    buf_bytes_read: usize,
     evented_stdin: EventedFd<'static>,
+    /// Set while the socket's write queue is full and stdin is not polled.
+    stdin_paused: bool,
+    /// Set once the closed socket is destroyed: `true`, if the server received all the data.
+    delivered: Option<bool>,
 }
 
 impl UtpClient {
-    fn new(buffer_size: usize) -> io::Result<Self> {
+    fn new(buffer_size: usize, ipv6: bool) -> io::Result<Self> {
         let evloop = Poll::new()?;
 
-        let udp_socket = Arc::new(UdpSocket::bind(&addr!("0.0.0.0:0"))?);
+        let bind_addr = if ipv6 {
+            addr!("[::]:0")
+        } else {
+            addr!("0.0.0.0:0")
+        };
+        let udp_socket = Arc::new(UdpSocket::bind(&bind_addr)?);
         let (client_data, event_handlers) = ClientData::new(Arc::clone(&udp_socket));
         let utp = make_client_utp_ctx(client_data);
 
-        let mut buf: Vec<u8> = Vec::with_capacity(buffer_size);
-        unsafe { buf.set_len(buffer_size) }
+        let buf = vec![0u8; buffer_size];
 
         const STDIN_FD: RawFd = 0;
         let evented_stdin = EventedFd(&STDIN_FD);
@@ -119,38 +134,65 @@ impl UtpClient {
             utp,
             event_handlers,
             buf,
-            buf_bytes_read: 0,
-            buf_bytes_sent: 0,
             evented_stdin,
+            stdin_paused: false,
+            delivered: None,
         })
     }
 
-    /// Runs main event loop.
+    /// Runs main event loop until all data from stdin is sent and the connection is closed.
     fn run(&mut self, server_addr: SocketAddr) -> io::Result<()> {
         self.register_events()?;
         let utp_socket = unwrap!(self.utp.connect(server_addr));
+        // stop reading stdin while the server can't keep up
+        let buffer_size = self.buf.len();
+        unwrap!(utp_socket.set_write_queue_limits(buffer_size, buffer_size / 2));
 
         let mut events = Events::with_capacity(1024);
         loop {
-            self.evloop.poll(&mut events, None)?;
+            self.evloop.poll(&mut events, self.utp.next_timeout())?;
             for ev in events.iter() {
                 self.handle_event(ev, &utp_socket)?;
             }
-            // TODO(povilas): do this every 500 ms V
-            self.utp.check_timeouts();
+            if self.utp.next_timeout() == Some(Duration::from_secs(0)) {
+                self.utp.check_timeouts();
+            }
+            match self.delivered {
+                Some(true) => return Ok(()),
+                Some(false) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "server did not receive all the data",
+                    ))
+                }
+                None => (),
+            }
         }
     }
 
-    fn on_stdin(&mut self, utp_socket: &UtpSocket) {
-        // read more data into input buffer beyond where it was read last time
-        let bytes_read = unwrap!(nix::unistd::read(0, &mut self.buf[self.buf_bytes_read..]));
-        if bytes_read > 0 {
-            self.buf_bytes_read += bytes_read;
-        } else if bytes_read == 0 && self.buf_bytes_read < self.buf.len() {
+    fn on_stdin(&mut self, utp_socket: &UtpSocket) -> io::Result<()> {
+        let bytes_read = unwrap!(nix::unistd::read(0, &mut self.buf));
+        if bytes_read == 0 {
             println!("stdin EOF");
-            unwrap!(self.evloop.deregister(&self.evented_stdin));
+            self.evloop.deregister(&self.evented_stdin)?;
+            // the data still queued is written before FIN, so nothing is lost
+            utp_socket.close();
+            return Ok(());
+        }
+        if !unwrap!(utp_socket.write_all(&self.buf[..bytes_read])) {
+            self.evloop.deregister(&self.evented_stdin)?;
+            self.stdin_paused = true;
         }
-        self.flush_input_buffer(utp_socket);
+        Ok(())
+    }
+
+    fn register_stdin(&self) -> io::Result<()> {
+        self.evloop.register(
+            &self.evented_stdin,
+            STDIN_TOKEN,
+            Ready::readable(),
+            PollOpt::level(),
+        )
     }
 
     fn handle_event(&mut self, event: mio::Event, utp_socket: &UtpSocket) -> io::Result<()> {
@@ -160,19 +202,19 @@ impl UtpClient {
         match event.token() {
             UTP_WRITABLE_TOKEN => {
                 unwrap!(self.event_handlers.utp_writable_rx.try_recv());
-                self.flush_input_buffer(&utp_socket);
+                // the write queue drained to its low-water mark
+                if self.stdin_paused {
+                    self.stdin_paused = false;
+                    self.register_stdin()?;
+                }
             }
             SOCKET_TOKEN => handle_udp(&self.udp_socket, &mut sock_buf[..], &self.utp)?,
-            CONNECTED_RX_TOKEN => {
-                // We're only interested in stdin, when we are connected with the server
-                self.evloop.register(
-                    &self.evented_stdin,
-                    STDIN_TOKEN,
-                    Ready::readable(),
-                    PollOpt::level(),
-                )?;
+            // We're only interested in stdin, when we are connected with the server
+            CONNECTED_RX_TOKEN => self.register_stdin()?,
+            STDIN_TOKEN => self.on_stdin(utp_socket)?,
+            DESTROYED_TOKEN => {
+                self.delivered = Some(unwrap!(self.event_handlers.destroyed_rx.try_recv()));
             }
-            STDIN_TOKEN => self.on_stdin(utp_socket),
             _ => panic!("Unexpected mio token polled"),
         }
 
@@ -198,27 +240,14 @@ impl UtpClient {
             Ready::readable(),
             PollOpt::level(),
         )?;
+        self.evloop.register(
+            &self.event_handlers.destroyed_rx,
+            DESTROYED_TOKEN,
+            Ready::readable(),
+            PollOpt::edge(),
+        )?;
         Ok(())
     }
-
-    fn flush_input_buffer(&mut self, utp_socket: &UtpSocket) {
-        loop {
-            match utp_socket.send(&self.buf[self.buf_bytes_sent..self.buf_bytes_read]) {
-                Ok(bytes_sent) => self.buf_bytes_sent += bytes_sent,
-                Err(UtpError::WouldBlock) => break,
-                Err(UtpError::SendFailed) => panic!("uTP socket send failed"),
-                Err(UtpError::UnexpectedResult(res)) => {
-                    panic!("Unknown send return value: {}", res)
-                }
-                Err(e) => panic!("Unexpected error: {:?}", e),
-            }
-        }
-        if self.buf_bytes_sent == self.buf_bytes_read {
-            // resent the counters because everything has been flushed
-            self.buf_bytes_sent = 0;
-            self.buf_bytes_read = 0;
-        }
-    }
 }
 
 fn make_client_utp_ctx(data: ClientData) -> UtpContext<ClientData> {
@@ -226,13 +255,18 @@ fn make_client_utp_ctx(data: ClientData) -> UtpContext<ClientData> {
     utp.set_callback(
         UtpCallbackType::Sendto,
         Box::new(|args| {
-            let addr = unwrap!(args.address());
             let client_data = args.user_data();
+            let addr = addr_for_socket(
+                unwrap!(args.address()),
+                &unwrap!(client_data.udp_socket.local_addr()),
+            );
             match client_data.udp_socket.send_to(args.buf(), &addr) {
                 Ok(bytes_sent) => assert_eq!(args.buf().len(), bytes_sent),
-                Err(e) => if e.kind() != io::ErrorKind::WouldBlock {
-                    panic!("UDP error: {}", e);
-                },
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        panic!("UDP error: {}", e);
+                    }
+                }
             }
             0
         }),
@@ -241,13 +275,16 @@ fn make_client_utp_ctx(data: ClientData) -> UtpContext<ClientData> {
         UtpCallbackType::OnStateChange,
         Box::new(|args| {
             debug!("state: {:?}", args.state());
-            match args.state() {
+            match unwrap!(args.state()) {
                 UtpState::Connected => {
                     unwrap!(args.user_data().connected_tx.send(()));
                 }
                 UtpState::Writable => {
                     unwrap!(args.user_data().utp_writable_tx.send(()));
                 }
+                UtpState::Destroying => {
+                    unwrap!(args.user_data().destroyed_tx.send(args.delivered()));
+                }
                 _ => {}
             }
             0
@@ -257,15 +294,18 @@ fn make_client_utp_ctx(data: ClientData) -> UtpContext<ClientData> {
 }
 
 /// Runs the server that receives uTP packets and prints them to stdout.
-fn run_server(listen_port: u16, buffer_size: usize) -> io::Result<()> {
-    let socket = UdpSocket::bind(&SocketAddr::V4(SocketAddrV4::new(
-        Ipv4Addr::new(0, 0, 0, 0),
-        listen_port,
-    )))?;
+/// If `ipv6` is set, the server listens on dual-stack socket accepting both IPv4 and IPv6
+/// connections.
+fn run_server(listen_port: u16, ipv6: bool, buffer_size: usize) -> io::Result<()> {
+    let listen_ip: IpAddr = if ipv6 {
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0).into()
+    } else {
+        Ipv4Addr::new(0, 0, 0, 0).into()
+    };
+    let socket = UdpSocket::bind(&SocketAddr::new(listen_ip, listen_port))?;
     let utp = make_server_utp_ctx(socket); // UDP socket must be accessible from uTP callbacks
     let socket = utp.user_data();
-    let mut buf: Vec<u8> = Vec::with_capacity(buffer_size);
-    unsafe { buf.set_len(buffer_size) }
+    let mut buf = vec![0u8; buffer_size];
 
     let evloop = Poll::new()?;
     evloop.register(
@@ -280,7 +320,7 @@ fn run_server(listen_port: u16, buffer_size: usize) -> io::Result<()> {
         evloop.poll(&mut events, None)?;
         for ev in events.iter() {
             match ev.token() {
-                SOCKET_TOKEN => handle_udp(&socket, &mut buf[..], &utp)?,
+                SOCKET_TOKEN => handle_udp(socket, &mut buf[..], &utp)?,
                 _ => panic!("Unexpected mio token polled"),
             }
         }
@@ -330,6 +370,7 @@ fn make_server_utp_ctx(data: UdpSocket) -> UtpContext<UdpSocket> {
         Box::new(|args| {
             if let Some(addr) = args.address() {
                 let sock = args.user_data();
+                let addr = addr_for_socket(addr, &unwrap!(sock.local_addr()));
                 sock.send_to(args.buf(), &addr).unwrap();
             }
             0
@@ -352,7 +393,7 @@ fn make_utp_ctx<T>(data: T) -> UtpContext<T> {
     utp.set_callback(
         UtpCallbackType::OnError,
         Box::new(|args| {
-            error!("{}", args.buf_as_string());
+            error!("{}", args.error());
             0
         }),
     );
@@ -370,32 +411,43 @@ fn parse_cli_args() -> Result<CliArgs, clap::Error> {
     let matches = App::new("uTP cat which sends stdin over uTP stream")
         .about("Send data from stdin over uTP stream.")
         .arg(Arg::with_name("listen_mode").short("l").help("Listen mode"))
+        .arg(
+            Arg::with_name("ipv6")
+                .short("6")
+                .help("Listen on IPv6 (dual-stack) address in listen mode"),
+        )
         .arg(
             Arg::with_name("port")
                 .short("p")
                 .value_name("LISTEN_PORT")
                 .help("Local port")
                 .takes_value(true),
-        ).arg(
+        )
+        .arg(
             Arg::with_name("buffer_size")
                 .short("B")
                 .value_name("BUFFER_SIZE")
                 .help(
                     "Buffer size for incoming data. Default is 65487 bytes - max uTP data length.",
-                ).takes_value(true),
-        ).arg(
+                )
+                .takes_value(true),
+        )
+        .arg(
             Arg::with_name("dst_ip")
                 .value_name("DESTINATION_IP")
                 .help("Destination IP. If specified ucat operates in client mode.")
                 .takes_value(true),
-        ).arg(
+        )
+        .arg(
             Arg::with_name("dst_port")
                 .value_name("DESTINATION_PORT")
                 .help("Destination port")
                 .takes_value(true),
-        ).get_matches();
+        )
+        .get_matches();
 
     let listen_mode = matches.is_present("listen_mode");
+    let ipv6 = matches.is_present("ipv6");
     let port = matches
         .value_of("port")
         .map(|port_str| port_str.parse::<u16>().expect("Invalid port number"));
@@ -413,7 +465,8 @@ fn parse_cli_args() -> Result<CliArgs, clap::Error> {
     let target_addr = if let Some(dst_ip) = matches.value_of("dst_ip") {
         if let Some(dst_port) = matches.value_of("dst_port") {
             let port = dst_port.parse::<u16>().expect("Invalid port number");
-            let dst_addr = SocketAddr::V4(SocketAddrV4::new(unwrap!(dst_ip.parse()), port));
+            let dst_ip: IpAddr = dst_ip.parse().expect("Invalid IP address");
+            let dst_addr = SocketAddr::new(dst_ip, port);
             Some(dst_addr)
         } else {
             return Err(clap::Error::with_description(
@@ -426,7 +479,7 @@ fn parse_cli_args() -> Result<CliArgs, clap::Error> {
     };
 
     Ok(CliArgs {
-        listen_mode,
+        ipv6,
         port,
         buffer_size,
         target_addr,
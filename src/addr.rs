@@ -0,0 +1,73 @@
+//! Socket address helpers for IPv4 and IPv6 interoperability.
+
+#![allow(unsafe_code)]
+
+use libc::{self, sa_family_t};
+use nix::sys::socket::{sockaddr, sockaddr_in, sockaddr_in6, SockAddr};
+use std::mem;
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+/// Converts IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) into plain IPv4 address. Other addresses
+/// are returned unchanged.
+///
+/// Dual-stack UDP sockets report IPv4 peers as IPv4-mapped addresses. uTP identifies connections by
+/// peer address, so we normalize addresses before they reach libutp and before they are handed
+/// to callbacks.
+pub fn normalize_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(addr) => match ipv4_mapped(addr.ip()) {
+            Some(ip) => SocketAddr::V4(SocketAddrV4::new(ip.into(), addr.port())),
+            None => SocketAddr::V6(addr),
+        },
+        addr => addr,
+    }
+}
+
+/// Converts address into the form that UDP socket bound to `local_addr` is able to send packets
+/// to: IPv4 addresses are mapped to IPv6, if the socket is IPv6 (dual-stack) socket.
+/// Use it in `UtpCallbackType::Sendto` callback when the UDP socket is bound to IPv6 address.
+pub fn addr_for_socket(addr: SocketAddr, local_addr: &SocketAddr) -> SocketAddr {
+    match (addr, local_addr) {
+        (SocketAddr::V4(addr), &SocketAddr::V6(_)) => SocketAddr::V6(SocketAddrV6::new(
+            addr.ip().to_ipv6_mapped(),
+            addr.port(),
+            0,
+            0,
+        )),
+        (addr, _) => addr,
+    }
+}
+
+/// Converts C socket address into normalized Rust socket address. `None` is returned for null
+/// pointers, non IP addresses and addresses shorter than their family requires.
+///
+/// # Safety
+///
+/// `addr` must be either null or point to at least `addr_len` bytes.
+pub unsafe fn from_raw_sockaddr(addr: *const sockaddr, addr_len: usize) -> Option<SocketAddr> {
+    if addr.is_null() || addr_len < mem::size_of::<sa_family_t>() {
+        return None;
+    }
+    let expected_len = match i32::from((*addr).sa_family) {
+        libc::AF_INET => mem::size_of::<sockaddr_in>(),
+        libc::AF_INET6 => mem::size_of::<sockaddr_in6>(),
+        _ => return None,
+    };
+    if addr_len < expected_len {
+        return None;
+    }
+    match SockAddr::from_libc_sockaddr(addr) {
+        Some(SockAddr::Inet(addr)) => Some(normalize_addr(addr.to_std())),
+        _ => None,
+    }
+}
+
+/// Returns IPv4 address bytes, if given address is IPv4-mapped IPv6 address.
+/// NOTE, `Ipv6Addr::to_ipv4()` also converts IPv4-compatible addresses, e.g. `::1`, which we don't
+/// want.
+fn ipv4_mapped(ip: &Ipv6Addr) -> Option<[u8; 4]> {
+    match ip.octets() {
+        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => Some([a, b, c, d]),
+        _ => None,
+    }
+}
@@ -0,0 +1,613 @@
+//! Asynchronous uTP streams and listeners.
+//!
+//! The core is runtime agnostic: UDP socket and timer are pluggable and the driver task is
+//! returned to the caller to spawn on whatever executor it uses. With `tokio` feature, streams
+//! implement tokio I/O traits and can be created with tokio socket and timer right away. With
+//! `futures-io` feature, streams implement `futures::io` traits, e.g. to be used with async-std
+//! or smol.
+
+use super::{SharedUtpContext, SharedUtpSocket, UtpError};
+use driver::{init_callbacks, StreamState, Streams, MAX_PACKET_SIZE};
+#[cfg(feature = "tokio")]
+use endpoint::DEFAULT_BACKLOG;
+#[cfg(feature = "futures-io")]
+use futures_io;
+use std::cmp;
+use std::future::Future;
+use std::io;
+use std::net::{Shutdown, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+#[cfg(feature = "tokio")]
+use stream::any_addr_for;
+use stream::{check_timeout, stream_error};
+#[cfg(feature = "tokio")]
+use tokio;
+
+/// The longest the driver task sleeps without checking timeouts. Connections made or written to
+/// outside the task might need their timeouts checked earlier than the task knows.
+const MAX_SLEEP: Duration = Duration::from_secs(1);
+
+/// UDP socket uTP packets are sent and received with.
+pub trait AsyncUdpSocket: Send + Sync {
+    /// Receives a single datagram. If there's nothing to receive, the task must be woken up once
+    /// there is.
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>>;
+
+    /// Sends a datagram without blocking. It's fine to drop datagrams that can't be sent right
+    /// away, libutp retransmits lost packets.
+    fn try_send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /// Returns the address the socket is bound to.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// Timer the driver task uses to check uTP timeouts.
+pub trait AsyncTimer: Send {
+    /// Makes the timer fire at the given instant instead of the previous deadline.
+    fn reset(&mut self, deadline: Instant);
+
+    /// Returns `Poll::Ready`, if the deadline has passed. Otherwise the task must be woken up once
+    /// it does.
+    fn poll_elapsed(&mut self, cx: &mut Context) -> Poll<()>;
+}
+
+/// Owns uTP context and the UDP socket driven by `UtpDriverTask`. The task stops when the driver
+/// is dropped.
+struct AsyncDriver {
+    utp: SharedUtpContext<()>,
+    socket: Arc<dyn AsyncUdpSocket>,
+    streams: Arc<Streams>,
+    signal: Arc<TaskSignal>,
+}
+
+impl AsyncDriver {
+    fn new(
+        socket: Arc<dyn AsyncUdpSocket>,
+        timer: Box<dyn AsyncTimer>,
+        backlog: usize,
+    ) -> io::Result<(Self, UtpDriverTask)> {
+        let utp = SharedUtpContext::new(());
+        let streams = Arc::new(Streams::new(backlog));
+        let socket2 = Arc::clone(&socket);
+        init_callbacks(&utp, &streams, socket.local_addr()?, move |packet, addr| {
+            // lost packets are retransmitted by libutp
+            let _ = socket2.try_send_to(packet, addr);
+        });
+        let signal = Arc::new(TaskSignal::default());
+        let task = UtpDriverTask {
+            utp: utp.clone(),
+            socket: Arc::clone(&socket),
+            streams: Arc::clone(&streams),
+            timer,
+            signal: Arc::clone(&signal),
+            buf: vec![0; MAX_PACKET_SIZE],
+        };
+        let driver = Self {
+            utp,
+            socket,
+            streams,
+            signal,
+        };
+        Ok((driver, task))
+    }
+
+    /// Binds tokio UDP socket to the given address and spawns the driver task on the current
+    /// tokio runtime.
+    #[cfg(feature = "tokio")]
+    fn bind_tokio(addr: SocketAddr, backlog: usize) -> io::Result<Self> {
+        let socket = ::std::net::UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        let socket = tokio::net::UdpSocket::from_std(socket)?;
+        let timer = Box::pin(tokio::time::sleep(MAX_SLEEP));
+        let (driver, task) = Self::new(Arc::new(socket), Box::new(timer), backlog)?;
+        drop(tokio::spawn(task));
+        Ok(driver)
+    }
+}
+
+impl Drop for AsyncDriver {
+    fn drop(&mut self) {
+        self.signal.stop();
+    }
+}
+
+/// Wakes up the driver task from outside, either to stop it or to reschedule its timer.
+#[derive(Default)]
+struct TaskSignal {
+    stopped: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl TaskSignal {
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = self.lock().take() {
+            waker.wake();
+        }
+    }
+
+    fn poll_stopped(&self, cx: &Context) -> bool {
+        *self.lock() = Some(cx.waker().clone());
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    fn lock(&self) -> MutexGuard<Option<Waker>> {
+        self.waker
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Feeds received packets to uTP context, sends deferred ACKs once there are no more packets to
+/// read and checks timeouts when the context needs it. The task must be spawned on an executor
+/// for the streams and listeners to make any progress. It completes once they all are dropped.
+pub struct UtpDriverTask {
+    utp: SharedUtpContext<()>,
+    socket: Arc<dyn AsyncUdpSocket>,
+    streams: Arc<Streams>,
+    timer: Box<dyn AsyncTimer>,
+    signal: Arc<TaskSignal>,
+    buf: Vec<u8>,
+}
+
+impl Future for UtpDriverTask {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let task = &mut *self;
+        if task.signal.poll_stopped(cx) {
+            return Poll::Ready(());
+        }
+        loop {
+            let mut received = false;
+            loop {
+                match task.socket.poll_recv_from(cx, &mut task.buf) {
+                    Poll::Ready(Ok((len, sender_addr))) => {
+                        // illegal packets are simply ignored
+                        let _ = task.utp.process_udp(&task.buf[..len], sender_addr);
+                        received = true;
+                    }
+                    // errors are usually reported for a single packet, e.g. ICMP errors on some
+                    // platforms, so the socket is polled again, but only after other tasks get a
+                    // chance to run, in case the error persists
+                    Poll::Ready(Err(_)) => {
+                        cx.waker().wake_by_ref();
+                        break;
+                    }
+                    Poll::Pending => break,
+                }
+            }
+            if received {
+                task.utp.ack_packets();
+            }
+
+            let timeout = match task.utp.next_timeout() {
+                Some(timeout) => cmp::min(timeout, MAX_SLEEP),
+                None => MAX_SLEEP,
+            };
+            let mut deadline = Instant::now() + timeout;
+            if let Some(connect_deadline) = task.streams.next_deadline() {
+                deadline = cmp::min(deadline, connect_deadline);
+            }
+            task.timer.reset(deadline);
+            match task.timer.poll_elapsed(cx) {
+                Poll::Ready(()) => {
+                    task.utp.check_timeouts();
+                    task.streams.wake_expired(Instant::now());
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// uTP socket server listening for connections, comparable to `tokio::net::TcpListener`.
+///
+/// The listener owns UDP socket and uTP context that are driven by `UtpDriverTask`. Accepted
+/// streams share them with the listener, hence the task keeps running until the listener and
+/// all its streams are dropped.
+pub struct AsyncUtpListener {
+    driver: Arc<AsyncDriver>,
+}
+
+impl AsyncUtpListener {
+    /// Creates a new listener bound to the given address. The driver task is spawned on the
+    /// current tokio runtime, hence this must be called within one.
+    #[cfg(feature = "tokio")]
+    pub fn bind(addr: SocketAddr) -> io::Result<AsyncUtpListener> {
+        Self::bind_with_backlog(addr, DEFAULT_BACKLOG)
+    }
+
+    /// Creates a new listener that queues at most `backlog` incoming connections until they are
+    /// accepted. See `UtpEndpoint::bind_with_backlog()`.
+    #[cfg(feature = "tokio")]
+    pub fn bind_with_backlog(addr: SocketAddr, backlog: usize) -> io::Result<AsyncUtpListener> {
+        Ok(AsyncUtpListener {
+            driver: Arc::new(AsyncDriver::bind_tokio(addr, backlog)?),
+        })
+    }
+
+    /// Creates a new listener over the given UDP socket that queues at most `backlog` incoming
+    /// connections. The returned task must be spawned by the caller.
+    pub fn with_socket<S, T>(
+        socket: S,
+        timer: T,
+        backlog: usize,
+    ) -> io::Result<(AsyncUtpListener, UtpDriverTask)>
+    where
+        S: AsyncUdpSocket + 'static,
+        T: AsyncTimer + 'static,
+    {
+        let (driver, task) = AsyncDriver::new(Arc::new(socket), Box::new(timer), backlog)?;
+        let listener = AsyncUtpListener {
+            driver: Arc::new(driver),
+        };
+        Ok((listener, task))
+    }
+
+    /// Returns the local socket address of this listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.driver.socket.local_addr()
+    }
+
+    /// Returns a future that resolves to a new incoming connection.
+    pub fn accept(&self) -> Accept {
+        Accept { listener: self }
+    }
+}
+
+impl Drop for AsyncUtpListener {
+    fn drop(&mut self) {
+        // streams might keep the driver running, don't let more connections in
+        for id in self.driver.streams.close_incoming() {
+            let _ = self.driver.utp.take_socket(id);
+            self.driver.streams.remove(id);
+        }
+    }
+}
+
+/// Future returned by `AsyncUtpListener::accept()`.
+pub struct Accept<'a> {
+    listener: &'a AsyncUtpListener,
+}
+
+impl<'a> Future for Accept<'a> {
+    type Output = io::Result<(AsyncUtpStream, SocketAddr)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let driver = &self.listener.driver;
+        loop {
+            let (id, addr) = match driver.streams.poll_incoming(cx.waker()) {
+                Poll::Ready(incoming) => incoming,
+                Poll::Pending => return Poll::Pending,
+            };
+            // the socket might have failed while waiting in the queue
+            match driver.utp.take_socket(id) {
+                Some(sock) => {
+                    let stream = AsyncUtpStream {
+                        sock,
+                        driver: Arc::clone(driver),
+                    };
+                    return Poll::Ready(Ok((stream, addr)));
+                }
+                None => driver.streams.remove(id),
+            }
+        }
+    }
+}
+
+/// Asynchronous uTP connection, comparable to `tokio::net::TcpStream`.
+///
+/// Data is read and written with tokio `AsyncRead` and `AsyncWrite` traits or their
+/// `futures::io` counterparts, depending on enabled features.
+pub struct AsyncUtpStream {
+    sock: SharedUtpSocket<()>,
+    driver: Arc<AsyncDriver>,
+}
+
+impl AsyncUtpStream {
+    /// Returns a future that opens uTP connection to a remote host over a new UDP socket and
+    /// resolves once the connection is established. The driver task is spawned on the current
+    /// tokio runtime, hence this must be called within one.
+    #[cfg(feature = "tokio")]
+    pub fn connect(addr: SocketAddr) -> Connect {
+        let res = AsyncDriver::bind_tokio(any_addr_for(&addr), 0)
+            .and_then(|driver| Self::connect_with_driver(driver, addr));
+        Connect {
+            stream: Some(res),
+            deadline: None,
+        }
+    }
+
+    /// Same as `connect()`, but gives up once the timeout elapses. See `Connect::timeout()`.
+    #[cfg(feature = "tokio")]
+    pub fn connect_timeout(addr: SocketAddr, timeout: Duration) -> Connect {
+        Self::connect(addr).timeout(timeout)
+    }
+
+    /// Opens uTP connection to a remote host over the given UDP socket. The returned future
+    /// resolves once the connection is established, the returned task must be spawned by the
+    /// caller.
+    pub fn connect_with_socket<S, T>(
+        socket: S,
+        timer: T,
+        addr: SocketAddr,
+    ) -> io::Result<(Connect, UtpDriverTask)>
+    where
+        S: AsyncUdpSocket + 'static,
+        T: AsyncTimer + 'static,
+    {
+        let (driver, task) = AsyncDriver::new(Arc::new(socket), Box::new(timer), 0)?;
+        let stream = Self::connect_with_driver(driver, addr)?;
+        let connect = Connect {
+            stream: Some(Ok(stream)),
+            deadline: None,
+        };
+        Ok((connect, task))
+    }
+
+    /// Returns the socket address of the remote peer of this connection.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.sock.peer_addr()?)
+    }
+
+    /// Returns the socket address of the local half of this connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.driver.socket.local_addr()
+    }
+
+    fn connect_with_driver(driver: AsyncDriver, addr: SocketAddr) -> io::Result<Self> {
+        let sock = driver.utp.connect(addr)?;
+        Ok(AsyncUtpStream {
+            sock,
+            driver: Arc::new(driver),
+        })
+    }
+
+    fn poll_state<F, R>(&self, cx: &Context, f: F) -> Poll<R>
+    where
+        F: FnOnce(&mut StreamState) -> Option<R>,
+    {
+        self.driver.streams.poll(self.sock.id(), cx.waker(), f)
+    }
+
+    fn poll_read_data(&self, cx: &Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            if let Some(res) = self.driver.streams.try_read(&self.sock, buf) {
+                return Poll::Ready(res);
+            }
+            let changed = self.poll_state(cx, |stream| {
+                if stream.readable || stream.read_closed || stream.error.is_some() {
+                    Some(())
+                } else {
+                    None
+                }
+            });
+            if changed.is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    fn poll_write_data(&self, cx: &Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let id = self.sock.id();
+        self.driver
+            .streams
+            .inspect(id, |stream| stream_error(stream))?;
+        loop {
+            match self.sock.send(buf) {
+                Ok(bytes_sent) => return Poll::Ready(Ok(bytes_sent)),
+                Err(UtpError::WouldBlock) => (),
+                Err(e) => return Poll::Ready(Err(e.into())),
+            }
+            // `UtpState::Writable` might have been reported while we were sending, then we try
+            // again right away
+            let res = self.poll_state(cx, |stream| match stream_error(stream) {
+                Err(e) => Some(Err(e)),
+                Ok(()) if stream.writable => {
+                    stream.writable = false;
+                    Some(Ok(()))
+                }
+                Ok(()) => None,
+            });
+            match res {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    /// Shuts down the write half of the connection: remote peer reads end of stream after all
+    /// the data written so far, while this side can keep reading. See `UtpStream::shutdown()`.
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.driver
+            .streams
+            .inspect(self.sock.id(), |stream| stream.write_closed = true);
+        self.sock.shutdown(Shutdown::Write)?;
+        Ok(())
+    }
+}
+
+impl Drop for AsyncUtpStream {
+    fn drop(&mut self) {
+        self.driver.streams.remove(self.sock.id());
+    }
+}
+
+/// Future returned by `AsyncUtpStream::connect()` and `AsyncUtpStream::connect_with_socket()`.
+///
+/// Dropping the future before it resolves closes the socket.
+pub struct Connect {
+    /// `None` once the future has resolved.
+    stream: Option<io::Result<AsyncUtpStream>>,
+    deadline: Option<Instant>,
+}
+
+impl Connect {
+    /// Makes the future give up once the timeout elapses. It then fails with
+    /// `io::ErrorKind::TimedOut` holding `UtpError::ConnectTimedOut` and the socket is closed.
+    /// Zero timeout is rejected with `io::ErrorKind::InvalidInput`.
+    ///
+    /// The deadline is tracked by the driver task, no extra timer is needed.
+    pub fn timeout(mut self, timeout: Duration) -> Connect {
+        if let Err(e) = check_timeout(Some(timeout)) {
+            self.stream = Some(Err(e));
+            return self;
+        }
+        let deadline = Instant::now() + timeout;
+        if let Some(Ok(ref stream)) = self.stream {
+            stream
+                .driver
+                .streams
+                .inspect(stream.sock.id(), |stream| stream.deadline = Some(deadline));
+            // the task might be asleep waiting for a later deadline
+            stream.driver.signal.wake();
+        }
+        self.deadline = Some(deadline);
+        self
+    }
+}
+
+impl Future for Connect {
+    type Output = io::Result<AsyncUtpStream>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let timed_out = self
+            .deadline
+            .map_or(false, |deadline| Instant::now() >= deadline);
+        let res = match self.stream {
+            Some(Ok(_)) if timed_out => Poll::Ready(Err(UtpError::ConnectTimedOut.into())),
+            Some(Ok(ref stream)) => stream.poll_state(cx, |stream| match stream.error {
+                Some(error) => Some(Err(io::Error::from(error))),
+                None if stream.connected => {
+                    stream.deadline = None;
+                    Some(Ok(()))
+                }
+                None => None,
+            }),
+            Some(Err(_)) => Poll::Ready(Ok(())),
+            None => panic!("Connect polled after completion"),
+        };
+        match res {
+            Poll::Ready(Ok(())) => Poll::Ready(self.stream.take().expect("Checked above")),
+            Poll::Ready(Err(e)) => {
+                self.stream = None;
+                Poll::Ready(Err(e))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncUdpSocket for tokio::net::UdpSocket {
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        let mut buf = tokio::io::ReadBuf::new(buf);
+        match tokio::net::UdpSocket::poll_recv_from(self, cx, &mut buf) {
+            Poll::Ready(Ok(addr)) => Poll::Ready(Ok((buf.filled().len(), addr))),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn try_send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        tokio::net::UdpSocket::try_send_to(self, buf, addr)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        tokio::net::UdpSocket::local_addr(self)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncTimer for Pin<Box<tokio::time::Sleep>> {
+    fn reset(&mut self, deadline: Instant) {
+        self.as_mut()
+            .reset(tokio::time::Instant::from_std(deadline));
+    }
+
+    fn poll_elapsed(&mut self, cx: &mut Context) -> Poll<()> {
+        self.as_mut().poll(cx)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncRead for AsyncUtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut tokio::io::ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let len = match self.poll_read_data(cx, buf.initialize_unfilled()) {
+            Poll::Ready(Ok(len)) => len,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        };
+        buf.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncWrite for AsyncUtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.poll_write_data(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(self.shutdown_write())
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl futures_io::AsyncRead for AsyncUtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_read_data(cx, buf)
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl futures_io::AsyncWrite for AsyncUtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.poll_write_data(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(self.shutdown_write())
+    }
+}
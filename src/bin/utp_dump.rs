@@ -0,0 +1,196 @@
+//! Reads a pcap file and summarizes every uTP connection in it: handshake RTT, transferred bytes,
+//! retransmissions, duplicate and selective ACKs, advertised windows and how it was closed.
+//!
+//! ```text
+//! cargo install --features clap --path . --bin utp_dump
+//! utp_dump capture.pcap
+//! tcpdump -w - udp port 5000 | utp_dump --json -
+//! ```
+
+extern crate clap;
+extern crate utp;
+
+use clap::{App, Arg};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::time::{Duration, UNIX_EPOCH};
+use utp::flows::{CloseReason, DirectionStats, Flow, FlowAnalyser};
+use utp::pcap::PcapReader;
+
+#[derive(Debug)]
+struct CliArgs {
+    input: String,
+    json: bool,
+}
+
+fn main() -> io::Result<()> {
+    let args = parse_cli_args();
+    let input: Box<dyn Read> = if args.input == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(&args.input)?)
+    };
+
+    let mut analyser = FlowAnalyser::new();
+    for record in PcapReader::new(BufReader::new(input))? {
+        let _ = analyser.add(&record?);
+    }
+    let ignored = analyser.ignored();
+    let flows = analyser.into_flows();
+
+    if args.json {
+        print_json(&flows);
+    } else {
+        print_table(&flows);
+        if ignored > 0 {
+            println!("\nSkipped {} non-uTP datagrams", ignored);
+        }
+    }
+    Ok(())
+}
+
+fn parse_cli_args() -> CliArgs {
+    let matches = App::new("utp_dump")
+        .about("Summarize uTP connections captured in a pcap file.")
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Print flows as JSON array instead of a table"),
+        )
+        .arg(
+            Arg::with_name("input")
+                .value_name("PCAP_FILE")
+                .help("Capture to read, \"-\" reads stdin")
+                .required(true)
+                .takes_value(true),
+        )
+        .get_matches();
+
+    CliArgs {
+        input: matches.value_of("input").unwrap_or("-").to_string(),
+        json: matches.is_present("json"),
+    }
+}
+
+fn print_table(flows: &[Flow]) {
+    println!(
+        "{:<47} {:>5} {:>9} {:>8} {:>21} {:>9} {:>10} {:>9} {:>23}  CLOSE",
+        "FLOW (SRC > DST)",
+        "ID",
+        "DURATION",
+        "RTT",
+        "BYTES >/<",
+        "RETX >/<",
+        "DUPACK >/<",
+        "SACK >/<",
+        "WINDOW >/<"
+    );
+    for flow in flows {
+        let rtt = flow
+            .handshake_rtt
+            .map_or_else(|| "-".to_string(), |rtt| format!("{:.1}ms", millis(rtt)));
+        println!(
+            "{:<47} {:>5} {:>7.1}ms {:>8} {:>21} {:>9} {:>10} {:>9} {:>23}  {}",
+            format!(
+                "{} > {}{}",
+                flow.src,
+                flow.dst,
+                if flow.syn_seen { "" } else { " *" }
+            ),
+            flow.connection_id,
+            millis(flow.duration()),
+            rtt,
+            both(flow, |stats| stats.bytes.to_string()),
+            both(flow, |stats| stats.retransmissions.to_string()),
+            both(flow, |stats| stats.duplicate_acks.to_string()),
+            both(flow, |stats| stats.selective_acks.to_string()),
+            both(flow, window_range),
+            close_reason(flow),
+        );
+    }
+    if flows.iter().any(|flow| !flow.syn_seen) {
+        println!("\n* SYN was not captured, the direction is a guess");
+    }
+}
+
+fn print_json(flows: &[Flow]) {
+    let flows: Vec<String> = flows.iter().map(flow_json).collect();
+    println!("[{}]", flows.join(",\n "));
+}
+
+fn flow_json(flow: &Flow) -> String {
+    let start = flow
+        .start
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0));
+    format!(
+        "{{\"src\": \"{}\", \"dst\": \"{}\", \"connection_id\": {}, \"syn_seen\": {}, \
+         \"start\": {:.6}, \"duration_ms\": {:.3}, \"handshake_rtt_ms\": {}, \
+         \"forward\": {}, \"backward\": {}, \"close\": {}}}",
+        flow.src,
+        flow.dst,
+        flow.connection_id,
+        flow.syn_seen,
+        secs(start),
+        millis(flow.duration()),
+        flow.handshake_rtt
+            .map_or_else(|| "null".to_string(), |rtt| format!("{:.3}", millis(rtt))),
+        stats_json(&flow.forward),
+        stats_json(&flow.backward),
+        match flow.close {
+            CloseReason::Open => "null".to_string(),
+            CloseReason::Fin(by) => format!("{{\"reason\": \"fin\", \"by\": \"{}\"}}", by),
+            CloseReason::Reset(by) => format!("{{\"reason\": \"reset\", \"by\": \"{}\"}}", by),
+        }
+    )
+}
+
+fn stats_json(stats: &DirectionStats) -> String {
+    let window: Vec<String> = stats
+        .window
+        .iter()
+        .map(|&(at, wnd_size)| format!("[{:.3}, {}]", millis(at), wnd_size))
+        .collect();
+    format!(
+        "{{\"packets\": {}, \"bytes\": {}, \"retransmissions\": {}, \"duplicate_acks\": {}, \
+         \"selective_acks\": {}, \"window\": [{}]}}",
+        stats.packets,
+        stats.bytes,
+        stats.retransmissions,
+        stats.duplicate_acks,
+        stats.selective_acks,
+        window.join(", ")
+    )
+}
+
+/// Formats the stats of both directions as "forward/backward".
+fn both<F: Fn(&DirectionStats) -> String>(flow: &Flow, f: F) -> String {
+    format!("{}/{}", f(&flow.forward), f(&flow.backward))
+}
+
+/// Formats advertised window as "min-max".
+fn window_range(stats: &DirectionStats) -> String {
+    let min = stats.window.iter().map(|&(_, wnd_size)| wnd_size).min();
+    let max = stats.window.iter().map(|&(_, wnd_size)| wnd_size).max();
+    match (min, max) {
+        (Some(min), Some(max)) if min == max => min.to_string(),
+        (Some(min), Some(max)) => format!("{}-{}", min, max),
+        _ => "-".to_string(),
+    }
+}
+
+fn close_reason(flow: &Flow) -> String {
+    match flow.close {
+        CloseReason::Open => "open".to_string(),
+        CloseReason::Fin(by) => format!("FIN by {}", by),
+        CloseReason::Reset(by) => format!("RESET by {}", by),
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    secs(duration) * 1000.0
+}
+
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
@@ -2,19 +2,25 @@
 
 #![allow(unsafe_code)]
 
-use super::UtpState;
-use ctx::{get_user_data, UtpUserData};
+use super::{UtpError, UtpErrorCode, UtpState};
+use addr::from_raw_sockaddr;
+use bytes::Bytes;
+use command::Command;
+use ctx::{get_user_data, CtxShared, UtpUserData};
 use libc;
 use libutp_sys::*;
-use nix::sys::socket::SockAddr;
+use socket::{socket_id, UtpSocketId};
+use std::any::Any;
+use std::cell::RefCell;
+use std::convert::TryFrom;
 use std::ffi::CStr;
 use std::io;
 use std::marker::PhantomData;
-use std::net::SocketAddr;
-use std::{mem, slice};
+use std::net::{Shutdown, SocketAddr};
+use std::{panic, slice};
 
 /// Identifies uTP callback.
-#[derive(Hash, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
 #[repr(u32)]
 pub enum UtpCallbackType {
     /// With this callback you can allow/reject connections based on some criteria.
@@ -55,8 +61,35 @@ pub enum UtpCallbackType {
     Sendto = UTP_SENDTO,
 }
 
+impl TryFrom<i32> for UtpCallbackType {
+    type Error = UtpError;
+
+    fn try_from(cb_type: i32) -> Result<Self, UtpError> {
+        let cb = match cb_type as u32 {
+            UTP_ON_FIREWALL => UtpCallbackType::OnFirewall,
+            UTP_ON_ACCEPT => UtpCallbackType::OnAccept,
+            UTP_ON_CONNECT => UtpCallbackType::OnConnect,
+            UTP_ON_ERROR => UtpCallbackType::OnError,
+            UTP_ON_READ => UtpCallbackType::OnRead,
+            UTP_ON_OVERHEAD_STATISTICS => UtpCallbackType::OnOverheadStatistics,
+            UTP_ON_STATE_CHANGE => UtpCallbackType::OnStateChange,
+            UTP_GET_READ_BUFFER_SIZE => UtpCallbackType::GetReadBufferSize,
+            UTP_ON_DELAY_SAMPLE => UtpCallbackType::OnDelaySample,
+            UTP_GET_UDP_MTU => UtpCallbackType::GetUdpMtu,
+            UTP_GET_UDP_OVERHEAD => UtpCallbackType::GetUdpOverhead,
+            UTP_GET_MILLISECONDS => UtpCallbackType::GetMiliseconds,
+            UTP_GET_MICROSECONDS => UtpCallbackType::GetMicroseconds,
+            UTP_GET_RANDOM => UtpCallbackType::GetRandom,
+            UTP_LOG => UtpCallbackType::Log,
+            UTP_SENDTO => UtpCallbackType::Sendto,
+            _ => return Err(UtpError::UnknownCallbackType(cb_type)),
+        };
+        Ok(cb)
+    }
+}
+
 /// Function type that will be called when some uTP event happens.
-pub type UtpCallback<T> = Box<Fn(UtpCallbackArgs<T>) -> u64>;
+pub type UtpCallback<T> = Box<dyn Fn(UtpCallbackArgs<T>) -> u64>;
 
 /// Gives a more Rust'ish interface to callback arguments. Each libutp callback receives this
 /// structure.
@@ -67,31 +100,58 @@ pub struct UtpCallbackArgs<T> {
 
 impl<T> UtpCallbackArgs<T> {
     /// Wraps libutp callback arguments to a more Rust'ish interface.
-    pub fn wrap(inner: *mut utp_callback_arguments) -> Self {
+    ///
+    /// # Safety
+    ///
+    /// `inner` must point to valid callback arguments that outlive the returned wrapper and
+    /// `inner.context` must be either null or the context created by `UtpContext<T>`.
+    pub(crate) unsafe fn wrap(inner: *mut utp_callback_arguments) -> Self {
         Self {
             inner,
             _user_data_type: PhantomData,
         }
     }
 
-    /// Returns socket address, if it's IPv4 or IPv4. Otherwise `None` is returned.
+    /// Returns the type of the callback these arguments were passed to.
+    pub fn callback_type(&self) -> Result<UtpCallbackType, UtpError> {
+        UtpCallbackType::try_from(unsafe { (*self.inner).callback_type })
+    }
+
+    /// Returns the id of the socket this callback is called for. `None` is returned for context
+    /// wide callbacks, e.g. logging.
+    pub fn socket_id(&self) -> Option<UtpSocketId> {
+        socket_id(self.raw_socket()).or_else(|| self.command_error().map(|(id, _)| id))
+    }
+
+    /// Returns libutp socket this callback is called for, might be null.
+    pub(crate) fn raw_socket(&self) -> *mut utp_socket {
+        unsafe { (*self.inner).socket }
+    }
+
+    /// Returns socket address, if it's IPv4 or IPv6. Otherwise `None` is returned.
+    /// The address is only passed to `OnFirewall`, `OnAccept` and `Sendto` callbacks.
+    /// IPv4-mapped IPv6 addresses are returned as IPv4 addresses, hence when sending packets over
+    /// dual-stack UDP socket use `addr_for_socket()`.
     pub fn address(&self) -> Option<SocketAddr> {
-        let addr_opt = unsafe {
+        match self.callback_type() {
+            Ok(UtpCallbackType::OnFirewall)
+            | Ok(UtpCallbackType::OnAccept)
+            | Ok(UtpCallbackType::Sendto) => (),
+            _ => return None,
+        }
+        unsafe {
             let addr = (*self.inner).args1.address;
-            SockAddr::from_libc_sockaddr(addr)
-        };
-        match addr_opt {
-            Some(SockAddr::Inet(addr)) => Some(addr.to_std()),
-            _ => None,
+            let addr_len = (*self.inner).args2.address_len as usize;
+            from_raw_sockaddr(addr, addr_len)
         }
     }
 
-    /// Returns connection state.
-    pub fn state(&self) -> UtpState {
-        unsafe {
-            let state = (*self.inner).args1.state;
-            mem::transmute(state)
+    /// Returns connection state. Only available in `OnStateChange` callback.
+    pub fn state(&self) -> Result<UtpState, UtpError> {
+        if self.callback_type()? != UtpCallbackType::OnStateChange {
+            return Err(UtpError::ArgumentNotAvailable("state"));
         }
+        UtpState::try_from(unsafe { (*self.inner).args1.state })
     }
 
     /// Returns immutable slice to the buffer used for a specific callback, say `on_read`.
@@ -99,50 +159,162 @@ impl<T> UtpCallbackArgs<T> {
         unsafe {
             let buf = (*self.inner).buf;
             let buf_len = (*self.inner).len;
-            slice::from_raw_parts(buf, buf_len)
+            if buf.is_null() {
+                &[]
+            } else {
+                slice::from_raw_parts(buf, buf_len)
+            }
         }
     }
 
     /// Returns user data associated with the uTP context which is accessible from the uTP
     /// callback arguments.
     pub fn user_data(&self) -> &T {
-        &get_user_data_from_args(self).data()
+        get_user_data_from_args(self).data()
     }
 
     /// Acknowledges received data.
     /// This function must be called from `OnRead` callback otherwise received data won't
     /// be acknowledged.
     pub fn ack_data(&mut self) {
-        unsafe { utp_read_drained((*self.inner).socket) }
+        unsafe {
+            let sock = (*self.inner).socket;
+            if !sock.is_null() {
+                utp_read_drained(sock);
+            }
+        }
     }
 
     /// In some cases (e.g. logging), `buf` argument holds a C style, 0 terminated, string.
     /// This function converts such string into Rust `String`.
     pub fn buf_as_string(&self) -> String {
         unsafe {
-            CStr::from_ptr((*self.inner).buf as *const libc::c_char)
+            let buf = (*self.inner).buf;
+            if buf.is_null() {
+                return String::new();
+            }
+            CStr::from_ptr(buf as *const libc::c_char)
                 .to_string_lossy()
                 .into_owned()
         }
     }
 
+    /// Returns error code that was passed to `OnError` callback. Deferred commands that failed,
+    /// e.g. `defer_write()` or `defer_connect()`, have no libutp error code: `UtpError::SendFailed`
+    /// or `UtpError::ConnectFailed` is returned instead.
+    pub fn error_code(&self) -> Result<UtpErrorCode, UtpError> {
+        if self.callback_type()? != UtpCallbackType::OnError {
+            return Err(UtpError::ArgumentNotAvailable("error_code"));
+        }
+        if let Some((_, e)) = self.command_error() {
+            return Err(e);
+        }
+        UtpErrorCode::try_from(unsafe { (*self.inner).args1.error_code })
+    }
+
     /// Returns error that was passed to `OnError` callback.
-    /// Should only be used from `OnError` callback.
+    /// Should only be used from `OnError` callback. Unknown error codes and failed deferred
+    /// commands are reported as `io::ErrorKind::Other` holding `UtpError` that describes the
+    /// problem.
     pub fn error(&self) -> io::Error {
-        let err_code = unsafe { (*self.inner).args1.error_code } as u32;
-        match err_code {
-            UTP_ECONNREFUSED => io::ErrorKind::ConnectionRefused.into(),
-            UTP_ECONNRESET => io::ErrorKind::ConnectionReset.into(),
-            UTP_ETIMEDOUT => io::ErrorKind::TimedOut.into(),
-            _ => io::ErrorKind::Other.into(),
+        match self.error_code() {
+            Ok(code) => code.into(),
+            Err(e) => io::Error::new(io::ErrorKind::Other, e),
         }
     }
+
+    /// Returns `true`, if remote peer acknowledged everything sent over the socket and the
+    /// connection didn't fail. Meant to be checked in `OnStateChange` callback reporting
+    /// `UtpState::Destroying`: it tells if the data written before `UtpSocket::close()` was
+    /// delivered.
+    pub fn delivered(&self) -> bool {
+        match self.socket_id() {
+            Some(id) => self.shared().timers.is_delivered(id),
+            None => false,
+        }
+    }
+
+    /// Queues data to be written to the given socket once libutp returns control to the
+    /// `UtpContext` method that triggered this callback, e.g. `process_udp()`. Data that libutp
+    /// doesn't accept right away is written as soon as the socket becomes writable.
+    ///
+    /// Deferred commands for the same socket are executed in order. Commands for closed sockets
+    /// are ignored. If libutp fails to write the data, `OnError` callback is called and no more
+    /// data is written to the socket, see `UtpSocket::write_all()`.
+    pub fn defer_write(&self, sock: UtpSocketId, data: &[u8]) {
+        self.shared()
+            .commands
+            .push(Command::Write(sock, Bytes::from(data)));
+    }
+
+    /// Queues socket shutdown. See `defer_write()`.
+    pub fn defer_shutdown(&self, sock: UtpSocketId, how: Shutdown) {
+        self.shared().commands.push(Command::Shutdown(sock, how));
+    }
+
+    /// Queues socket close. See `defer_write()`. Data queued before is still written.
+    /// If the socket is held by `UtpSocket` handle, further operations on it fail with
+    /// `UtpError::SocketClosed`.
+    pub fn defer_close(&self, sock: UtpSocketId) {
+        self.shared().commands.push(Command::Close(sock));
+    }
+
+    /// Queues connection to the given address and returns the id the new socket will have in
+    /// callbacks. The socket is owned by the context and can be closed with `defer_close()`. If
+    /// libutp fails to start connecting, `OnError` callback is called with this id.
+    pub fn defer_connect(&self, addr: SocketAddr) -> UtpSocketId {
+        let shared = self.shared();
+        let id = shared.sockets.next_id();
+        shared.commands.push(Command::Connect(id, addr));
+        id
+    }
+
+    /// Returns the deferred command failure this `OnError` callback reports, if any.
+    fn command_error(&self) -> Option<(UtpSocketId, UtpError)> {
+        if self.callback_type().ok()? != UtpCallbackType::OnError {
+            return None;
+        }
+        self.shared().command_error.borrow().clone()
+    }
+
+    fn shared(&self) -> &CtxShared {
+        get_user_data_from_args(self).shared()
+    }
 }
 
 /// Returns pointer to user data which callback arguments point to.
 pub fn get_user_data_from_args<T>(args: &UtpCallbackArgs<T>) -> &UtpUserData<T> {
     unsafe {
-        get_user_data::<UtpUserData<T>>((*args.inner).context)
-            .expect("User data must be always set.")
+        let ctx = (*args.inner).context;
+        assert!(
+            !ctx.is_null(),
+            "Callback arguments must point to uTP context."
+        );
+        get_user_data::<UtpUserData<T>>(ctx).expect("User data must be always set.")
+    }
+}
+
+/// Holds a panic caught in a callback until control returns from libutp back to Rust code.
+/// Unwinding through C frames is undefined behavior, hence we can't let panics propagate.
+#[derive(Default)]
+pub struct PanicSlot {
+    payload: RefCell<Option<Box<dyn Any + Send>>>,
+}
+
+impl PanicSlot {
+    /// Stores panic payload. If multiple callbacks panic, only the first panic is kept.
+    pub fn store(&self, payload: Box<dyn Any + Send>) {
+        let mut slot = self.payload.borrow_mut();
+        if slot.is_none() {
+            *slot = Some(payload);
+        }
+    }
+
+    /// Resumes unwinding, if some callback has panicked.
+    pub fn resume(&self) {
+        let payload = self.payload.borrow_mut().take();
+        if let Some(payload) = payload {
+            panic::resume_unwind(payload);
+        }
     }
 }
@@ -0,0 +1,79 @@
+//! Records packets that go through the context into a pcap file.
+
+use addr::normalize_addr;
+use pcap::PcapWriter;
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+struct Capture {
+    writer: PcapWriter<Box<dyn Write + Send>>,
+    local_addr: SocketAddr,
+    /// The first write error, nothing is recorded after it.
+    error: Option<io::Error>,
+}
+
+/// Packet capture of a single context, disabled by default.
+#[derive(Default)]
+pub struct PacketCapture {
+    capture: RefCell<Option<Capture>>,
+}
+
+impl PacketCapture {
+    /// Starts writing pcap stream to `out`. Packets are recorded as if the context was bound to
+    /// `local_addr`. The previous capture, if any, is dropped.
+    pub fn start(&self, local_addr: SocketAddr, out: Box<dyn Write + Send>) -> io::Result<()> {
+        let writer = PcapWriter::new(out)?;
+        *self.capture.borrow_mut() = Some(Capture {
+            writer,
+            local_addr: normalize_addr(local_addr),
+            error: None,
+        });
+        Ok(())
+    }
+
+    /// Stops the capture and flushes the stream. Returns the first error the capture ran into.
+    pub fn stop(&self) -> io::Result<()> {
+        match self.capture.borrow_mut().take() {
+            Some(mut capture) => match capture.error {
+                Some(e) => Err(e),
+                None => capture.writer.flush(),
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Records the packet received from remote peer.
+    pub fn on_receive(&self, sender_addr: SocketAddr, packet: &[u8], time: SystemTime) {
+        self.record(
+            |capture| (normalize_addr(sender_addr), capture.local_addr),
+            packet,
+            time,
+        );
+    }
+
+    /// Records the packet libutp sent to remote peer.
+    pub fn on_send(&self, addr: SocketAddr, packet: &[u8], time: SystemTime) {
+        self.record(
+            |capture| (capture.local_addr, normalize_addr(addr)),
+            packet,
+            time,
+        );
+    }
+
+    fn record<F>(&self, addrs: F, packet: &[u8], time: SystemTime)
+    where
+        F: FnOnce(&Capture) -> (SocketAddr, SocketAddr),
+    {
+        let mut capture = self.capture.borrow_mut();
+        let capture = match *capture {
+            Some(ref mut capture) if capture.error.is_none() => capture,
+            _ => return,
+        };
+        let (src, dst) = addrs(capture);
+        if let Err(e) = capture.writer.write_udp(time, src, dst, packet) {
+            capture.error = Some(e);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Time sources of uTP contexts.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Clock that only moves when told to. A context driven by it sees no time pass between the
+/// calls, hence timeouts fire exactly when the clock is advanced past them. Useful to replay
+/// captured traffic with its original timing or to run deterministic tests.
+///
+/// Clones share the same time, so a single clock can drive multiple contexts.
+#[derive(Clone)]
+pub struct ManualClock {
+    inner: Arc<Mutex<ClockState>>,
+}
+
+struct ClockState {
+    /// Monotonic time the clock started at.
+    origin: Instant,
+    /// Wall clock time the clock started at.
+    wall_origin: SystemTime,
+    elapsed: Duration,
+}
+
+impl ManualClock {
+    /// Creates a clock that shows the given wall clock time.
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(ClockState {
+                origin: Instant::now(),
+                wall_origin: start,
+                elapsed: Duration::from_secs(0),
+            })),
+        }
+    }
+
+    /// Returns the current time as `Instant`, which is what `UtpContext::next_timeout()` is
+    /// relative to.
+    pub fn now(&self) -> Instant {
+        let state = self.lock();
+        state.origin + state.elapsed
+    }
+
+    /// Returns the current wall clock time.
+    pub fn system_time(&self) -> SystemTime {
+        let state = self.lock();
+        state.wall_origin + state.elapsed
+    }
+
+    /// Returns how much time has passed since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        self.lock().elapsed
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        self.lock().elapsed += duration;
+    }
+
+    /// Moves the clock forward to the given wall clock time. The clock never goes back, earlier
+    /// times are ignored.
+    pub fn advance_to(&self, time: SystemTime) {
+        let mut state = self.lock();
+        if let Ok(elapsed) = time.duration_since(state.wall_origin) {
+            if elapsed > state.elapsed {
+                state.elapsed = elapsed;
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<ClockState> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Time source of a single context.
+#[derive(Default)]
+pub enum Clock {
+    /// libutp reads the system clock itself.
+    #[default]
+    System,
+    Manual(ManualClock),
+}
+
+impl Clock {
+    pub fn now(&self) -> Instant {
+        match *self {
+            Clock::System => Instant::now(),
+            Clock::Manual(ref clock) => clock.now(),
+        }
+    }
+
+    pub fn system_time(&self) -> SystemTime {
+        match *self {
+            Clock::System => SystemTime::now(),
+            Clock::Manual(ref clock) => clock.system_time(),
+        }
+    }
+
+    /// Answers libutp's `GetMicroseconds` query. Only used with manual clock: wall clock time is
+    /// as good a time base as any and it's never zero.
+    pub fn micros(&self) -> u64 {
+        let since_epoch = self
+            .system_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|_| Duration::from_secs(0));
+        since_epoch.as_secs() * 1_000_000 + u64::from(since_epoch.subsec_micros())
+    }
+
+    pub fn is_manual(&self) -> bool {
+        match *self {
+            Clock::System => false,
+            Clock::Manual(_) => true,
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! Operations deferred until libutp returns control to Rust code.
+
+use bytes::Bytes;
+use socket::UtpSocketId;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{Shutdown, SocketAddr};
+
+/// Socket operation requested from within a callback. libutp is in the middle of processing a
+/// packet or a timer when it calls callbacks, hence calling back into it right away is not safe.
+#[derive(Debug)]
+pub enum Command {
+    /// Write data to the socket.
+    Write(UtpSocketId, Bytes),
+    /// Shutdown reads and/or writes on the socket.
+    Shutdown(UtpSocketId, Shutdown),
+    /// Close the socket.
+    Close(UtpSocketId),
+    /// Connect new socket with the given id to remote peer.
+    Connect(UtpSocketId, SocketAddr),
+}
+
+impl Command {
+    /// Returns the id of the socket this command operates on.
+    pub fn socket_id(&self) -> UtpSocketId {
+        match *self {
+            Command::Write(id, _)
+            | Command::Shutdown(id, _)
+            | Command::Close(id)
+            | Command::Connect(id, _) => id,
+        }
+    }
+}
+
+/// Limits on how much data is queued for a single socket.
+struct WaterMarks {
+    high: usize,
+    low: usize,
+    /// Queued data exceeded the high-water mark and the writer was told so.
+    full: bool,
+}
+
+/// Commands waiting to be executed. Commands for the same socket are executed in the order they
+/// were queued. If libutp doesn't accept all the data being written, the rest of the data and all
+/// subsequent commands for that socket wait until the socket becomes writable.
+#[derive(Default)]
+pub struct CommandQueue {
+    ready: RefCell<VecDeque<Command>>,
+    blocked: RefCell<HashMap<UtpSocketId, VecDeque<Command>>>,
+    water_marks: RefCell<HashMap<UtpSocketId, WaterMarks>>,
+    /// Sockets whose `UtpSocket::send()` was refused because of queued data.
+    send_blocked: RefCell<HashSet<UtpSocketId>>,
+    /// Sockets drained since `UtpState::Writable` was last reported for them.
+    writable: RefCell<VecDeque<UtpSocketId>>,
+    /// Sockets libutp refused to write queued data to.
+    write_failed: RefCell<HashSet<UtpSocketId>>,
+}
+
+impl CommandQueue {
+    /// Queues the command for execution.
+    pub fn push(&self, cmd: Command) {
+        self.ready.borrow_mut().push_back(cmd);
+    }
+
+    /// Takes the next command that can be executed right away. Commands for blocked sockets are
+    /// put aside until the socket becomes writable.
+    pub fn pop(&self) -> Option<Command> {
+        loop {
+            let cmd = self.ready.borrow_mut().pop_front()?;
+            let id = cmd.socket_id();
+            match self.blocked.borrow_mut().get_mut(&id) {
+                Some(cmds) => cmds.push_back(cmd),
+                None => return Some(cmd),
+            }
+        }
+    }
+
+    /// Blocks the socket until it becomes writable: given command will be retried first.
+    pub fn block(&self, cmd: Command) {
+        self.blocked
+            .borrow_mut()
+            .entry(cmd.socket_id())
+            .or_default()
+            .push_front(cmd);
+    }
+
+    /// Resumes commands of the socket that became writable.
+    pub fn unblock(&self, id: UtpSocketId) {
+        let cmds = self.blocked.borrow_mut().remove(&id);
+        if let Some(cmds) = cmds {
+            let mut ready = self.ready.borrow_mut();
+            for cmd in cmds.into_iter().rev() {
+                ready.push_front(cmd);
+            }
+        }
+    }
+
+    /// Drops commands of the socket that was closed.
+    pub fn forget(&self, id: UtpSocketId) {
+        let _ = self.blocked.borrow_mut().remove(&id);
+        let _ = self.water_marks.borrow_mut().remove(&id);
+        let _ = self.send_blocked.borrow_mut().remove(&id);
+        let _ = self.write_failed.borrow_mut().remove(&id);
+        self.writable
+            .borrow_mut()
+            .retain(|&writable_id| writable_id != id);
+    }
+
+    /// Returns `true`, if there are commands waiting to be executed for the socket.
+    pub fn has_pending(&self, id: UtpSocketId) -> bool {
+        self.blocked.borrow().contains_key(&id)
+            || self.ready.borrow().iter().any(|cmd| cmd.socket_id() == id)
+    }
+
+    /// Returns `true`, if the socket is going to be closed once its preceding commands are
+    /// executed.
+    pub fn is_closing(&self, id: UtpSocketId) -> bool {
+        let is_close = |cmd: &Command| match *cmd {
+            Command::Close(cmd_id) => cmd_id == id,
+            _ => false,
+        };
+        self.ready.borrow().iter().any(&is_close)
+            || self
+                .blocked
+                .borrow()
+                .get(&id)
+                .map_or(false, |cmds| cmds.iter().any(&is_close))
+    }
+
+    /// Returns how many bytes are waiting to be written to the socket.
+    pub fn queued_len(&self, id: UtpSocketId) -> usize {
+        let write_len = |cmd: &Command| match *cmd {
+            Command::Write(cmd_id, ref data) if cmd_id == id => data.len(),
+            _ => 0,
+        };
+        let ready: usize = self.ready.borrow().iter().map(&write_len).sum();
+        let blocked: usize = self
+            .blocked
+            .borrow()
+            .get(&id)
+            .map_or(0, |cmds| cmds.iter().map(&write_len).sum());
+        ready + blocked
+    }
+
+    /// Sets high and low-water marks for the data queued for the socket.
+    pub fn set_water_marks(&self, id: UtpSocketId, high: usize, low: usize) {
+        let full = self.queued_len(id) > high;
+        let _ = self
+            .water_marks
+            .borrow_mut()
+            .insert(id, WaterMarks { high, low, full });
+    }
+
+    /// Checks if the data queued for the socket exceeds its high-water mark. If so, the socket
+    /// stays full until `take_drained()` reports otherwise.
+    pub fn check_full(&self, id: UtpSocketId) -> bool {
+        let queued_len = self.queued_len(id);
+        match self.water_marks.borrow_mut().get_mut(&id) {
+            Some(marks) => {
+                marks.full = marks.full || queued_len > marks.high;
+                marks.full
+            }
+            None => false,
+        }
+    }
+
+    /// Returns `true`, if the socket exceeded its high-water mark and is not drained yet.
+    pub fn is_full(&self, id: UtpSocketId) -> bool {
+        self.water_marks
+            .borrow()
+            .get(&id)
+            .map_or(false, |marks| marks.full)
+    }
+
+    /// Records that `send()` was refused, so the socket is reported writable once all its
+    /// queued commands are executed.
+    pub fn block_send(&self, id: UtpSocketId) {
+        let _ = self.send_blocked.borrow_mut().insert(id);
+    }
+
+    /// Checks if the socket should be reported writable after its command was executed: either
+    /// the data queued for the full socket dropped to its low-water mark or the socket has no
+    /// queued commands anymore and `send()` was refused before.
+    pub fn check_drained(&self, id: UtpSocketId) {
+        let queued_len = self.queued_len(id);
+        let drained = match self.water_marks.borrow_mut().get_mut(&id) {
+            Some(ref mut marks) if marks.full && queued_len <= marks.low => {
+                marks.full = false;
+                true
+            }
+            _ => false,
+        };
+        let send_unblocked = !self.has_pending(id) && self.send_blocked.borrow_mut().remove(&id);
+        if (drained || send_unblocked) && !self.writable.borrow().contains(&id) {
+            self.writable.borrow_mut().push_back(id);
+        }
+    }
+
+    /// Records that libutp failed to write queued data to the socket. Further data is not written
+    /// to it, otherwise remote peer would receive the data with a gap.
+    pub fn fail_writes(&self, id: UtpSocketId) {
+        let _ = self.write_failed.borrow_mut().insert(id);
+    }
+
+    /// Returns `true`, if writing queued data to the socket failed.
+    pub fn writes_failed(&self, id: UtpSocketId) -> bool {
+        self.write_failed.borrow().contains(&id)
+    }
+
+    /// Takes the next socket that must be reported writable.
+    pub fn take_writable(&self) -> Option<UtpSocketId> {
+        self.writable.borrow_mut().pop_front()
+    }
+}
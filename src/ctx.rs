@@ -2,19 +2,46 @@
 
 #![allow(unsafe_code)]
 
-use super::UtpError;
-use callback::{get_user_data_from_args, UtpCallback, UtpCallbackArgs, UtpCallbackType};
+use super::{normalize_addr, UtpError, UtpEvent, UtpState};
+use bytes::Bytes;
+use callback::{get_user_data_from_args, PanicSlot, UtpCallback, UtpCallbackArgs, UtpCallbackType};
+use capture::PacketCapture;
+use clock::{Clock, ManualClock};
+use command::{Command, CommandQueue};
 use libutp_sys::*;
-use nix::sys::socket::{sockaddr, InetAddr, SockAddr};
-use socket::{make_utp_socket, UtpSocket};
-use std::collections::HashMap;
+use nix::sys::socket::{InetAddr, SockAddr};
+use read_buffer::ReadBuffers;
+use rng::Rng;
+use socket::{
+    make_utp_socket, shutdown_socket, take_utp_socket, SocketRegistry, UtpSocket, UtpSocketId,
+};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
 use std::marker::PhantomData;
+use std::mem;
 use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use timers::TimeoutTracker;
 
 /// To manipulate the user data held inside uTP context use `UtpContextRef` which is acquired with
 /// `UtpContext::get_ref()`.
+///
+/// When the context is dropped, all sockets created by it are closed and destroyed.
+///
+/// If any callback panics, the panic is caught at the FFI boundary and resumed once control
+/// returns from libutp to the Rust method that triggered the callback, e.g. `process_udp()`.
+///
+/// Callbacks must not call back into libutp. Instead they can queue socket operations with
+/// `UtpCallbackArgs::defer_write()` and friends, which are executed by the context once libutp
+/// returns control.
 pub struct UtpContext<T> {
     ctx: *mut utp_context,
+    shared: Rc<CtxShared>,
     _user_data_type: PhantomData<T>,
 }
 
@@ -25,8 +52,9 @@ impl<T> UtpContext<T> {
 
         // create user data on the heap and keep a pointer to it inside uTP context.
         // NOTE: don't forget to destroy this user data.
-        // TODO(povilas): guard user data with mutex?
-        let utp_user_data = Box::new(UtpUserData::new(user_data));
+        // NOTE: the context is not thread-safe, use `SharedUtpContext` to share it between threads.
+        let shared = Rc::new(CtxShared::new(ctx, dispatch_callback::<T>));
+        let utp_user_data = Box::new(UtpUserData::new(user_data, Rc::clone(&shared)));
         unsafe {
             let _ = utp_context_set_userdata(ctx, Box::into_raw(utp_user_data) as *mut _);
         };
@@ -34,6 +62,7 @@ impl<T> UtpContext<T> {
         init_callbacks::<T>(ctx);
         Self {
             ctx,
+            shared,
             _user_data_type: PhantomData,
         }
     }
@@ -65,10 +94,21 @@ impl<T> UtpContext<T> {
 
     /// Feed UDP packet to underlying uTP library that will process it and react appropriately:
     /// e.g. terminate connection or call `UtpCallbackType::OnRead` callback, etc.
+    /// IPv4-mapped IPv6 sender addresses, as reported by dual-stack sockets, are treated as IPv4
+    /// addresses.
     pub fn process_udp(&self, packet: &[u8], sender_addr: SocketAddr) -> Result<(), UtpError> {
-        let (sockaddr, socklen) = c_sock_addr(sender_addr);
-        let res =
-            unsafe { utp_process_udp(self.ctx, packet.as_ptr(), packet.len(), &sockaddr, socklen) };
+        self.shared
+            .capture
+            .on_receive(sender_addr, packet, self.shared.system_time());
+        self.shared
+            .timers
+            .on_receive(normalize_addr(sender_addr), packet);
+        let sockaddr = c_sock_addr(sender_addr);
+        let res = unsafe {
+            let (sockaddr, socklen) = sockaddr.as_ffi_pair();
+            utp_process_udp(self.ctx, packet.as_ptr(), packet.len(), sockaddr, socklen)
+        };
+        self.shared.finish_utp_call();
         match res {
             1 => Ok(()),
             0 => Err(UtpError::IllegalPacket),
@@ -82,34 +122,147 @@ impl<T> UtpContext<T> {
     }
 
     /// Attempt to make a uTP connection to a given address.
+    /// The returned socket can't outlive this context: once the context is dropped, socket
+    /// operations fail with `UtpError::ContextDestroyed`.
     pub fn connect(&mut self, addr: SocketAddr) -> Result<UtpSocket, UtpError> {
-        let (sockaddr, socklen) = c_sock_addr(addr);
-        let (sock, res) = unsafe {
-            let sock = utp_create_socket(self.ctx);
-            let res = utp_connect(sock, &sockaddr, socklen);
-            (sock, res)
+        let sockaddr = c_sock_addr(addr);
+        let raw_sock = unsafe { utp_create_socket(self.ctx) };
+        if raw_sock.is_null() {
+            return Err(UtpError::ConnectFailed);
+        }
+        // wrap the socket right away so that it's closed and freed by libutp, if connect fails.
+        let sock = make_utp_socket(raw_sock, Rc::clone(&self.shared));
+        let res = unsafe {
+            let (sockaddr, socklen) = sockaddr.as_ffi_pair();
+            utp_connect(raw_sock, sockaddr, socklen)
         };
+        self.shared.finish_call();
         match res {
-            0 => Ok(make_utp_socket(sock)),
-            // TODO(povilas): destroy socket handle on error. NOTE: currently there's no way to do
-            // this in libutp.
+            0 => Ok(sock),
             -1 => Err(UtpError::ConnectFailed),
             result => Err(UtpError::UnexpectedResult(i64::from(result))),
         }
     }
 
+    /// Returns a handle for the socket that is owned by the context: accepted in `OnAccept`
+    /// callback or connected with `UtpCallbackArgs::defer_connect()`. Once taken, the socket is
+    /// closed when the handle is dropped. `None` is returned, if the socket is already closed or
+    /// has a handle.
+    pub fn take_socket(&mut self, id: UtpSocketId) -> Option<UtpSocket> {
+        take_utp_socket(id, Rc::clone(&self.shared))
+    }
+
+    /// Switches the context into event queue mode: instead of calling `OnAccept`, `OnRead`,
+    /// `OnStateChange`, `OnError` and `Sendto` callbacks, the context records `UtpEvent`s which
+    /// must be drained with `poll_events()` after each `process_udp()`, `check_timeouts()`,
+    /// `ack_packets()`, `connect()` or `UtpSocket::send()` call.
+    ///
+    /// Setting any of those callbacks afterwards stops the respective events from being recorded.
+    pub fn enable_events(&mut self)
+    where
+        T: 'static,
+    {
+        self.set_callback(UtpCallbackType::OnAccept, Box::new(on_accept_event));
+        self.set_callback(UtpCallbackType::OnRead, Box::new(on_read_event));
+        self.set_callback(
+            UtpCallbackType::OnStateChange,
+            Box::new(on_state_change_event),
+        );
+        self.set_callback(UtpCallbackType::OnError, Box::new(on_error_event));
+        self.set_callback(UtpCallbackType::Sendto, Box::new(on_sendto_event));
+    }
+
+    /// Drains events recorded since the last call. See `enable_events()`.
+    pub fn poll_events(&self) -> impl Iterator<Item = UtpEvent> {
+        let events = mem::take(&mut *self.shared.events.borrow_mut());
+        events.into_iter()
+    }
+
+    /// Switches the context into pull mode for received data: instead of handing it to `OnRead`
+    /// callback to be processed right away, the context buffers it per socket until it's read with
+    /// `UtpSocket::read()`. Data is acknowledged as it's read and the receive window advertised
+    /// to remote peers shrinks by the amount of unread data, so slow readers throttle senders.
+    ///
+    /// `OnRead` is still called as a notification, `UtpCallbackArgs::buf()` holding the data just
+    /// appended to the buffer, but there's no need to call `UtpCallbackArgs::ack_data()`. In event
+    /// queue mode `UtpEvent::Readable` is recorded instead of `UtpEvent::Data`.
+    pub fn enable_read_buffers(&mut self) {
+        self.shared.read_buffers.enable();
+    }
+
+    /// Starts recording every packet passed to `process_udp()` and sent with `Sendto` callback
+    /// into pcap stream written to `out`, e.g. a file that Wireshark opens with its uTP
+    /// dissector. IP and UDP headers are synthesized with `local_addr` standing for this side of
+    /// the connections and packets are timestamped by the context's clock, see `set_clock()`.
+    ///
+    /// Starting a new capture replaces the previous one. A write error stops the capture, it's
+    /// returned by `stop_capture()`.
+    pub fn start_capture<W>(&mut self, local_addr: SocketAddr, out: W) -> io::Result<()>
+    where
+        W: Write + Send + 'static,
+    {
+        self.shared.capture.start(local_addr, Box::new(out))
+    }
+
+    /// Stops the capture started with `start_capture()` and flushes the stream.
+    pub fn stop_capture(&mut self) -> io::Result<()> {
+        self.shared.capture.stop()
+    }
+
+    /// Drives the context by the given clock instead of the system clock: libutp timestamps,
+    /// retransmits, keep-alives and `next_timeout()` all follow it, so no time passes for the
+    /// context unless the clock is advanced. Call `check_timeouts()` once the clock passes
+    /// `next_timeout()`.
+    ///
+    /// Must be called before any sockets are created, libutp can't handle time jumping under
+    /// open connections.
+    pub fn set_clock(&mut self, clock: ManualClock) {
+        *self.shared.clock.borrow_mut() = Clock::Manual(clock);
+        init_clock_callbacks::<T>(self.ctx);
+    }
+
+    /// Makes connection ids and initial sequence numbers picked by libutp follow a pseudo random
+    /// sequence generated from `seed`, so that runs driven by a `ManualClock` are reproducible.
+    /// By default libutp uses the C library's `rand()`, which is shared by all the contexts in
+    /// the process.
+    pub fn set_random_seed(&mut self, seed: u64) {
+        *self.shared.random.borrow_mut() = Some(Rng::new(seed));
+        init_random_callback::<T>(self.ctx);
+    }
+
     /// Sends all deferred ACK packets.
     /// This method should be called when real UDP socket becomes unreadable - returns EWOULDBLOCK.
     pub fn ack_packets(&self) {
         unsafe {
             utp_issue_deferred_acks(self.ctx);
         }
+        self.shared.finish_utp_call();
     }
 
     /// Checks for timedout connections, ACK packets, reschedules lost packets, etc.
-    /// Should be called every 500ms - recommendation from libutp.
+    /// Call it once `next_timeout()` elapses.
     pub fn check_timeouts(&mut self) {
         unsafe { utp_check_timeouts(self.ctx) }
+        self.shared.timers.on_check(self.shared.now());
+        self.shared.finish_utp_call();
+    }
+
+    /// Returns how long to wait before calling `check_timeouts()`, or `None`, if there are no
+    /// connections that need it. Zero means it's due already.
+    ///
+    /// Connections with unacknowledged packets, e.g. pending SYN, retransmits or FIN, are checked
+    /// every 500ms - libutp ignores more frequent calls. Idle connections only need to be checked
+    /// when keep-alive packets are due. Note that deferred ACKs are not timer driven: they are
+    /// sent by `ack_packets()`.
+    ///
+    /// libutp doesn't expose its timers, so this is a best-effort estimate derived from the packets
+    /// going through the context. Event loops should still cap how long they sleep, so that a
+    /// timer the estimate misses is only late, not lost.
+    ///
+    /// The timeout changes whenever packets are sent or received, so query it before going to
+    /// sleep each time.
+    pub fn next_timeout(&self) -> Option<Duration> {
+        self.shared.timers.next_timeout(self.shared.now())
     }
 
     fn utp_user_data(&self) -> &UtpUserData<T> {
@@ -149,12 +302,334 @@ fn get_user_data_mut<'a, T>(ctx: *mut utp_context) -> Option<&'a mut T> {
 
 impl<T> Drop for UtpContext<T> {
     fn drop(&mut self) {
+        self.shared.destroyed.set(true);
         unsafe {
+            // Close sockets that are still open. `UtpSocket` handles become unusable from now on
+            // and deferred commands are discarded.
+            for sock in self.shared.sockets.drain() {
+                utp_close(sock);
+            }
+            // libutp might still call callbacks while destroying sockets, hence user data must
+            // outlive the context.
             let user_data_ptr = utp_context_get_userdata(self.ctx) as *mut UtpUserData<T>;
-            let _ = Box::from_raw(user_data_ptr); // this will make sure UserData is dropped properly.
             utp_destroy(self.ctx);
+            let _ = Box::from_raw(user_data_ptr); // this will make sure UserData is dropped properly.
+        }
+        // don't panic while already unwinding, that would abort the process
+        if !thread::panicking() {
+            self.shared.panic.resume();
+        }
+    }
+}
+
+/// State shared between `UtpContext`, its callbacks and the sockets created by the context.
+pub struct CtxShared {
+    ctx: *mut utp_context,
+    /// Open sockets.
+    pub sockets: SocketRegistry,
+    /// Panic caught in one of the callbacks.
+    pub panic: PanicSlot,
+    /// Events recorded in event queue mode.
+    pub events: RefCell<VecDeque<UtpEvent>>,
+    /// Failure of a deferred command that is being reported to `OnError` callback.
+    pub command_error: RefCell<Option<(UtpSocketId, UtpError)>>,
+    /// Socket operations deferred by callbacks.
+    pub commands: CommandQueue,
+    /// Figures out when timeouts must be checked.
+    pub timers: TimeoutTracker,
+    /// Received data not read yet, if the context buffers it.
+    pub read_buffers: ReadBuffers,
+    /// Records packets into pcap stream, if enabled.
+    capture: PacketCapture,
+    /// Time source of libutp and timers.
+    clock: RefCell<Clock>,
+    /// Answers libutp's `GetRandom` queries, if seeded.
+    random: RefCell<Option<Rng>>,
+    /// How many callbacks or command executions are in progress.
+    depth: Cell<usize>,
+    destroyed: Cell<bool>,
+    /// Calls user callbacks for the context's user data type.
+    dispatch: Dispatch,
+}
+
+/// `dispatch_callback()` instance for a specific user data type.
+type Dispatch = unsafe fn(&UtpCallbackType, *mut utp_callback_arguments) -> u64;
+
+impl CtxShared {
+    fn new(ctx: *mut utp_context, dispatch: Dispatch) -> Self {
+        Self {
+            ctx,
+            dispatch,
+            sockets: Default::default(),
+            panic: Default::default(),
+            events: Default::default(),
+            command_error: Default::default(),
+            commands: Default::default(),
+            timers: Default::default(),
+            read_buffers: Default::default(),
+            capture: Default::default(),
+            clock: Default::default(),
+            random: Default::default(),
+            depth: Cell::new(0),
+            destroyed: Cell::new(false),
+        }
+    }
+
+    /// Returns the current time of the context's clock.
+    pub fn now(&self) -> Instant {
+        self.clock.borrow().now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.clock.borrow().system_time()
+    }
+
+    /// Returns `true`, if the context was dropped.
+    pub fn is_destroyed(&self) -> bool {
+        self.destroyed.get()
+    }
+
+    /// Must be called once control returns from libutp to Rust code: executes deferred commands
+    /// and resumes a panic caught in some callback.
+    pub fn finish_call(&self) {
+        self.run_commands();
+        self.panic.resume();
+    }
+
+    /// Like `finish_call()`, but also reports sockets whose queued commands drained as writable.
+    /// Must be used by methods that make libutp process packets or timers: callbacks are
+    /// expected to be called from there, unlike from methods that merely queue data.
+    fn finish_utp_call(&self) {
+        self.run_commands();
+        self.report_writable();
+        self.panic.resume();
+    }
+
+    fn run_commands(&self) {
+        // libutp might be in the middle of dispatching callbacks, e.g. when `UtpSocket::send()`
+        // is called from a callback. Then commands are executed by the outermost call.
+        if self.destroyed.get() || self.depth.get() > 0 {
+            return;
+        }
+        self.enter();
+        while let Some(cmd) = self.commands.pop() {
+            let id = cmd.socket_id();
+            self.execute(cmd);
+            self.commands.check_drained(id);
+        }
+        self.leave();
+    }
+
+    fn execute(&self, cmd: Command) {
+        match cmd {
+            Command::Write(id, mut data) => {
+                let sock = match self.sockets.get(id) {
+                    Some(sock) => sock,
+                    None => return,
+                };
+                if data.is_empty() || self.commands.writes_failed(id) {
+                    return;
+                }
+                let res = unsafe { utp_write(sock, data.as_ptr() as *mut _, data.len()) };
+                if res < 0 {
+                    // the data is discarded, just like the socket would be closed
+                    self.commands.fail_writes(id);
+                    self.report_error(id, sock, UtpError::SendFailed);
+                } else if (res as usize) < data.len() {
+                    data.advance(res as usize);
+                    self.commands.block(Command::Write(id, data));
+                }
+            }
+            Command::Shutdown(id, how) => shutdown_socket(self, id, how),
+            Command::Close(id) => {
+                self.commands.forget(id);
+                if let Some(sock) = self.sockets.remove(id) {
+                    self.timers.on_close(id);
+                    unsafe {
+                        utp_close(sock);
+                    }
+                }
+            }
+            Command::Connect(id, addr) => {
+                let sock = unsafe { utp_create_socket(self.ctx) };
+                if sock.is_null() {
+                    self.commands.forget(id);
+                    self.report_error(id, sock, UtpError::ConnectFailed);
+                    return;
+                }
+                self.sockets.insert(id, sock);
+                let sockaddr = c_sock_addr(addr);
+                let res = unsafe {
+                    let (sockaddr, socklen) = sockaddr.as_ffi_pair();
+                    utp_connect(sock, sockaddr, socklen)
+                };
+                if res != 0 {
+                    self.report_error(id, sock, UtpError::ConnectFailed);
+                    self.execute(Command::Close(id));
+                }
+            }
+        }
+    }
+
+    fn enter(&self) {
+        self.depth.set(self.depth.get() + 1);
+    }
+
+    fn leave(&self) {
+        self.depth.set(self.depth.get() - 1);
+    }
+
+    /// Keeps the socket registry, deferred commands, timers and read buffers up to date with
+    /// socket state changes. Also records sent packets, if capture is enabled.
+    fn track_socket<T>(&self, cb_type: UtpCallbackType, args: &UtpCallbackArgs<T>) {
+        match cb_type {
+            // accepted sockets are owned by the context, unless taken over by `UtpSocket` handle
+            UtpCallbackType::OnAccept => {
+                let sock = args.raw_socket();
+                if !sock.is_null() && args.socket_id().is_none() {
+                    self.sockets.insert(self.sockets.next_id(), sock);
+                }
+            }
+            UtpCallbackType::OnRead => match args.socket_id() {
+                Some(id) if self.read_buffers.is_enabled() => {
+                    self.read_buffers.push(id, args.buf())
+                }
+                _ => (),
+            },
+            UtpCallbackType::OnStateChange => match (args.socket_id(), args.state()) {
+                (Some(id), Ok(UtpState::Connected)) | (Some(id), Ok(UtpState::Writable)) => {
+                    self.commands.unblock(id);
+                }
+                (Some(id), Ok(UtpState::ConnectionClosed)) if self.read_buffers.is_enabled() => {
+                    self.read_buffers.set_eof(id)
+                }
+                (Some(id), Ok(UtpState::Destroying)) => {
+                    self.commands.forget(id);
+                    self.timers.on_destroy(id);
+                    // `UtpSocket` handle can still read the data received before
+                    if !self.sockets.has_handle(id) {
+                        self.read_buffers.forget(id);
+                    }
+                    let _ = self.sockets.remove(id);
+                }
+                _ => (),
+            },
+            UtpCallbackType::OnError => {
+                if let Some(id) = args.socket_id() {
+                    self.timers.on_error(id);
+                }
+            }
+            UtpCallbackType::Sendto => {
+                if let Some(addr) = args.address() {
+                    self.capture.on_send(addr, args.buf(), self.system_time());
+                    if let Some(id) = args.socket_id() {
+                        self.timers.on_send(id, addr, args.buf(), self.now());
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Reports `UtpState::Writable` to the user callback for the sockets drained since the last
+    /// call. Arguments are filled in the same way libutp fills them for state changes.
+    fn report_writable(&self) {
+        if self.destroyed.get() || self.depth.get() > 0 {
+            return;
+        }
+        while let Some(id) = self.commands.take_writable() {
+            let sock = match self.sockets.get(id) {
+                Some(sock) => sock,
+                None => continue,
+            };
+            let mut args: utp_callback_arguments = unsafe { mem::zeroed() };
+            args.context = self.ctx;
+            args.socket = sock;
+            args.callback_type = UTP_ON_STATE_CHANGE as i32;
+            args.args1.state = UTP_STATE_WRITABLE as i32;
+            let _ = unsafe { (self.dispatch)(&UtpCallbackType::OnStateChange, &mut args) };
+            // the callback might have written more data
+            self.run_commands();
+        }
+    }
+
+    /// Reports that a deferred command failed. The failure goes through `OnError` callback just
+    /// like libutp errors do, so in event queue mode it's recorded as `UtpEvent::Error`. `sock` is
+    /// null, if libutp didn't create the socket at all.
+    fn report_error(&self, id: UtpSocketId, sock: *mut utp_socket, error: UtpError) {
+        *self.command_error.borrow_mut() = Some((id, error));
+        let mut args: utp_callback_arguments = unsafe { mem::zeroed() };
+        args.context = self.ctx;
+        args.socket = sock;
+        args.callback_type = UTP_ON_ERROR as i32;
+        let _ = unsafe { (self.dispatch)(&UtpCallbackType::OnError, &mut args) };
+        *self.command_error.borrow_mut() = None;
+    }
+
+    fn push_event(&self, event: UtpEvent) {
+        self.events.borrow_mut().push_back(event);
+    }
+}
+
+fn on_accept_event<T>(args: UtpCallbackArgs<T>) -> u64 {
+    let shared = &get_user_data_from_args(&args).shared;
+    let sock = make_utp_socket(args.raw_socket(), Rc::clone(shared));
+    // libutp only learns peer addresses from `process_udp()`, which takes `SocketAddr`, so this
+    // is always an IP address. Otherwise the socket would be closed right away without an event:
+    // its id was never reported, there's nothing the user could relate an error to.
+    if let Some(addr) = args.address() {
+        shared.push_event(UtpEvent::Accepted(sock, addr));
+    }
+    0
+}
+
+fn on_read_event<T>(mut args: UtpCallbackArgs<T>) -> u64 {
+    if let Some(id) = args.socket_id() {
+        let shared = &get_user_data_from_args(&args).shared;
+        if shared.read_buffers.is_enabled() {
+            // the data is acknowledged as it's read from the buffer
+            shared.push_event(UtpEvent::Readable(id));
+            return 0;
         }
+        shared.push_event(UtpEvent::Data(id, Bytes::from(args.buf())));
     }
+    args.ack_data();
+    0
+}
+
+fn on_state_change_event<T>(args: UtpCallbackArgs<T>) -> u64 {
+    let id = match args.socket_id() {
+        Some(id) => id,
+        None => return 0,
+    };
+    let event = match args.state() {
+        Ok(UtpState::Connected) => UtpEvent::Connected(id),
+        Ok(UtpState::Writable) => UtpEvent::Writable(id),
+        Ok(UtpState::ConnectionClosed) => UtpEvent::Eof(id),
+        Ok(UtpState::Destroying) => UtpEvent::Destroyed(id),
+        Err(_) => return 0,
+    };
+    get_user_data_from_args(&args).shared.push_event(event);
+    0
+}
+
+fn on_error_event<T>(args: UtpCallbackArgs<T>) -> u64 {
+    if let Some(id) = args.socket_id() {
+        get_user_data_from_args(&args)
+            .shared
+            .push_event(UtpEvent::Error(id, args.error()));
+    }
+    0
+}
+
+fn on_sendto_event<T>(args: UtpCallbackArgs<T>) -> u64 {
+    if let Some(addr) = args.address() {
+        let packet = Bytes::from(args.buf());
+        get_user_data_from_args(&args)
+            .shared
+            .push_event(UtpEvent::Transmit(packet, addr));
+    }
+    0
 }
 
 /// Initialize all possible uTP callbacks.
@@ -162,10 +637,10 @@ impl<T> Drop for UtpContext<T> {
 fn init_callbacks<T>(ctx: *mut utp_context) {
     macro_rules! set_callback {
         ($cb_type:expr) => {{
-            unsafe extern "C" fn c_utp_callback<T>(raw_args: *mut utp_callback_arguments) -> uint64 {
-                let args: UtpCallbackArgs<T> = UtpCallbackArgs::wrap(raw_args);
-                let args2 = UtpCallbackArgs::wrap(raw_args);
-                (*get_user_data_from_args(&args).callbacks[&$cb_type])(args2)
+            unsafe extern "C" fn c_utp_callback<T>(
+                raw_args: *mut utp_callback_arguments,
+            ) -> uint64 {
+                dispatch_callback::<T>(&$cb_type, raw_args)
             }
             unsafe { utp_set_callback(ctx, $cb_type as i32, Some(c_utp_callback::<T>)) }
         }};
@@ -174,14 +649,14 @@ fn init_callbacks<T>(ctx: *mut utp_context) {
     // TODO(povilas): uncomment once implemented, cause if `GetUdpMtu` is implemented unproperly,
     // it craches. Other callbacks coud crash too.
 
-    // set_callback!(UtpCallbackType::OnFirewall);
+    set_callback!(UtpCallbackType::OnFirewall);
     // set_callback!(UtpCallbackType::OnConnect);
     set_callback!(UtpCallbackType::OnAccept);
     set_callback!(UtpCallbackType::OnError);
     set_callback!(UtpCallbackType::OnRead);
     // set_callback!(UtpCallbackType::OnOverheadStatistics);
     set_callback!(UtpCallbackType::OnStateChange);
-    // set_callback!(UtpCallbackType::GetReadBufferSize);
+    set_callback!(UtpCallbackType::GetReadBufferSize);
     // set_callback!(UtpCallbackType::OnDelaySample);
     // set_callback!(UtpCallbackType::GetUdpMtu);
     // set_callback!(UtpCallbackType::GetUdpOverhead);
@@ -192,11 +667,140 @@ fn init_callbacks<T>(ctx: *mut utp_context) {
     set_callback!(UtpCallbackType::Sendto);
 }
 
-/// Converts Rust socket address into corresponding C data type.
-fn c_sock_addr(addr: SocketAddr) -> (sockaddr, u32) {
-    let sockaddr = SockAddr::new_inet(InetAddr::from_std(&addr));
-    let (sockaddr, socklen) = unsafe { sockaddr.as_ffi_pair() };
-    (*sockaddr, socklen)
+/// Makes libutp ask the context what time it is instead of reading the system clock.
+fn init_clock_callbacks<T>(ctx: *mut utp_context) {
+    unsafe extern "C" fn get_milliseconds<T>(raw_args: *mut utp_callback_arguments) -> uint64 {
+        dispatch_callback::<T>(&UtpCallbackType::GetMiliseconds, raw_args)
+    }
+    unsafe extern "C" fn get_microseconds<T>(raw_args: *mut utp_callback_arguments) -> uint64 {
+        dispatch_callback::<T>(&UtpCallbackType::GetMicroseconds, raw_args)
+    }
+    unsafe {
+        let get_milliseconds_type = UtpCallbackType::GetMiliseconds as i32;
+        let get_microseconds_type = UtpCallbackType::GetMicroseconds as i32;
+        utp_set_callback(ctx, get_milliseconds_type, Some(get_milliseconds::<T>));
+        utp_set_callback(ctx, get_microseconds_type, Some(get_microseconds::<T>));
+    }
+}
+
+/// Makes libutp ask the context for random numbers instead of calling `rand()`.
+fn init_random_callback<T>(ctx: *mut utp_context) {
+    unsafe extern "C" fn get_random<T>(raw_args: *mut utp_callback_arguments) -> uint64 {
+        dispatch_callback::<T>(&UtpCallbackType::GetRandom, raw_args)
+    }
+    unsafe {
+        utp_set_callback(
+            ctx,
+            UtpCallbackType::GetRandom as i32,
+            Some(get_random::<T>),
+        );
+    }
+}
+
+/// Calls user callback making sure that no panic unwinds into libutp which is undefined behavior.
+/// Instead, panic is stored in the context and resumed later.
+///
+/// # Safety
+///
+/// `raw_args` must be the arguments libutp passed to the callback of `UtpContext<T>`.
+unsafe fn dispatch_callback<T>(
+    cb_type: &UtpCallbackType,
+    raw_args: *mut utp_callback_arguments,
+) -> u64 {
+    let args: UtpCallbackArgs<T> = UtpCallbackArgs::wrap(raw_args);
+    let user_data = match panic::catch_unwind(AssertUnwindSafe(|| get_user_data_from_args(&args))) {
+        Ok(user_data) => user_data,
+        // there's no context to store the panic in, can't do much else
+        Err(_) => process::abort(),
+    };
+    let shared = &user_data.shared;
+    shared.enter();
+    // accepted sockets and received data must be known before the user callback is called
+    let track_first = matches!(
+        *cb_type,
+        UtpCallbackType::OnAccept | UtpCallbackType::OnRead
+    );
+    if track_first {
+        shared.track_socket(*cb_type, &args);
+    }
+    let res = if let Some(size) = read_buffer_size(*cb_type, shared, &args) {
+        Ok(size)
+    } else if let Some(time) = clock_time(*cb_type, shared) {
+        Ok(time)
+    } else if let Some(random) = random_value(*cb_type, shared) {
+        Ok(random)
+    } else if is_queue_full_writable(shared, &args) {
+        // the socket becomes writable for the user once the queued data is drained
+        Ok(0)
+    } else {
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            (*user_data.callbacks[cb_type])(UtpCallbackArgs::wrap(raw_args))
+        }))
+    };
+    if !track_first {
+        shared.track_socket(*cb_type, &args);
+    }
+    shared.leave();
+    match res {
+        Ok(res) => res,
+        Err(payload) => {
+            user_data.shared.panic.store(payload);
+            0
+        }
+    }
+}
+
+/// Answers libutp's `GetReadBufferSize` query, if the context buffers received data.
+fn read_buffer_size<T>(
+    cb_type: UtpCallbackType,
+    shared: &CtxShared,
+    args: &UtpCallbackArgs<T>,
+) -> Option<u64> {
+    if cb_type != UtpCallbackType::GetReadBufferSize || !shared.read_buffers.is_enabled() {
+        return None;
+    }
+    let size = args.socket_id().map_or(0, |id| shared.read_buffers.len(id));
+    Some(size as u64)
+}
+
+/// Answers libutp's time queries, if the context is driven by a manual clock.
+fn clock_time(cb_type: UtpCallbackType, shared: &CtxShared) -> Option<u64> {
+    let clock = shared.clock.borrow();
+    if !clock.is_manual() {
+        return None;
+    }
+    match cb_type {
+        UtpCallbackType::GetMiliseconds => Some(clock.micros() / 1000),
+        UtpCallbackType::GetMicroseconds => Some(clock.micros()),
+        _ => None,
+    }
+}
+
+/// Answers libutp's `GetRandom` query, if the context has a random seed.
+fn random_value(cb_type: UtpCallbackType, shared: &CtxShared) -> Option<u64> {
+    if cb_type != UtpCallbackType::GetRandom {
+        return None;
+    }
+    shared
+        .random
+        .borrow_mut()
+        .as_mut()
+        .map(|rng| u64::from(rng.next_u32()))
+}
+
+/// Returns `true`, if libutp reports `UtpState::Writable` for the socket whose write queue is above
+/// its high-water mark.
+fn is_queue_full_writable<T>(shared: &CtxShared, args: &UtpCallbackArgs<T>) -> bool {
+    match (args.socket_id(), args.state()) {
+        (Some(id), Ok(UtpState::Writable)) => shared.commands.is_full(id),
+        _ => false,
+    }
+}
+
+/// Converts Rust socket address into corresponding C data type. Use `SockAddr::as_ffi_pair()` to
+/// pass it to libutp: plain `sockaddr` is too small to hold IPv6 address.
+fn c_sock_addr(addr: SocketAddr) -> SockAddr {
+    SockAddr::new_inet(InetAddr::from_std(&normalize_addr(addr)))
 }
 
 /// libutp is capable of holding arbitrary user data. We will use this structure to hold our
@@ -204,10 +808,11 @@ fn c_sock_addr(addr: SocketAddr) -> (sockaddr, u32) {
 pub struct UtpUserData<T> {
     data: T,
     callbacks: HashMap<UtpCallbackType, UtpCallback<T>>,
+    shared: Rc<CtxShared>,
 }
 
 impl<T> UtpUserData<T> {
-    fn new(data: T) -> Self {
+    fn new(data: T, shared: Rc<CtxShared>) -> Self {
         // no operation - a.k.a do nothing default callbacks do nothing.
         let nop = Box::new(|_| 0);
         let mut callbacks: HashMap<UtpCallbackType, UtpCallback<T>> = HashMap::new();
@@ -228,11 +833,257 @@ impl<T> UtpUserData<T> {
         let _ = callbacks.insert(UtpCallbackType::Log, nop.clone());
         let _ = callbacks.insert(UtpCallbackType::Sendto, nop);
 
-        Self { data, callbacks }
+        Self {
+            data,
+            callbacks,
+            shared,
+        }
     }
 
     /// Returns reference to user data.
     pub fn data(&self) -> &T {
         &self.data
     }
+
+    /// Returns state shared with the context.
+    pub fn shared(&self) -> &CtxShared {
+        &self.shared
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc;
+    use nix::sys::socket::{sockaddr, sockaddr_in, sockaddr_in6};
+    use std::cell::RefCell;
+    use std::convert::TryFrom;
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv6Addr, SocketAddrV6};
+    use {UtpError, UtpErrorCode, UtpState};
+
+    /// Zeroed callback arguments, just like the ones libutp initializes before calling callbacks.
+    fn raw_args(cb_type: UtpCallbackType) -> utp_callback_arguments {
+        let mut args: utp_callback_arguments = unsafe { mem::zeroed() };
+        args.callback_type = cb_type as i32;
+        args
+    }
+
+    /// Feeds synthetic arguments to the callback trampoline and returns whatever `accessor`
+    /// extracts from them inside the user callback.
+    fn probe<R: 'static>(
+        mut args: utp_callback_arguments,
+        accessor: fn(&mut UtpCallbackArgs<()>) -> R,
+    ) -> R {
+        let cb_type = unwrap!(UtpCallbackType::try_from(args.callback_type));
+        let result = Rc::new(RefCell::new(None));
+        let result2 = Rc::clone(&result);
+        let mut utp = UtpContext::new(());
+        utp.set_callback(
+            cb_type,
+            Box::new(move |mut args| {
+                *result2.borrow_mut() = Some(accessor(&mut args));
+                0
+            }),
+        );
+        args.context = utp.ctx;
+
+        let _ = unsafe { dispatch_callback::<()>(&cb_type, &mut args) };
+        utp.shared.panic.resume();
+
+        let res = result.borrow_mut().take();
+        unwrap!(res, "Callback was not called")
+    }
+
+    #[test]
+    fn state_converts_known_values() {
+        let mut args = raw_args(UtpCallbackType::OnStateChange);
+        args.args1.state = UTP_STATE_WRITABLE as i32;
+
+        assert_eq!(probe(args, |args| args.state()), Ok(UtpState::Writable));
+    }
+
+    #[test]
+    fn state_reports_unknown_values() {
+        let mut args = raw_args(UtpCallbackType::OnStateChange);
+        args.args1.state = 42;
+
+        assert_eq!(
+            probe(args, |args| args.state()),
+            Err(UtpError::UnknownState(42))
+        );
+    }
+
+    #[test]
+    fn state_is_not_available_outside_state_change_callback() {
+        let mut args = raw_args(UtpCallbackType::OnRead);
+        args.args1.state = UTP_STATE_CONNECT as i32;
+
+        assert_eq!(
+            probe(args, |args| args.state()),
+            Err(UtpError::ArgumentNotAvailable("state"))
+        );
+    }
+
+    #[test]
+    fn error_converts_known_codes() {
+        let mut args = raw_args(UtpCallbackType::OnError);
+        args.args1.error_code = UTP_ETIMEDOUT as i32;
+
+        assert_eq!(
+            probe(args, |args| args.error_code()),
+            Ok(UtpErrorCode::TimedOut)
+        );
+        assert_eq!(
+            probe(args, |args| args.error().kind()),
+            io::ErrorKind::TimedOut
+        );
+    }
+
+    #[test]
+    fn error_reports_unknown_codes() {
+        let mut args = raw_args(UtpCallbackType::OnError);
+        args.args1.error_code = 7;
+
+        assert_eq!(
+            probe(args, |args| args.error_code()),
+            Err(UtpError::UnknownErrorCode(7))
+        );
+        assert_eq!(
+            probe(args, |args| args.error().kind()),
+            io::ErrorKind::Other
+        );
+    }
+
+    #[test]
+    fn unknown_callback_type_is_reported() {
+        assert_eq!(
+            UtpCallbackType::try_from(100),
+            Err(UtpError::UnknownCallbackType(100))
+        );
+        assert_eq!(
+            probe(raw_args(UtpCallbackType::OnRead), |args| args
+                .callback_type()),
+            Ok(UtpCallbackType::OnRead)
+        );
+    }
+
+    #[test]
+    fn buf_is_empty_when_null() {
+        let mut args = raw_args(UtpCallbackType::OnRead);
+        args.len = 1024;
+
+        assert_eq!(probe(args, |args| args.buf().to_vec()), Vec::<u8>::new());
+        assert_eq!(probe(args, |args| args.buf_as_string()), "");
+    }
+
+    #[test]
+    fn buf_points_to_given_data() {
+        static DATA: &[u8] = b"hello\0";
+        let mut args = raw_args(UtpCallbackType::Log);
+        args.buf = DATA.as_ptr();
+        args.len = DATA.len() - 1;
+
+        assert_eq!(probe(args, |args| args.buf().to_vec()), b"hello".to_vec());
+        assert_eq!(probe(args, |args| args.buf_as_string()), "hello");
+    }
+
+    #[test]
+    fn ack_data_ignores_null_socket() {
+        let args = raw_args(UtpCallbackType::OnRead);
+        probe(args, |args| args.ack_data());
+    }
+
+    #[test]
+    fn address_is_none_when_null() {
+        let args = raw_args(UtpCallbackType::Sendto);
+        assert_eq!(probe(args, |args| args.address()), None);
+    }
+
+    #[test]
+    fn address_converts_ipv4() {
+        let addr: SocketAddr = addr!("127.0.0.1:1234");
+        let sockaddr = c_sock_addr(addr);
+        let (sockaddr, socklen) = unsafe { sockaddr.as_ffi_pair() };
+        let mut args = raw_args(UtpCallbackType::OnAccept);
+        args.args1.address = sockaddr;
+        args.args2.address_len = socklen;
+
+        assert_eq!(probe(args, |args| args.address()), Some(addr));
+    }
+
+    #[test]
+    fn address_converts_ipv6() {
+        let addr = SocketAddrV6::new(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1), 1234, 0, 0);
+        let mut sockaddr: sockaddr_in6 = unsafe { mem::zeroed() };
+        sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        sockaddr.sin6_port = addr.port().to_be();
+        sockaddr.sin6_addr.s6_addr = addr.ip().octets();
+        let sockaddr_ptr: *const sockaddr_in6 = &sockaddr;
+        let mut args = raw_args(UtpCallbackType::Sendto);
+        args.args1.address = sockaddr_ptr as *const sockaddr;
+        args.args2.address_len = mem::size_of::<sockaddr_in6>() as u32;
+
+        assert_eq!(
+            probe(args, |args| args.address()),
+            Some(SocketAddr::V6(addr))
+        );
+    }
+
+    #[test]
+    fn address_converts_ipv4_mapped_ipv6_to_ipv4() {
+        let mut sockaddr: sockaddr_in6 = unsafe { mem::zeroed() };
+        sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        sockaddr.sin6_port = 1234u16.to_be();
+        sockaddr.sin6_addr.s6_addr = Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0x7f00, 1).octets();
+        let sockaddr_ptr: *const sockaddr_in6 = &sockaddr;
+        let mut args = raw_args(UtpCallbackType::Sendto);
+        args.args1.address = sockaddr_ptr as *const sockaddr;
+        args.args2.address_len = mem::size_of::<sockaddr_in6>() as u32;
+
+        assert_eq!(
+            probe(args, |args| args.address()),
+            Some(addr!("127.0.0.1:1234"))
+        );
+    }
+
+    #[test]
+    fn address_is_none_when_address_length_is_too_small() {
+        let mut sockaddr: sockaddr_in6 = unsafe { mem::zeroed() };
+        sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        let sockaddr_ptr: *const sockaddr_in6 = &sockaddr;
+        let mut args = raw_args(UtpCallbackType::Sendto);
+        args.args1.address = sockaddr_ptr as *const sockaddr;
+        args.args2.address_len = mem::size_of::<sockaddr_in>() as u32;
+
+        assert_eq!(probe(args, |args| args.address()), None);
+    }
+
+    #[test]
+    fn address_is_not_available_outside_address_callbacks() {
+        let mut args = raw_args(UtpCallbackType::OnStateChange);
+        // state and address share the same memory
+        args.args1.state = UTP_STATE_CONNECT as i32;
+
+        assert_eq!(probe(args, |args| args.address()), None);
+    }
+
+    #[test]
+    fn callback_panic_is_stored_in_context() {
+        let mut args = raw_args(UtpCallbackType::OnRead);
+        let mut utp = UtpContext::new(());
+        utp.set_callback(
+            UtpCallbackType::OnRead,
+            Box::new(|_| panic!("callback failed")),
+        );
+        args.context = utp.ctx;
+
+        let res = unsafe { dispatch_callback::<()>(&UtpCallbackType::OnRead, &mut args) };
+
+        assert_eq!(res, 0);
+        let payload =
+            unwrap!(panic::catch_unwind(AssertUnwindSafe(|| { utp.shared.panic.resume() })).err());
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"callback failed"));
+    }
 }
@@ -0,0 +1,119 @@
+//! Sharing a single UDP port between uTP and other protocols, e.g. DHT or STUN.
+
+use packet::{PacketType, HEADER_SIZE, VERSION};
+use std::net::SocketAddr;
+
+/// Returns `true`, if the packet looks like uTP packet: it's long enough to hold BEP 29 header
+/// which has the right version and a known packet type. The rest of the packet is not validated,
+/// that's up to libutp. See `packet::Packet::parse()` for the full validation.
+pub fn is_utp_packet(packet: &[u8]) -> bool {
+    packet.len() >= HEADER_SIZE
+        && packet[0] & 0x0f == VERSION
+        && packet[0] >> 4 <= PacketType::Syn as u8
+}
+
+/// Selects the packets a handler registered with `UdpDemux` receives.
+pub enum PacketFilter {
+    /// Packets starting with the given byte, e.g. `b'd'` for bencoded DHT messages.
+    FirstByte(u8),
+    /// Packets holding the given bytes at the given offset, e.g. STUN magic cookie
+    /// `[0x21, 0x12, 0xa4, 0x42]` at offset 4.
+    MagicCookie {
+        /// Where in the packet the cookie starts.
+        offset: usize,
+        /// Bytes the packet must hold.
+        cookie: Vec<u8>,
+    },
+    /// Packets the given function returns `true` for.
+    Predicate(PacketPredicate),
+}
+
+impl PacketFilter {
+    /// Returns `true`, if the packet passes this filter.
+    pub fn matches(&self, packet: &[u8]) -> bool {
+        match *self {
+            PacketFilter::FirstByte(byte) => packet.first() == Some(&byte),
+            PacketFilter::MagicCookie { offset, ref cookie } => packet
+                .get(offset..offset + cookie.len())
+                .map_or(false, |bytes| bytes == &cookie[..]),
+            PacketFilter::Predicate(ref predicate) => predicate(packet),
+        }
+    }
+}
+
+/// Decides whether `PacketFilter::Predicate` accepts a packet.
+pub type PacketPredicate = Box<dyn Fn(&[u8]) -> bool + Send>;
+
+/// Handles packets of some other protocol received over UDP socket of type `S`. Receives the
+/// packet, the sender's address and the socket to send replies over.
+pub type PacketHandler<S> = Box<dyn FnMut(&[u8], SocketAddr, &S) + Send>;
+
+/// Where `UdpDemux` routed a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketRoute {
+    /// One of the registered handlers took the packet.
+    Handler,
+    /// No handler wanted the packet and it looks like uTP packet: pass it to
+    /// `UtpContext::process_udp()`.
+    Utp,
+    /// Nobody wants the packet, it should be dropped.
+    Unknown,
+}
+
+/// Routes packets received over UDP socket shared by uTP and other protocols. Packets are offered
+/// to the registered handlers in the order they were added, the first handler whose filter
+/// matches takes the packet. Handlers go first, so that specific filters, e.g. STUN magic
+/// cookie, win over the uTP header sniff that some packets of other protocols might pass.
+/// Packets no handler takes are passed to uTP, if they look like uTP packets, see
+/// `is_utp_packet()`.
+///
+/// ```ignore
+/// let mut demux = UdpDemux::new();
+/// demux.add_handler(PacketFilter::FirstByte(b'd'), Box::new(|packet, addr, socket: &UdpSocket| {
+///     let _ = socket.send_to(&dht_reply(packet), addr);
+/// }));
+/// let (len, addr) = socket.recv_from(&mut buf)?;
+/// if demux.dispatch(&buf[..len], addr, &socket) == PacketRoute::Utp {
+///     utp.process_udp(&buf[..len], addr)?;
+/// }
+/// ```
+pub struct UdpDemux<S> {
+    handlers: Vec<(PacketFilter, PacketHandler<S>)>,
+}
+
+impl<S> UdpDemux<S> {
+    /// Creates a demultiplexer that has no handlers: all packets that look like uTP packets are
+    /// routed to uTP.
+    pub fn new() -> Self {
+        Self {
+            handlers: Vec::new(),
+        }
+    }
+
+    /// Registers a handler for the packets that pass the given filter.
+    pub fn add_handler(&mut self, filter: PacketFilter, handler: PacketHandler<S>) {
+        self.handlers.push((filter, handler));
+    }
+
+    /// Passes the packet to the first handler that wants it. Otherwise tells if the packet
+    /// should be passed to uTP.
+    pub fn dispatch(&mut self, packet: &[u8], sender_addr: SocketAddr, socket: &S) -> PacketRoute {
+        for &mut (ref filter, ref mut handler) in &mut self.handlers {
+            if filter.matches(packet) {
+                handler(packet, sender_addr, socket);
+                return PacketRoute::Handler;
+            }
+        }
+        if is_utp_packet(packet) {
+            PacketRoute::Utp
+        } else {
+            PacketRoute::Unknown
+        }
+    }
+}
+
+impl<S> Default for UdpDemux<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
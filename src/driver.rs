@@ -0,0 +1,671 @@
+//! Background thread that drives uTP context over UDP socket.
+
+use super::{
+    addr_for_socket, SharedUtpContext, SharedUtpSocket, UtpCallbackType, UtpError, UtpSocketId,
+    UtpState,
+};
+use demux::{PacketFilter, PacketHandler, PacketRoute, UdpDemux};
+#[cfg(feature = "mio")]
+use evented::stream_readiness;
+#[cfg(feature = "mio")]
+use mio::{Ready, SetReadiness};
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+use std::task::{Poll, Waker};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The longest the driver thread blocks on UDP socket. Normally the thread is woken up by a
+/// datagram when the driver is dropped, this is just a safety net in case it gets lost.
+const MAX_RECV_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long the driver thread waits for more datagrams before sending deferred ACKs.
+const PENDING_RECV_TIMEOUT: Duration = Duration::from_millis(1);
+/// Big enough for any UDP packet.
+pub const MAX_PACKET_SIZE: usize = 64 * 1024;
+
+/// Owns UDP socket and uTP context and runs the usual loop on a background thread:
+/// feeds received packets to the context, sends deferred ACKs once there are no more packets to
+/// read and checks timeouts when the context asks for it. The thread is stopped when the driver
+/// is dropped.
+///
+/// Incoming connections are queued until accepted. When the queue is full, connection requests
+/// are ignored and remote peers keep retrying.
+///
+/// Packets of other protocols sharing the socket are routed to the handlers registered with
+/// `add_packet_handler()`, the rest of non-uTP packets are dropped.
+pub struct Driver {
+    utp: SharedUtpContext<()>,
+    socket: Arc<UdpSocket>,
+    demux: Arc<Mutex<UdpDemux<UdpSocket>>>,
+    streams: Arc<Streams>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Driver {
+    /// Binds UDP socket to the given address and starts the driver thread. At most `backlog`
+    /// incoming connections are queued.
+    pub fn bind(addr: SocketAddr, backlog: usize) -> io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr)?);
+        let utp = SharedUtpContext::new(());
+        let streams = Arc::new(Streams::new(backlog));
+        let socket2 = Arc::clone(&socket);
+        init_callbacks(&utp, &streams, socket.local_addr()?, move |packet, addr| {
+            // lost packets are retransmitted by libutp
+            let _ = socket2.send_to(packet, addr);
+        });
+
+        let demux = Arc::new(Mutex::new(UdpDemux::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = {
+            let utp = utp.clone();
+            let socket = Arc::clone(&socket);
+            let demux = Arc::clone(&demux);
+            let running = Arc::clone(&running);
+            thread::spawn(move || run(&utp, &socket, &demux, &running))
+        };
+        Ok(Self {
+            utp,
+            socket,
+            demux,
+            streams,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns uTP context driven by this driver.
+    pub fn utp(&self) -> &SharedUtpContext<()> {
+        &self.utp
+    }
+
+    /// Returns state of the connections made over this driver.
+    pub fn streams(&self) -> &Streams {
+        &self.streams
+    }
+
+    /// Returns the address UDP socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Routes received packets that pass the filter to the handler, which is called on the
+    /// driver thread.
+    pub fn add_packet_handler(&self, filter: PacketFilter, handler: PacketHandler<UdpSocket>) {
+        self.demux
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .add_handler(filter, handler);
+    }
+
+    /// Sends a datagram over the driver's UDP socket.
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.socket.send_to(buf, addr)
+    }
+}
+
+impl Drop for Driver {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        // wake up the driver thread, if it's blocked on the socket
+        if let Ok(local_addr) = self.socket.local_addr() {
+            let _ = self.socket.send_to(&[], wake_addr(local_addr));
+        }
+        if let Some(thread) = self.thread.take() {
+            // the thread doesn't panic unless libutp callbacks do
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Sets the callbacks that keep `streams` up to date. Packets libutp produces are passed to
+/// `send_to`.
+pub fn init_callbacks<S>(
+    utp: &SharedUtpContext<()>,
+    streams: &Arc<Streams>,
+    local_addr: SocketAddr,
+    send_to: S,
+) where
+    S: Fn(&[u8], SocketAddr) + Send + 'static,
+{
+    // received data is acknowledged as stream handles read it, so that slow readers throttle
+    // remote peers
+    utp.enable_read_buffers();
+    utp.set_callback(
+        UtpCallbackType::Sendto,
+        Box::new(move |args| {
+            if let Some(addr) = args.address() {
+                send_to(args.buf(), addr_for_socket(addr, &local_addr));
+            }
+            0
+        }),
+    );
+
+    let streams2 = Arc::clone(streams);
+    utp.set_callback(
+        UtpCallbackType::OnFirewall,
+        Box::new(move |_| u64::from(!streams2.can_accept())),
+    );
+
+    let streams2 = Arc::clone(streams);
+    utp.set_callback(
+        UtpCallbackType::OnAccept,
+        Box::new(move |args| {
+            if let (Some(id), Some(addr)) = (args.socket_id(), args.address()) {
+                streams2.push_incoming(id, addr);
+            }
+            0
+        }),
+    );
+
+    let streams2 = Arc::clone(streams);
+    utp.set_callback(
+        UtpCallbackType::OnRead,
+        Box::new(move |args| {
+            if let Some(id) = args.socket_id() {
+                streams2.update(id, |stream| stream.readable = true);
+            }
+            0
+        }),
+    );
+
+    let streams2 = Arc::clone(streams);
+    utp.set_callback(
+        UtpCallbackType::OnStateChange,
+        Box::new(move |args| {
+            let id = match args.socket_id() {
+                Some(id) => id,
+                None => return 0,
+            };
+            match args.state() {
+                Ok(UtpState::Connected) => streams2.update(id, |stream| {
+                    stream.connected = true;
+                    stream.writable = true;
+                }),
+                Ok(UtpState::Writable) => streams2.update(id, |stream| stream.writable = true),
+                Ok(UtpState::ConnectionClosed) => {
+                    streams2.update(id, |stream| stream.read_closed = true)
+                }
+                Ok(UtpState::Destroying) => streams2.destroy(id, args.delivered()),
+                Err(_) => (),
+            }
+            0
+        }),
+    );
+
+    let streams2 = Arc::clone(streams);
+    utp.set_callback(
+        UtpCallbackType::OnError,
+        Box::new(move |args| {
+            if let Some(id) = args.socket_id() {
+                let error = args.error().kind();
+                streams2.update(id, |stream| stream.error = Some(error));
+            }
+            0
+        }),
+    );
+}
+
+fn run(
+    utp: &SharedUtpContext<()>,
+    socket: &UdpSocket,
+    demux: &Mutex<UdpDemux<UdpSocket>>,
+    running: &AtomicBool,
+) {
+    let mut buf = vec![0; MAX_PACKET_SIZE];
+    while running.load(Ordering::SeqCst) {
+        let recv_timeout = match utp.next_timeout() {
+            Some(timeout) if timeout == Duration::from_secs(0) => {
+                utp.check_timeouts();
+                continue;
+            }
+            Some(timeout) => cmp::min(timeout, MAX_RECV_TIMEOUT),
+            None => MAX_RECV_TIMEOUT,
+        };
+        if socket.set_read_timeout(Some(recv_timeout)).is_err() {
+            return;
+        }
+        if let Ok((len, sender_addr)) = socket.recv_from(&mut buf) {
+            process_packet(utp, socket, demux, &buf[..len], sender_addr);
+            process_pending_packets(utp, socket, demux, &mut buf);
+            utp.ack_packets();
+        }
+    }
+}
+
+/// Returns the address datagrams to the socket bound to `local_addr` can be sent to.
+fn wake_addr(local_addr: SocketAddr) -> SocketAddr {
+    match local_addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, local_addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, local_addr.port()).into(),
+        _ => local_addr,
+    }
+}
+
+/// Processes packets that are already received, so that ACKs could be deferred until there's
+/// nothing more to read.
+///
+/// Other threads send packets over the same socket, so it's never switched to nonblocking mode:
+/// `send_to()` could fail with `WouldBlock` then. Nonblocking mode is shared by cloned handles
+/// too, hence reads just time out as soon as possible instead.
+fn process_pending_packets(
+    utp: &SharedUtpContext<()>,
+    socket: &UdpSocket,
+    demux: &Mutex<UdpDemux<UdpSocket>>,
+    buf: &mut [u8],
+) {
+    if socket.set_read_timeout(Some(PENDING_RECV_TIMEOUT)).is_err() {
+        return;
+    }
+    while let Ok((len, sender_addr)) = socket.recv_from(buf) {
+        process_packet(utp, socket, demux, &buf[..len], sender_addr);
+    }
+}
+
+/// Passes the packet either to a registered handler or to uTP context.
+fn process_packet(
+    utp: &SharedUtpContext<()>,
+    socket: &UdpSocket,
+    demux: &Mutex<UdpDemux<UdpSocket>>,
+    packet: &[u8],
+    sender_addr: SocketAddr,
+) {
+    let route = demux
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .dispatch(packet, sender_addr, socket);
+    if route == PacketRoute::Utp {
+        // illegal packets are simply ignored
+        let _ = utp.process_udp(packet, sender_addr);
+    }
+}
+
+/// State of a single uTP connection as reported by libutp callbacks.
+#[derive(Default)]
+pub struct StreamState {
+    /// Data was received since the stream handle last found the socket's read buffer empty.
+    pub readable: bool,
+    /// Outgoing connection was established.
+    pub connected: bool,
+    /// libutp is able to accept more outgoing data.
+    pub writable: bool,
+    /// No more data will be received.
+    pub read_closed: bool,
+    /// Stream was shut down for writing.
+    pub write_closed: bool,
+    /// Connection failed.
+    pub error: Option<io::ErrorKind>,
+    /// Stream handle closed the socket and waits until libutp destroys it.
+    pub closing: bool,
+    /// Set once the closed socket is destroyed: `true`, if remote peer acknowledged everything.
+    pub delivered: Option<bool>,
+    /// Tasks waiting for the state to change.
+    #[cfg(any(feature = "tokio", feature = "futures-io"))]
+    wakers: Vec<Waker>,
+    /// The waiting tasks are woken up once this passes, even if the state doesn't change.
+    #[cfg(any(feature = "tokio", feature = "futures-io"))]
+    pub deadline: Option<Instant>,
+}
+
+/// Connection states shared between the driver thread and stream handles. Callbacks are called
+/// while the context lock is held, hence stream handles must never lock the context while holding
+/// the streams lock.
+pub struct Streams {
+    inner: Mutex<StreamsInner>,
+    changed: Condvar,
+}
+
+struct StreamsInner {
+    states: HashMap<UtpSocketId, StreamState>,
+    /// Accepted connections waiting to be taken over by stream handles.
+    incoming: VecDeque<(UtpSocketId, SocketAddr)>,
+    backlog: usize,
+    /// Readiness of evented streams.
+    #[cfg(feature = "mio")]
+    readiness: HashMap<UtpSocketId, SetReadiness>,
+    /// Readiness of evented endpoint: readable, when there are incoming connections.
+    #[cfg(feature = "mio")]
+    incoming_readiness: Option<SetReadiness>,
+    /// Tasks waiting for incoming connections.
+    #[cfg(any(feature = "tokio", feature = "futures-io"))]
+    incoming_wakers: Vec<Waker>,
+}
+
+impl Streams {
+    /// Creates connection states for the driver that queues at most `backlog` incoming
+    /// connections.
+    pub fn new(backlog: usize) -> Self {
+        Self {
+            inner: Mutex::new(StreamsInner {
+                states: HashMap::new(),
+                incoming: VecDeque::new(),
+                backlog,
+                #[cfg(feature = "mio")]
+                readiness: HashMap::new(),
+                #[cfg(feature = "mio")]
+                incoming_readiness: None,
+                #[cfg(any(feature = "tokio", feature = "futures-io"))]
+                incoming_wakers: Vec::new(),
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Updates the state of the given connection and wakes up the threads waiting for it.
+    pub fn update<F, R>(&self, id: UtpSocketId, f: F) -> R
+    where
+        F: FnOnce(&mut StreamState) -> R,
+    {
+        let mut inner = self.lock();
+        let res = f(inner.state(id));
+        #[cfg(feature = "mio")]
+        inner.update_readiness(id);
+        #[cfg(any(feature = "tokio", feature = "futures-io"))]
+        wake_all(&mut inner.state(id).wakers);
+        self.changed.notify_all();
+        res
+    }
+
+    /// Reads the data the context buffered for the socket. `None` means there's nothing to read
+    /// yet: the caller should wait until the stream is `readable`, read-closed or failed.
+    /// Locks the context, hence must not be called with the streams lock held.
+    pub fn try_read(
+        &self,
+        sock: &SharedUtpSocket<()>,
+        buf: &mut [u8],
+    ) -> Option<io::Result<usize>> {
+        let id = sock.id();
+        // if data arrives while we're reading, the flag is set again and we don't miss it
+        self.set_readable(id, false);
+        let error = match sock.read(buf) {
+            Ok(len) => {
+                if len > 0 && sock.buffered_len() > 0 {
+                    self.set_readable(id, true);
+                }
+                return Some(Ok(len));
+            }
+            Err(error) => error,
+        };
+        let mut inner = self.lock();
+        let stream = inner.state(id);
+        if stream.read_closed {
+            return Some(Ok(0));
+        }
+        if let Some(error) = stream.error {
+            return Some(Err(error.into()));
+        }
+        match error {
+            UtpError::WouldBlock => None,
+            // e.g. the socket was destroyed
+            error => Some(Err(error.into())),
+        }
+    }
+
+    /// Sets the `readable` flag without waking up anyone: only the reader cares about it.
+    fn set_readable(&self, id: UtpSocketId, readable: bool) {
+        let mut inner = self.lock();
+        inner.state(id).readable = readable;
+        #[cfg(feature = "mio")]
+        inner.update_readiness(id);
+    }
+
+    /// Calls `f` with the state of the given connection without waking up anyone.
+    #[cfg(any(feature = "tokio", feature = "futures-io"))]
+    pub fn inspect<F, R>(&self, id: UtpSocketId, f: F) -> R
+    where
+        F: FnOnce(&mut StreamState) -> R,
+    {
+        f(self.lock().state(id))
+    }
+
+    /// Calls `f` with the state of the given connection. If `f` returns nothing, the task is
+    /// woken up once the state changes.
+    #[cfg(any(feature = "tokio", feature = "futures-io"))]
+    pub fn poll<F, R>(&self, id: UtpSocketId, waker: &Waker, f: F) -> Poll<R>
+    where
+        F: FnOnce(&mut StreamState) -> Option<R>,
+    {
+        let mut inner = self.lock();
+        let state = inner.state(id);
+        match f(state) {
+            Some(res) => Poll::Ready(res),
+            None => {
+                add_waker(&mut state.wakers, waker);
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Returns the earliest deadline of all connections.
+    #[cfg(any(feature = "tokio", feature = "futures-io"))]
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.lock()
+            .states
+            .values()
+            .filter_map(|state| state.deadline)
+            .min()
+    }
+
+    /// Wakes up the tasks waiting for connections whose deadlines have passed.
+    #[cfg(any(feature = "tokio", feature = "futures-io"))]
+    pub fn wake_expired(&self, now: Instant) {
+        for state in self.lock().states.values_mut() {
+            if state.deadline.map_or(false, |deadline| deadline <= now) {
+                state.deadline = None;
+                wake_all(&mut state.wakers);
+            }
+        }
+    }
+
+    /// Blocks until `f` returns something or the deadline passes. `f` is called with the lock
+    /// held every time the state of any connection changes.
+    pub fn wait<F, R>(&self, id: UtpSocketId, deadline: Option<Instant>, mut f: F) -> io::Result<R>
+    where
+        F: FnMut(&mut StreamState) -> Option<R>,
+    {
+        let mut inner = self.lock();
+        loop {
+            if let Some(res) = f(inner.state(id)) {
+                return Ok(res);
+            }
+            inner = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::ErrorKind::TimedOut.into());
+                    }
+                    self.changed
+                        .wait_timeout(inner, deadline - now)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .0
+                }
+                None => self
+                    .changed
+                    .wait(inner)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()),
+            };
+        }
+    }
+
+    /// Reports that libutp destroyed the socket. The state is forgotten, unless the stream
+    /// handle is waiting for the socket to close or may still read after shutting down writes.
+    pub fn destroy(&self, id: UtpSocketId, delivered: bool) {
+        let mut inner = self.lock();
+        let in_use = inner
+            .states
+            .get(&id)
+            .map_or(false, |state| state.closing || state.write_closed);
+        if !in_use {
+            inner.remove(id);
+            return;
+        }
+        {
+            let state = inner.state(id);
+            // the data already received can be read, but unless remote peer has closed its half,
+            // the rest of its data is cut off
+            if state.error.is_none() && !state.read_closed {
+                state.error = Some(io::ErrorKind::ConnectionAborted);
+            }
+            state.delivered = Some(delivered);
+            #[cfg(any(feature = "tokio", feature = "futures-io"))]
+            wake_all(&mut state.wakers);
+        }
+        #[cfg(feature = "mio")]
+        inner.update_readiness(id);
+        self.changed.notify_all();
+    }
+
+    /// Forgets the connection.
+    pub fn remove(&self, id: UtpSocketId) {
+        self.lock().remove(id);
+    }
+
+    /// Keeps readiness of the given connection up to date with its state.
+    #[cfg(feature = "mio")]
+    pub fn set_readiness(&self, id: UtpSocketId, readiness: SetReadiness) {
+        let mut inner = self.lock();
+        let _ = inner.readiness.insert(id, readiness);
+        inner.update_readiness(id);
+    }
+
+    /// Makes the given readiness readable whenever there are incoming connections.
+    #[cfg(feature = "mio")]
+    pub fn set_incoming_readiness(&self, readiness: SetReadiness) {
+        let mut inner = self.lock();
+        inner.incoming_readiness = Some(readiness);
+        inner.update_incoming_readiness();
+    }
+
+    /// Returns `true`, if there's room for more incoming connections.
+    pub fn can_accept(&self) -> bool {
+        let inner = self.lock();
+        inner.incoming.len() < inner.backlog
+    }
+
+    /// Queues accepted connection.
+    pub fn push_incoming(&self, id: UtpSocketId, addr: SocketAddr) {
+        let mut inner = self.lock();
+        inner.incoming.push_back((id, addr));
+        #[cfg(feature = "mio")]
+        inner.update_incoming_readiness();
+        #[cfg(any(feature = "tokio", feature = "futures-io"))]
+        wake_all(&mut inner.incoming_wakers);
+        self.changed.notify_all();
+    }
+
+    /// Returns an accepted connection or wakes up the task once there is one.
+    #[cfg(any(feature = "tokio", feature = "futures-io"))]
+    pub fn poll_incoming(&self, waker: &Waker) -> Poll<(UtpSocketId, SocketAddr)> {
+        let mut inner = self.lock();
+        match inner.incoming.pop_front() {
+            Some(incoming) => {
+                #[cfg(feature = "mio")]
+                inner.update_incoming_readiness();
+                Poll::Ready(incoming)
+            }
+            None => {
+                add_waker(&mut inner.incoming_wakers, waker);
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Returns an accepted connection, if there is one.
+    #[cfg(feature = "mio")]
+    pub fn try_pop_incoming(&self) -> Option<(UtpSocketId, SocketAddr)> {
+        let mut inner = self.lock();
+        let incoming = inner.incoming.pop_front();
+        #[cfg(feature = "mio")]
+        inner.update_incoming_readiness();
+        incoming
+    }
+
+    /// Blocks until there's an accepted connection.
+    pub fn pop_incoming(&self) -> (UtpSocketId, SocketAddr) {
+        let mut inner = self.lock();
+        loop {
+            if let Some(incoming) = inner.incoming.pop_front() {
+                #[cfg(feature = "mio")]
+                inner.update_incoming_readiness();
+                return incoming;
+            }
+            inner = self
+                .changed
+                .wait(inner)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+
+    /// Stops accepting connections and returns the ones that were not taken yet.
+    pub fn close_incoming(&self) -> Vec<UtpSocketId> {
+        let mut inner = self.lock();
+        inner.backlog = 0;
+        let ids = inner.incoming.drain(..).map(|(id, _)| id).collect();
+        #[cfg(feature = "mio")]
+        inner.update_incoming_readiness();
+        ids
+    }
+
+    fn lock(&self) -> MutexGuard<StreamsInner> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl StreamsInner {
+    fn state(&mut self, id: UtpSocketId) -> &mut StreamState {
+        self.states.entry(id).or_default()
+    }
+
+    fn remove(&mut self, id: UtpSocketId) {
+        let _ = self.states.remove(&id);
+        #[cfg(feature = "mio")]
+        let _ = self.readiness.remove(&id);
+    }
+
+    #[cfg(feature = "mio")]
+    fn update_readiness(&mut self, id: UtpSocketId) {
+        if let Some(readiness) = self.readiness.get(&id) {
+            let ready = self
+                .states
+                .get(&id)
+                .map_or(Ready::empty(), stream_readiness);
+            // fails only if the registration is gone, then nobody is interested in readiness
+            let _ = readiness.set_readiness(ready);
+        }
+    }
+
+    #[cfg(feature = "mio")]
+    fn update_incoming_readiness(&self) {
+        if let Some(ref readiness) = self.incoming_readiness {
+            let ready = if self.incoming.is_empty() {
+                Ready::empty()
+            } else {
+                Ready::readable()
+            };
+            let _ = readiness.set_readiness(ready);
+        }
+    }
+}
+
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+fn add_waker(wakers: &mut Vec<Waker>, waker: &Waker) {
+    if !wakers.iter().any(|w| w.will_wake(waker)) {
+        wakers.push(waker.clone());
+    }
+}
+
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+fn wake_all(wakers: &mut Vec<Waker>) {
+    for waker in mem::take(wakers) {
+        waker.wake();
+    }
+}
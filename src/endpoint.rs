@@ -0,0 +1,150 @@
+//! uTP endpoint that drives uTP context over its own UDP socket.
+
+use demux::PacketFilter;
+use driver::Driver;
+use listener::Incoming;
+use socket::UtpSocketId;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+use stream::{self, UtpStream};
+
+/// How many incoming connections are queued until accepted by default.
+pub const DEFAULT_BACKLOG: usize = 128;
+
+/// Owns UDP socket and `UtpContext` and runs the usual loop on a background thread: feeds
+/// received packets to the context, sends deferred ACKs once there are no more packets to read
+/// and checks timeouts whenever the context needs it. Packets libutp produces are sent over the
+/// same UDP socket.
+///
+/// Any number of connections can be made and accepted over a single endpoint. They share the UDP
+/// socket and the driver thread with the endpoint, hence the thread keeps running until the
+/// endpoint and all its streams are dropped.
+///
+/// The UDP socket can be shared with other protocols, e.g. DHT or STUN: their packets are routed
+/// to the handlers registered with `add_packet_handler()` and non-uTP packets nobody handles are
+/// dropped.
+pub struct UtpEndpoint {
+    driver: Arc<Driver>,
+}
+
+impl UtpEndpoint {
+    /// Creates a new endpoint bound to the given address. If `addr` yields multiple addresses,
+    /// binding is attempted with each of them until it succeeds.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UtpEndpoint> {
+        Self::bind_with_backlog(addr, DEFAULT_BACKLOG)
+    }
+
+    /// Creates a new endpoint that queues at most `backlog` incoming connections until they are
+    /// accepted. When the queue is full, connection requests are ignored and remote peers retry
+    /// them later, just like with TCP. Zero backlog means no incoming connections are accepted.
+    pub fn bind_with_backlog<A: ToSocketAddrs>(addr: A, backlog: usize) -> io::Result<UtpEndpoint> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match Driver::bind(addr, backlog) {
+                Ok(driver) => {
+                    return Ok(UtpEndpoint {
+                        driver: Arc::new(driver),
+                    })
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any addresses",
+            )
+        }))
+    }
+
+    /// Returns the address UDP socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.driver.local_addr()
+    }
+
+    /// Routes received packets that pass the given filter to the handler instead of uTP. Packets
+    /// are offered to the handlers in the order they were added, see `UdpDemux`.
+    ///
+    /// The handler is called on the driver thread with the packet, the sender's address and the
+    /// endpoint's UDP socket to send replies over. It should return quickly, since uTP packets
+    /// are not processed meanwhile.
+    pub fn add_packet_handler<F>(&self, filter: PacketFilter, handler: F)
+    where
+        F: FnMut(&[u8], SocketAddr, &UdpSocket) + Send + 'static,
+    {
+        self.driver.add_packet_handler(filter, Box::new(handler));
+    }
+
+    /// Sends a datagram over the endpoint's UDP socket, e.g. a query of another protocol sharing
+    /// the port.
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.driver.send_to(buf, addr)
+    }
+
+    /// Opens uTP connection to a remote host. The call blocks until the connection is established
+    /// or libutp gives up.
+    pub fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        stream::connect_with(Arc::clone(&self.driver), addr, None)
+    }
+
+    /// Opens uTP connection to a remote host, giving up once the timeout elapses. See
+    /// `UtpStream::connect_timeout()`.
+    pub fn connect_timeout(&self, addr: SocketAddr, timeout: Duration) -> io::Result<UtpStream> {
+        stream::connect_with(Arc::clone(&self.driver), addr, Some(timeout))
+    }
+
+    /// Accepts a new incoming connection. This call blocks until a new connection is established.
+    pub fn accept(&self) -> io::Result<(UtpStream, SocketAddr)> {
+        loop {
+            let (id, addr) = self.driver.streams().pop_incoming();
+            if let Some(stream) = self.take_incoming(id) {
+                return Ok((stream, addr));
+            }
+        }
+    }
+
+    /// Returns an iterator over the connections being received on this endpoint. The iterator
+    /// never returns `None`.
+    pub fn incoming(&self) -> Incoming {
+        Incoming::new(self)
+    }
+
+    /// Accepts a new incoming connection, if there is one, without blocking.
+    #[cfg(feature = "mio")]
+    pub(crate) fn try_accept(&self) -> Option<(UtpStream, SocketAddr)> {
+        while let Some((id, addr)) = self.driver.streams().try_pop_incoming() {
+            if let Some(stream) = self.take_incoming(id) {
+                return Some((stream, addr));
+            }
+        }
+        None
+    }
+
+    #[cfg(feature = "mio")]
+    pub(crate) fn driver(&self) -> &Arc<Driver> {
+        &self.driver
+    }
+
+    fn take_incoming(&self, id: UtpSocketId) -> Option<UtpStream> {
+        // the socket might have failed while waiting in the queue
+        match self.driver.utp().take_socket(id) {
+            Some(sock) => Some(stream::from_socket(sock, Arc::clone(&self.driver))),
+            None => {
+                self.driver.streams().remove(id);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for UtpEndpoint {
+    fn drop(&mut self) {
+        // streams might keep the driver running, don't let more connections in
+        for id in self.driver.streams().close_incoming() {
+            let _ = self.driver.utp().take_socket(id);
+            self.driver.streams().remove(id);
+        }
+    }
+}
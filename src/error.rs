@@ -3,12 +3,14 @@
 // NOTE, this code must not be in the same module that imports utp_sys, otherwise bindgen produced
 // code somehow conflicts with quick_error.
 
+use std::io;
+
 // TODO(povilas): split this error into multiple: SendError, etc.
 // Otherwise we have to handle all irrelevant cases, e.g. when `UtpSocket::send()` is called
 // `ConnectFailed` is impossible.
 quick_error! {
     /// Will cover all uTP errors.
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, Clone, PartialEq)]
     pub enum UtpError {
         /// Failure to write data to uTP socket. The reason is unknown because the underlying C library
         /// doesn't expose more info.
@@ -19,6 +21,10 @@ quick_error! {
         ConnectFailed {
             display("Failed to establish uTP connection")
         }
+        /// Connection was not established before the deadline given to `connect_timeout()`.
+        ConnectTimedOut {
+            display("uTP connection was not established in time")
+        }
         /// 0 bytes were writen to uTP socket which means that we should wait until the socket gets
         /// writable again.
         WouldBlock {
@@ -32,5 +38,94 @@ quick_error! {
         IllegalPacket {
             display("UDP packet was not legal uTP packet")
         }
+        /// libutp passed connection state we don't know about.
+        UnknownState(state: i32) {
+            display("Unknown uTP connection state: {}", state)
+        }
+        /// libutp passed error code we don't know about.
+        UnknownErrorCode(code: i32) {
+            display("Unknown uTP error code: {}", code)
+        }
+        /// libutp called callback we don't know about.
+        UnknownCallbackType(cb_type: i32) {
+            display("Unknown uTP callback type: {}", cb_type)
+        }
+        /// Requested callback argument is not set for the callback being called, e.g. connection
+        /// state is only passed to `UtpCallbackType::OnStateChange`.
+        ArgumentNotAvailable(arg: &'static str) {
+            display("Argument '{}' is not available for this callback type", arg)
+        }
+        /// `UtpContext` the socket belongs to was dropped, hence the socket is closed too.
+        ContextDestroyed {
+            display("uTP context this socket belongs to is already destroyed")
+        }
+        /// Socket was closed by a deferred command or destroyed by libutp.
+        SocketClosed {
+            display("uTP socket is already closed")
+        }
+        /// Write queue's low-water mark must not exceed its high-water mark.
+        InvalidWaterMarks {
+            display("Low-water mark is greater than high-water mark")
+        }
+        /// `UtpSocket::read()` was called, but the context hands received data to `OnRead`
+        /// callback instead of buffering it.
+        ReadBuffersDisabled {
+            display("uTP context does not buffer received data")
+        }
+        /// Socket was destroyed before remote peer acknowledged all the data sent over it.
+        NotDelivered {
+            display("Remote peer did not acknowledge all data sent over uTP socket")
+        }
+    }
+}
+
+quick_error! {
+    /// Reasons `Packet::parse()` rejects a packet or `PacketHeader::encode()` rejects
+    /// extensions.
+    #[derive(Debug, PartialEq)]
+    pub enum PacketError {
+        /// Packet is shorter than uTP header.
+        TooShort(len: usize) {
+            display("{} bytes is too short for uTP packet", len)
+        }
+        /// Packet version is not 1.
+        UnsupportedVersion(version: u8) {
+            display("Unsupported uTP version: {}", version)
+        }
+        /// Packet type is not one of `PacketType`.
+        UnknownPacketType(packet_type: u8) {
+            display("Unknown uTP packet type: {}", packet_type)
+        }
+        /// Extension of the given type runs past the end of the packet.
+        TruncatedExtension(kind: u8) {
+            display("uTP extension {} is truncated", kind)
+        }
+        /// Selective ACK bitmask length is not a non-zero multiple of 4.
+        InvalidSelectiveAck(len: usize) {
+            display("Invalid uTP selective ACK length: {}", len)
+        }
+        /// Extension type is zero or its data is longer than 255 bytes.
+        InvalidExtension(kind: u8, len: usize) {
+            display("Invalid uTP extension {} of {} bytes", kind, len)
+        }
+    }
+}
+
+impl From<PacketError> for io::Error {
+    fn from(e: PacketError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+impl From<UtpError> for io::Error {
+    fn from(e: UtpError) -> Self {
+        let kind = match e {
+            UtpError::WouldBlock => io::ErrorKind::WouldBlock,
+            UtpError::ConnectTimedOut => io::ErrorKind::TimedOut,
+            UtpError::InvalidWaterMarks => io::ErrorKind::InvalidInput,
+            UtpError::ContextDestroyed | UtpError::SocketClosed => io::ErrorKind::NotConnected,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, e)
     }
 }
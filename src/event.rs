@@ -0,0 +1,34 @@
+//! Callback-free uTP event API.
+
+use bytes::Bytes;
+use socket::{UtpSocket, UtpSocketId};
+use std::io;
+use std::net::SocketAddr;
+
+/// Something that happened inside `UtpContext` while processing UDP packets, checking timeouts or
+/// writing data. When event queue is enabled with `UtpContext::enable_events()`, these events are
+/// recorded instead of calling the respective callbacks, and are drained with
+/// `UtpContext::poll_events()`.
+#[derive(Debug)]
+pub enum UtpEvent {
+    /// New incoming connection was accepted. The socket is closed, if it's dropped.
+    Accepted(UtpSocket, SocketAddr),
+    /// Outgoing connection was established. This implies the socket is writable.
+    Connected(UtpSocketId),
+    /// Data was received from remote peer. The data is already acknowledged.
+    Data(UtpSocketId, Bytes),
+    /// Data was received and buffered, read it with `UtpSocket::read()`. Reported instead of
+    /// `Data` once `UtpContext::enable_read_buffers()` is called.
+    Readable(UtpSocketId),
+    /// Socket is able to send more data.
+    Writable(UtpSocketId),
+    /// Remote peer closed the connection, no more data will be received.
+    Eof(UtpSocketId),
+    /// Connection failed. Deferred commands libutp failed to execute are reported as
+    /// `UtpError::SendFailed` or `UtpError::ConnectFailed`.
+    Error(UtpSocketId, io::Error),
+    /// Socket was destroyed. The id won't appear in any further events.
+    Destroyed(UtpSocketId),
+    /// uTP packet must be sent to the given address over UDP.
+    Transmit(Bytes, SocketAddr),
+}
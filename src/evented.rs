@@ -0,0 +1,281 @@
+//! uTP endpoints and streams that can be registered in `mio::Poll`.
+
+use driver::StreamState;
+use endpoint::UtpEndpoint;
+#[cfg(unix)]
+use mio::unix::UnixReady;
+use mio::{Evented, Poll, PollOpt, Ready, Registration, Token};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use stream::{self, UtpStream};
+
+/// `UtpEndpoint` that can be registered in `mio::Poll`. The endpoint becomes readable when there
+/// are incoming connections to accept.
+///
+/// The UDP socket is still driven by a background thread, readiness is reported via
+/// `mio::Registration`, hence the endpoint and its streams work with any `Poll` instance.
+pub struct EventedUtpEndpoint {
+    endpoint: UtpEndpoint,
+    registration: Registration,
+}
+
+impl EventedUtpEndpoint {
+    /// Creates a new endpoint bound to the given address. See `UtpEndpoint::bind()`.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<EventedUtpEndpoint> {
+        Ok(Self::wrap(UtpEndpoint::bind(addr)?))
+    }
+
+    /// Creates a new endpoint that queues at most `backlog` incoming connections until they are
+    /// accepted. See `UtpEndpoint::bind_with_backlog()`.
+    pub fn bind_with_backlog<A: ToSocketAddrs>(
+        addr: A,
+        backlog: usize,
+    ) -> io::Result<EventedUtpEndpoint> {
+        Ok(Self::wrap(UtpEndpoint::bind_with_backlog(addr, backlog)?))
+    }
+
+    /// Returns the address UDP socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    /// Starts opening uTP connection to a remote host without blocking. The stream becomes
+    /// writable once the connection is established, just like `mio::net::TcpStream`.
+    pub fn connect(&self, addr: &SocketAddr) -> io::Result<EventedUtpStream> {
+        let stream = stream::connect_nonblocking(Arc::clone(self.endpoint.driver()), *addr)?;
+        Ok(EventedUtpStream::wrap(stream))
+    }
+
+    /// Accepts a new incoming connection. Fails with `io::ErrorKind::WouldBlock`, if there are
+    /// no connections to accept.
+    pub fn accept(&self) -> io::Result<(EventedUtpStream, SocketAddr)> {
+        let (stream, addr) = self
+            .endpoint
+            .try_accept()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+        stream.set_nonblocking(true)?;
+        // accepted connections are established right away
+        stream.driver().streams().update(stream.id(), |state| {
+            state.connected = true;
+            state.writable = true;
+        });
+        Ok((EventedUtpStream::wrap(stream), addr))
+    }
+
+    fn wrap(endpoint: UtpEndpoint) -> Self {
+        let (registration, readiness) = Registration::new2();
+        endpoint
+            .driver()
+            .streams()
+            .set_incoming_readiness(readiness);
+        Self {
+            endpoint,
+            registration,
+        }
+    }
+}
+
+impl Evented for EventedUtpEndpoint {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        poll.deregister(&self.registration)
+    }
+}
+
+/// uTP server socket that can be registered in `mio::Poll`, comparable to
+/// `mio::net::TcpListener`. The listener is an `EventedUtpEndpoint` that is only used to accept
+/// connections.
+pub struct EventedUtpListener {
+    endpoint: EventedUtpEndpoint,
+}
+
+impl EventedUtpListener {
+    /// Creates a new listener bound to the given address.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<EventedUtpListener> {
+        Ok(EventedUtpListener {
+            endpoint: EventedUtpEndpoint::bind(addr)?,
+        })
+    }
+
+    /// Returns the local socket address of this listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    /// Accepts a new incoming connection. See `EventedUtpEndpoint::accept()`.
+    pub fn accept(&self) -> io::Result<(EventedUtpStream, SocketAddr)> {
+        self.endpoint.accept()
+    }
+}
+
+impl Evented for EventedUtpListener {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.endpoint.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.endpoint.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.endpoint.deregister(poll)
+    }
+}
+
+/// Nonblocking uTP stream that can be registered in `mio::Poll`, comparable to
+/// `mio::net::TcpStream`.
+///
+/// The stream is readable when there's data to read, the remote peer closed the connection or
+/// the connection failed. Failed connections are writable too. On Unix closed connections are
+/// also reported as `UnixReady::hup()` and failed ones as `UnixReady::error()`.
+pub struct EventedUtpStream {
+    stream: UtpStream,
+    registration: Registration,
+}
+
+impl EventedUtpStream {
+    /// Starts opening uTP connection to a remote host over a new UDP socket bound to any local
+    /// address.
+    pub fn connect(addr: &SocketAddr) -> io::Result<EventedUtpStream> {
+        EventedUtpEndpoint::bind_with_backlog(stream::any_addr_for(addr), 0)?.connect(addr)
+    }
+
+    /// Returns the socket address of the remote peer of this connection.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// Returns the socket address of the local half of this connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    /// Shuts down the read, write, or both halves of this connection.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.stream.shutdown(how)
+    }
+
+    fn wrap(stream: UtpStream) -> Self {
+        let (registration, readiness) = Registration::new2();
+        stream
+            .driver()
+            .streams()
+            .set_readiness(stream.id(), readiness);
+        Self {
+            stream,
+            registration,
+        }
+    }
+}
+
+impl Read for EventedUtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.stream).read(buf)
+    }
+}
+
+impl<'a> Read for &'a EventedUtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.stream).read(buf)
+    }
+}
+
+impl Write for EventedUtpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&self.stream).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Write for &'a EventedUtpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&self.stream).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for EventedUtpStream {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        poll.deregister(&self.registration)
+    }
+}
+
+/// Returns readiness of the stream in the given state.
+pub fn stream_readiness(stream: &StreamState) -> Ready {
+    let failed = stream.error.is_some();
+    let mut ready = Ready::empty();
+    if stream.readable || stream.read_closed || failed {
+        ready |= Ready::readable();
+    }
+    if stream.writable || stream.write_closed || failed {
+        ready |= Ready::writable();
+    }
+    #[cfg(unix)]
+    {
+        if stream.read_closed {
+            ready |= UnixReady::hup();
+        }
+        if failed {
+            ready |= UnixReady::error();
+        }
+    }
+    ready
+}
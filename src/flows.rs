@@ -0,0 +1,298 @@
+//! Groups captured uTP packets into connections and summarizes how each of them went.
+//!
+//! A connection is identified by the address pair and its connection ids: the initiator sends
+//! SYN with id `R` and then uses `R + 1`, while the acceptor replies with `R`. Flows whose SYN
+//! was not captured are recognized by the ids differing by one in each direction.
+
+use packet::{Packet, PacketType};
+use pcap::UdpRecord;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+/// libutp never has more packets than this in flight, hence reordered packets are never further
+/// behind the newest one.
+const MAX_PACKETS_IN_FLIGHT: u16 = 1024;
+
+/// How the connection ended, as far as the capture tells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// No FIN or RESET was captured.
+    Open,
+    /// The given peer sent FIN first.
+    Fin(SocketAddr),
+    /// The given peer sent RESET. Reported even if FIN was sent before.
+    Reset(SocketAddr),
+}
+
+/// Statistics of the packets sent in one direction of the flow.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DirectionStats {
+    /// All uTP packets, retransmissions included.
+    pub packets: u64,
+    /// Payload of data packets, retransmissions excluded.
+    pub bytes: u64,
+    /// SYN, DATA and FIN packets with sequence numbers that were already captured. Packets that
+    /// arrive out of order are not counted, but a retransmission of a packet that is missing
+    /// from the capture is taken for the original.
+    pub retransmissions: u64,
+    /// STATE packets that acknowledge the same packet as the previous one while the other side
+    /// has unacknowledged packets.
+    pub duplicate_acks: u64,
+    /// Packets that carry selective ACK extension.
+    pub selective_acks: u64,
+    /// Advertised receive window, recorded whenever it changes. Times are relative to the start
+    /// of the flow.
+    pub window: Vec<(Duration, u32)>,
+}
+
+/// Summary of a single uTP connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Flow {
+    /// Initiator of the connection, or the sender of the first captured packet, if SYN was not
+    /// captured.
+    pub src: SocketAddr,
+    /// The other peer.
+    pub dst: SocketAddr,
+    /// Connection id from SYN, or the id of the first captured packet, if SYN was not captured.
+    pub connection_id: u16,
+    /// Whether the capture has the connection's SYN.
+    pub syn_seen: bool,
+    /// Time of the first packet.
+    pub start: SystemTime,
+    /// Time of the last packet.
+    pub end: SystemTime,
+    /// Time from the last SYN to the first reply.
+    pub handshake_rtt: Option<Duration>,
+    /// Packets sent by `src`.
+    pub forward: DirectionStats,
+    /// Packets sent by `dst`.
+    pub backward: DirectionStats,
+    /// How the connection ended.
+    pub close: CloseReason,
+}
+
+impl Flow {
+    /// Returns how long the flow lasted in the capture.
+    pub fn duration(&self) -> Duration {
+        self.end
+            .duration_since(self.start)
+            .unwrap_or_else(|_| Duration::from_secs(0))
+    }
+}
+
+/// Per direction state that is needed to classify the packets, but not reported.
+#[derive(Default)]
+struct DirectionState {
+    /// Sequence number of the newest reliable packet.
+    last_seq_nr: Option<u16>,
+    /// Sequence numbers skipped by the newest packet that were not captured yet.
+    missing_seq_nrs: HashSet<u16>,
+    last_ack_nr: Option<u16>,
+    last_wnd_size: Option<u32>,
+}
+
+impl DirectionState {
+    /// Records the sequence number of a reliable packet. Returns `false`, if the packet was
+    /// captured before.
+    fn on_seq_nr(&mut self, seq_nr: u16) -> bool {
+        let last = match self.last_seq_nr {
+            Some(last) => last,
+            None => {
+                self.last_seq_nr = Some(seq_nr);
+                return true;
+            }
+        };
+        if !is_newer(seq_nr, last) {
+            return self.missing_seq_nrs.remove(&seq_nr);
+        }
+        let skipped = seq_nr.wrapping_sub(last) - 1;
+        if skipped < MAX_PACKETS_IN_FLIGHT {
+            self.missing_seq_nrs
+                .extend((1..=skipped).map(|i| last.wrapping_add(i)));
+        }
+        self.missing_seq_nrs
+            .retain(|missing| seq_nr.wrapping_sub(*missing) < MAX_PACKETS_IN_FLIGHT);
+        self.last_seq_nr = Some(seq_nr);
+        true
+    }
+}
+
+struct FlowState {
+    flow: Flow,
+    forward: DirectionState,
+    backward: DirectionState,
+    /// Time of the last SYN that is not replied yet.
+    syn_time: Option<SystemTime>,
+}
+
+/// Collects captured packets into flows, see `Flow`.
+#[derive(Default)]
+pub struct FlowAnalyser {
+    flows: Vec<FlowState>,
+    /// Maps sender, receiver and connection id of the packets to flows.
+    index: HashMap<(SocketAddr, SocketAddr, u16), usize>,
+    ignored: u64,
+}
+
+impl FlowAnalyser {
+    /// Creates an analyser that has seen no packets.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accounts the captured datagram. Returns `false`, if it's not a uTP packet.
+    pub fn add(&mut self, record: &UdpRecord) -> bool {
+        let packet = match Packet::parse(&record.payload) {
+            Ok(packet) => packet,
+            Err(_) => {
+                self.ignored += 1;
+                return false;
+            }
+        };
+        let (i, forward) = self.flow_index(record, &packet);
+        self.flows[i].on_packet(record.timestamp, forward, &packet);
+        true
+    }
+
+    /// Returns the number of datagrams that were not uTP packets.
+    pub fn ignored(&self) -> u64 {
+        self.ignored
+    }
+
+    /// Returns the flows in the order their first packets were captured.
+    pub fn into_flows(self) -> Vec<Flow> {
+        self.flows.into_iter().map(|state| state.flow).collect()
+    }
+
+    /// Finds the flow the packet belongs to, creating a new one if needed. Also tells if the
+    /// packet was sent from `src` to `dst` of the flow.
+    fn flow_index(&mut self, record: &UdpRecord, packet: &Packet) -> (usize, bool) {
+        let (src, dst) = (record.src, record.dst);
+        let id = packet.connection_id();
+        if let Some(&i) = self.index.get(&(src, dst, id)) {
+            return (i, self.flows[i].flow.src == src);
+        }
+
+        if packet.packet_type() == PacketType::Syn {
+            let i = self.new_flow(record, packet);
+            let _ = self.index.insert((src, dst, id), i);
+            let _ = self.index.insert((src, dst, id.wrapping_add(1)), i);
+            let _ = self.index.insert((dst, src, id), i);
+            return (i, true);
+        }
+
+        // the reverse direction of a flow whose SYN was not captured
+        let reverse = [id.wrapping_add(1), id.wrapping_sub(1)]
+            .iter()
+            .filter_map(|reverse_id| self.index.get(&(dst, src, *reverse_id)))
+            .cloned()
+            .find(|i| !self.flows[*i].flow.syn_seen && self.flows[*i].flow.src == dst);
+        if let Some(i) = reverse {
+            let _ = self.index.insert((src, dst, id), i);
+            return (i, false);
+        }
+
+        let i = self.new_flow(record, packet);
+        let _ = self.index.insert((src, dst, id), i);
+        (i, true)
+    }
+
+    fn new_flow(&mut self, record: &UdpRecord, packet: &Packet) -> usize {
+        self.flows.push(FlowState {
+            flow: Flow {
+                src: record.src,
+                dst: record.dst,
+                connection_id: packet.connection_id(),
+                syn_seen: packet.packet_type() == PacketType::Syn,
+                start: record.timestamp,
+                end: record.timestamp,
+                handshake_rtt: None,
+                forward: DirectionStats::default(),
+                backward: DirectionStats::default(),
+                close: CloseReason::Open,
+            },
+            forward: DirectionState::default(),
+            backward: DirectionState::default(),
+            syn_time: None,
+        });
+        self.flows.len() - 1
+    }
+}
+
+impl FlowState {
+    fn on_packet(&mut self, time: SystemTime, forward: bool, packet: &Packet) {
+        let flow = &mut self.flow;
+        flow.end = time;
+        let since_start = time
+            .duration_since(flow.start)
+            .unwrap_or_else(|_| Duration::from_secs(0));
+        let sender = if forward { flow.src } else { flow.dst };
+
+        match packet.packet_type() {
+            PacketType::Syn => self.syn_time = Some(time),
+            PacketType::Fin => {
+                if flow.close == CloseReason::Open {
+                    flow.close = CloseReason::Fin(sender);
+                }
+            }
+            PacketType::Reset => match flow.close {
+                CloseReason::Reset(_) => (),
+                _ => flow.close = CloseReason::Reset(sender),
+            },
+            PacketType::Data | PacketType::State => (),
+        }
+        if !forward && flow.handshake_rtt.is_none() {
+            if let Some(syn_time) = self.syn_time.take() {
+                flow.handshake_rtt = time.duration_since(syn_time).ok();
+            }
+        }
+
+        let (stats, state, other_state) = if forward {
+            (&mut flow.forward, &mut self.forward, &self.backward)
+        } else {
+            (&mut flow.backward, &mut self.backward, &self.forward)
+        };
+        stats.packets += 1;
+
+        if packet.packet_type().is_reliable() {
+            if state.on_seq_nr(packet.seq_nr()) {
+                if packet.packet_type() == PacketType::Data {
+                    stats.bytes += packet.payload().len() as u64;
+                }
+            } else {
+                stats.retransmissions += 1;
+            }
+        }
+
+        let ack_nr = packet.ack_nr();
+        if packet.packet_type() == PacketType::State && state.last_ack_nr == Some(ack_nr) {
+            let outstanding = other_state
+                .last_seq_nr
+                .map_or(false, |seq_nr| is_newer(seq_nr, ack_nr));
+            if outstanding {
+                stats.duplicate_acks += 1;
+            }
+        }
+        // SYN has no valid ack number
+        if packet.packet_type() != PacketType::Syn {
+            state.last_ack_nr = Some(ack_nr);
+        }
+
+        if packet.selective_ack().is_some() {
+            stats.selective_acks += 1;
+        }
+
+        let wnd_size = packet.wnd_size();
+        if state.last_wnd_size != Some(wnd_size) {
+            state.last_wnd_size = Some(wnd_size);
+            stats.window.push((since_start, wnd_size));
+        }
+    }
+}
+
+/// Compares sequence numbers that wrap around.
+fn is_newer(seq_nr: u16, than: u16) -> bool {
+    let diff = seq_nr.wrapping_sub(than);
+    diff != 0 && diff < 0x8000
+}
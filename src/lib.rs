@@ -1,7 +1,7 @@
 //! This crate provides safe libutp Rust bindings.
 
 #![forbid(
-    exceeding_bitshifts,
+    arithmetic_overflow,
     mutable_transmutes,
     no_mangle_const_items,
     unknown_crate_types,
@@ -13,9 +13,6 @@
     missing_docs,
     non_shorthand_field_patterns,
     overflowing_literals,
-    plugin_as_library,
-    private_no_mangle_fns,
-    private_no_mangle_statics,
     stable_features,
     unconditional_recursion,
     unknown_lints,
@@ -37,32 +34,87 @@
     unused_results
 )]
 #![allow(
-    box_pointers,
     missing_copy_implementations,
     missing_debug_implementations,
     variant_size_differences
 )]
 
+extern crate bytes;
+#[cfg(feature = "futures-io")]
+extern crate futures_io;
 extern crate libc;
+#[cfg(feature = "mio")]
+extern crate mio;
 extern crate nix;
 #[macro_use]
 extern crate quick_error;
 extern crate libutp_sys;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(test)]
+#[macro_use]
+extern crate net_literals;
+#[cfg(test)]
+#[macro_use]
+extern crate unwrap;
 
+mod addr;
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+mod async_io;
 mod callback;
+mod capture;
+mod clock;
+mod command;
 mod ctx;
+mod demux;
+mod driver;
+mod endpoint;
 mod error;
+mod event;
+#[cfg(feature = "mio")]
+mod evented;
+pub mod flows;
+mod listener;
+mod network;
+pub mod packet;
+pub mod pcap;
+mod read_buffer;
+mod replay;
+mod rng;
+mod shared;
 mod socket;
+mod stream;
+mod timers;
 
+pub use addr::{addr_for_socket, normalize_addr};
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+pub use async_io::{
+    Accept, AsyncTimer, AsyncUdpSocket, AsyncUtpListener, AsyncUtpStream, Connect, UtpDriverTask,
+};
 pub use callback::{UtpCallback, UtpCallbackArgs, UtpCallbackType};
+pub use clock::ManualClock;
 pub use ctx::UtpContext;
-pub use error::UtpError;
-pub use socket::UtpSocket;
+pub use demux::{
+    is_utp_packet, PacketFilter, PacketHandler, PacketPredicate, PacketRoute, UdpDemux,
+};
+pub use endpoint::UtpEndpoint;
+pub use error::{PacketError, UtpError};
+pub use event::UtpEvent;
+#[cfg(feature = "mio")]
+pub use evented::{EventedUtpEndpoint, EventedUtpListener, EventedUtpStream};
+pub use listener::{Incoming, UtpListener};
+pub use network::{LinkConfig, NetworkStats, NodeId, VirtualNetwork};
+pub use replay::{Replay, ReplayEvent};
+pub use shared::{SharedUtpCallback, SharedUtpContext, SharedUtpSocket};
+pub use socket::{UtpSocket, UtpSocketId};
+pub use stream::UtpStream;
 
 use libutp_sys::*;
+use std::convert::TryFrom;
+use std::io;
 
 /// uTP connection state
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum UtpState {
     /// socket has reveived syn-ack (notification only for outgoing connection completion)
@@ -80,6 +132,55 @@ pub enum UtpState {
     Destroying = UTP_STATE_DESTROYING,
 }
 
+impl TryFrom<i32> for UtpState {
+    type Error = UtpError;
+
+    fn try_from(state: i32) -> Result<Self, UtpError> {
+        match state as u32 {
+            UTP_STATE_CONNECT => Ok(UtpState::Connected),
+            UTP_STATE_WRITABLE => Ok(UtpState::Writable),
+            UTP_STATE_EOF => Ok(UtpState::ConnectionClosed),
+            UTP_STATE_DESTROYING => Ok(UtpState::Destroying),
+            _ => Err(UtpError::UnknownState(state)),
+        }
+    }
+}
+
+/// Error codes libutp passes to `UtpCallbackType::OnError` callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum UtpErrorCode {
+    /// Remote peer refused the connection.
+    ConnectionRefused = UTP_ECONNREFUSED,
+    /// Connection was reset by remote peer.
+    ConnectionReset = UTP_ECONNRESET,
+    /// Remote peer did not respond in time.
+    TimedOut = UTP_ETIMEDOUT,
+}
+
+impl TryFrom<i32> for UtpErrorCode {
+    type Error = UtpError;
+
+    fn try_from(code: i32) -> Result<Self, UtpError> {
+        match code as u32 {
+            UTP_ECONNREFUSED => Ok(UtpErrorCode::ConnectionRefused),
+            UTP_ECONNRESET => Ok(UtpErrorCode::ConnectionReset),
+            UTP_ETIMEDOUT => Ok(UtpErrorCode::TimedOut),
+            _ => Err(UtpError::UnknownErrorCode(code)),
+        }
+    }
+}
+
+impl From<UtpErrorCode> for io::Error {
+    fn from(code: UtpErrorCode) -> Self {
+        match code {
+            UtpErrorCode::ConnectionRefused => io::ErrorKind::ConnectionRefused.into(),
+            UtpErrorCode::ConnectionReset => io::ErrorKind::ConnectionReset.into(),
+            UtpErrorCode::TimedOut => io::ErrorKind::TimedOut.into(),
+        }
+    }
+}
+
 // TODO(povilas): wrap utp context options:
 //
 // UTP_LOG_NORMAL,
@@ -0,0 +1,67 @@
+//! uTP server socket.
+
+use endpoint::UtpEndpoint;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use stream::UtpStream;
+
+/// uTP socket server listening for connections, comparable to `std::net::TcpListener`.
+///
+/// The listener is a `UtpEndpoint` that is only used to accept connections. Accepted streams
+/// share UDP socket and the driver thread with the listener.
+pub struct UtpListener {
+    endpoint: UtpEndpoint,
+}
+
+impl UtpListener {
+    /// Creates a new listener bound to the given address. If `addr` yields multiple addresses,
+    /// binding is attempted with each of them until it succeeds.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UtpListener> {
+        Ok(UtpListener {
+            endpoint: UtpEndpoint::bind(addr)?,
+        })
+    }
+
+    /// Creates a new listener that queues at most `backlog` incoming connections until they are
+    /// accepted. See `UtpEndpoint::bind_with_backlog()`.
+    pub fn bind_with_backlog<A: ToSocketAddrs>(addr: A, backlog: usize) -> io::Result<UtpListener> {
+        Ok(UtpListener {
+            endpoint: UtpEndpoint::bind_with_backlog(addr, backlog)?,
+        })
+    }
+
+    /// Returns the local socket address of this listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    /// Accepts a new incoming connection. This call blocks until a new connection is established.
+    pub fn accept(&self) -> io::Result<(UtpStream, SocketAddr)> {
+        self.endpoint.accept()
+    }
+
+    /// Returns an iterator over the connections being received on this listener. The iterator
+    /// never returns `None`.
+    pub fn incoming(&self) -> Incoming {
+        self.endpoint.incoming()
+    }
+}
+
+/// An iterator that infinitely accepts connections on a `UtpListener` or `UtpEndpoint`.
+pub struct Incoming<'a> {
+    endpoint: &'a UtpEndpoint,
+}
+
+impl<'a> Incoming<'a> {
+    pub(crate) fn new(endpoint: &'a UtpEndpoint) -> Self {
+        Self { endpoint }
+    }
+}
+
+impl<'a> Iterator for Incoming<'a> {
+    type Item = io::Result<UtpStream>;
+
+    fn next(&mut self) -> Option<io::Result<UtpStream>> {
+        Some(self.endpoint.accept().map(|(stream, _)| stream))
+    }
+}
@@ -0,0 +1,387 @@
+//! In-process network of uTP contexts with emulated link conditions, for deterministic tests.
+
+use addr::normalize_addr;
+use bytes::Bytes;
+use clock::ManualClock;
+use ctx::UtpContext;
+use event::UtpEvent;
+use rng::Rng;
+use std::cmp::{self, Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::mem;
+use std::net::SocketAddr;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Conditions of the packets sent from one node to another. The default is a perfect link:
+/// no delay, no loss and unlimited bandwidth.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinkConfig {
+    /// How long packets take to arrive.
+    pub latency: Duration,
+    /// Each packet's latency varies uniformly within `latency ± jitter`, which also reorders
+    /// packets sent close to each other.
+    pub jitter: Duration,
+    /// Probability that a packet is dropped, from 0 to 1.
+    pub loss: f64,
+    /// Probability that a packet is delivered twice. The copy has its own latency.
+    pub duplicate: f64,
+    /// Probability that a packet skips the latency and overtakes the packets sent before it.
+    pub reorder: f64,
+    /// Link capacity in bytes per second. Packets queue up behind each other when it's exceeded.
+    pub bandwidth: Option<u64>,
+}
+
+/// Counts of packets that went through the network.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkStats {
+    /// Packets the nodes sent.
+    pub sent: u64,
+    /// Packets passed to the receivers, duplicates included.
+    pub delivered: u64,
+    /// Packets dropped by links or sent to addresses with no node.
+    pub lost: u64,
+    /// Extra copies made by links.
+    pub duplicated: u64,
+}
+
+/// Identifies a node of `VirtualNetwork`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+/// Connects uTP contexts to each other without real sockets: packets the contexts send are
+/// delivered with `process_udp()` over links that delay, drop, reorder and duplicate them as
+/// configured. All the contexts and the network itself are driven by a single `ManualClock` and
+/// every random decision, libutp's included, comes from the seed, hence a run can be repeated
+/// exactly.
+///
+/// The contexts are in event queue mode, `UtpEvent::Transmit` events are consumed by the
+/// network, while the rest are kept for the test to take with `take_events()`. Sockets are used
+/// as usual, e.g. `network.utp(node).connect(addr)` or `UtpSocket::send()`, and then the network
+/// is run with `step()`, `run_for()` or `run_until()`.
+pub struct VirtualNetwork {
+    clock: ManualClock,
+    rng: Rng,
+    nodes: Vec<Node>,
+    /// Node index by address.
+    addrs: HashMap<SocketAddr, usize>,
+    default_link: LinkConfig,
+    /// Links configured with `set_link()`.
+    links: HashMap<(usize, usize), LinkConfig>,
+    /// When the link is done sending the queued packets, if its bandwidth is limited.
+    busy_until: HashMap<(usize, usize), Duration>,
+    in_flight: BinaryHeap<Reverse<InFlight>>,
+    /// Orders packets that are delivered at the same time.
+    next_packet: u64,
+    stats: NetworkStats,
+}
+
+struct Node {
+    addr: SocketAddr,
+    utp: UtpContext<()>,
+    events: Vec<UtpEvent>,
+}
+
+/// Packet on its way to the receiver.
+struct InFlight {
+    /// Time since the start of the network.
+    deliver_at: Duration,
+    order: u64,
+    src: SocketAddr,
+    dst: SocketAddr,
+    packet: Bytes,
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deliver_at, self.order).cmp(&(other.deliver_at, other.order))
+    }
+}
+
+impl VirtualNetwork {
+    /// Creates a network with no nodes. Runs with the same seed, nodes, links and calls are
+    /// identical.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            // a fixed start time keeps libutp timestamps the same across runs
+            clock: ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000_000)),
+            rng: Rng::new(seed),
+            nodes: Vec::new(),
+            addrs: HashMap::new(),
+            default_link: LinkConfig::default(),
+            links: HashMap::new(),
+            busy_until: HashMap::new(),
+            in_flight: BinaryHeap::new(),
+            next_packet: 0,
+            stats: NetworkStats::default(),
+        }
+    }
+
+    /// Adds a new context reachable at the given address. Addresses must be unique.
+    pub fn add_node(&mut self, addr: SocketAddr) -> NodeId {
+        let addr = normalize_addr(addr);
+        let mut utp = UtpContext::new(());
+        utp.set_clock(self.clock.clone());
+        utp.set_random_seed(self.rng.next_u64());
+        utp.enable_events();
+        let id = self.nodes.len();
+        self.nodes.push(Node {
+            addr,
+            utp,
+            events: Vec::new(),
+        });
+        let _ = self.addrs.insert(addr, id);
+        NodeId(id)
+    }
+
+    /// Returns the address of the node.
+    pub fn addr(&self, node: NodeId) -> SocketAddr {
+        self.nodes[node.0].addr
+    }
+
+    /// Returns the context of the node.
+    pub fn utp(&mut self, node: NodeId) -> &mut UtpContext<()> {
+        &mut self.nodes[node.0].utp
+    }
+
+    /// Takes the events the node's context reported so far, except for `UtpEvent::Transmit`.
+    pub fn take_events(&mut self, node: NodeId) -> Vec<UtpEvent> {
+        self.collect_events();
+        mem::take(&mut self.nodes[node.0].events)
+    }
+
+    /// Sets conditions of the links that are not configured with `set_link()`. Only affects
+    /// packets sent from now on.
+    pub fn set_default_link(&mut self, config: LinkConfig) {
+        self.default_link = config;
+    }
+
+    /// Sets conditions of the packets sent from `from` to `to`. The opposite direction is
+    /// configured separately.
+    pub fn set_link(&mut self, from: NodeId, to: NodeId, config: LinkConfig) {
+        let _ = self.links.insert((from.0, to.0), config);
+    }
+
+    /// Returns the clock that drives the network.
+    pub fn clock(&self) -> &ManualClock {
+        &self.clock
+    }
+
+    /// Returns how many packets were sent, delivered, lost and duplicated so far.
+    pub fn stats(&self) -> &NetworkStats {
+        &self.stats
+    }
+
+    /// Moves the clock to the next packet delivery or timeout, whichever comes first, and
+    /// processes everything that is due then. Returns `false`, if there's nothing to wait for.
+    pub fn step(&mut self) -> bool {
+        let next = match self.next_deadline() {
+            Some(next) => next,
+            None => return false,
+        };
+        self.clock
+            .advance(next.checked_sub(self.clock.elapsed()).unwrap_or_default());
+        self.process_due();
+        true
+    }
+
+    /// Runs the network for the given time.
+    pub fn run_for(&mut self, duration: Duration) {
+        let until = self.clock.elapsed() + duration;
+        loop {
+            match self.next_deadline() {
+                Some(next) if next <= until => {
+                    let _ = self.step();
+                }
+                _ => break,
+            }
+        }
+        self.clock
+            .advance(until.checked_sub(self.clock.elapsed()).unwrap_or_default());
+    }
+
+    /// Runs the network until `done` returns `true`, which is checked after every step. Gives
+    /// up and returns `false` once `timeout` passes or there's nothing to wait for.
+    pub fn run_until<F>(&mut self, timeout: Duration, mut done: F) -> bool
+    where
+        F: FnMut(&mut Self) -> bool,
+    {
+        let until = self.clock.elapsed() + timeout;
+        loop {
+            if done(self) {
+                return true;
+            }
+            match self.next_deadline() {
+                Some(next) if next <= until => {
+                    let _ = self.step();
+                }
+                _ => return false,
+            }
+        }
+    }
+
+    /// Returns the time of the next delivery or timeout relative to the start of the network.
+    fn next_deadline(&mut self) -> Option<Duration> {
+        // packets sent since the last step are not in flight yet
+        self.collect_events();
+        let now = self.clock.elapsed();
+        let delivery = self
+            .in_flight
+            .peek()
+            .map(|Reverse(packet)| packet.deliver_at);
+        let timeout = self
+            .nodes
+            .iter()
+            .filter_map(|node| node.utp.next_timeout())
+            .min()
+            .map(|timeout| now + timeout);
+        match (delivery, timeout) {
+            (Some(delivery), Some(timeout)) => Some(cmp::min(delivery, timeout)),
+            (delivery, timeout) => delivery.or(timeout),
+        }
+    }
+
+    /// Delivers packets and checks timeouts that are due.
+    fn process_due(&mut self) {
+        let now = self.clock.elapsed();
+        let mut receivers = Vec::new();
+        while let Some(packet) = self.pop_due(now) {
+            match self.addrs.get(&packet.dst) {
+                Some(&i) => {
+                    // illegal packets are ignored as they would be by a real socket
+                    let _ = self.nodes[i].utp.process_udp(&packet.packet, packet.src);
+                    self.stats.delivered += 1;
+                    if !receivers.contains(&i) {
+                        receivers.push(i);
+                    }
+                }
+                None => self.stats.lost += 1,
+            }
+        }
+        // just like after draining a real socket
+        for i in receivers {
+            self.nodes[i].utp.ack_packets();
+        }
+
+        for node in &mut self.nodes {
+            if node.utp.next_timeout() == Some(Duration::from_secs(0)) {
+                node.utp.check_timeouts();
+            }
+        }
+        self.collect_events();
+    }
+
+    fn pop_due(&mut self, now: Duration) -> Option<InFlight> {
+        let due = self
+            .in_flight
+            .peek()
+            .map_or(false, |Reverse(packet)| packet.deliver_at <= now);
+        if due {
+            self.in_flight.pop().map(|Reverse(packet)| packet)
+        } else {
+            None
+        }
+    }
+
+    /// Sends the packets the contexts transmitted and keeps the other events.
+    fn collect_events(&mut self) {
+        let mut transmitted = Vec::new();
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            for event in node.utp.poll_events() {
+                match event {
+                    UtpEvent::Transmit(packet, addr) => transmitted.push((i, packet, addr)),
+                    event => node.events.push(event),
+                }
+            }
+        }
+        for (i, packet, addr) in transmitted {
+            self.send(i, packet, normalize_addr(addr));
+        }
+    }
+
+    fn send(&mut self, src: usize, packet: Bytes, dst_addr: SocketAddr) {
+        self.stats.sent += 1;
+        let dst = match self.addrs.get(&dst_addr) {
+            Some(&dst) => dst,
+            None => {
+                self.stats.lost += 1;
+                return;
+            }
+        };
+        let now = self.clock.elapsed();
+        let src_addr = self.nodes[src].addr;
+        let config = self
+            .links
+            .get(&(src, dst))
+            .unwrap_or(&self.default_link)
+            .clone();
+        if self.rng.chance(config.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+
+        let sent_at = match config.bandwidth {
+            Some(bandwidth) => {
+                let busy_until = self.busy_until.entry((src, dst)).or_default();
+                *busy_until =
+                    cmp::max(*busy_until, now) + transmission_time(packet.len(), bandwidth);
+                *busy_until
+            }
+            None => now,
+        };
+        let copies = if self.rng.chance(config.duplicate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let delay = self.delay(&config);
+            self.in_flight.push(Reverse(InFlight {
+                deliver_at: sent_at + delay,
+                order: self.next_packet,
+                src: src_addr,
+                dst: dst_addr,
+                packet: packet.clone(),
+            }));
+            self.next_packet += 1;
+        }
+    }
+
+    /// Picks the latency of a single packet.
+    fn delay(&mut self, config: &LinkConfig) -> Duration {
+        if self.rng.chance(config.reorder) {
+            return Duration::from_secs(0);
+        }
+        if config.jitter == Duration::from_secs(0) {
+            return config.latency;
+        }
+        let jitter = duration_to_nanos(config.jitter);
+        let offset = (self.rng.next_f64() * 2.0 * jitter as f64) as u64;
+        let latency = (duration_to_nanos(config.latency) + offset).saturating_sub(jitter);
+        Duration::from_nanos(latency)
+    }
+}
+
+/// Returns how long it takes to put a packet of the given size on the link.
+fn transmission_time(len: usize, bandwidth: u64) -> Duration {
+    let bandwidth = cmp::max(bandwidth, 1);
+    Duration::from_nanos(len as u64 * 1_000_000_000 / bandwidth)
+}
+
+fn duration_to_nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + u64::from(duration.subsec_nanos())
+}
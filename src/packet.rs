@@ -0,0 +1,366 @@
+//! BEP 29 packet codec: zero-copy views of uTP packets and their serialization.
+//!
+//! Every uTP packet starts with a 20 byte header, all integers are big endian:
+//!
+//! ```text
+//! 0       4       8               16              24              32
+//! +-------+-------+---------------+---------------+---------------+
+//! | type  | ver   | extension     | connection_id                 |
+//! +-------+-------+---------------+---------------+---------------+
+//! | timestamp_microseconds                                        |
+//! +---------------+---------------+---------------+---------------+
+//! | timestamp_difference_microseconds                             |
+//! +---------------+---------------+---------------+---------------+
+//! | wnd_size                                                      |
+//! +---------------+---------------+---------------+---------------+
+//! | seq_nr                        | ack_nr                        |
+//! +---------------+---------------+---------------+---------------+
+//! ```
+//!
+//! The header is followed by a chain of extensions, each made of the type of the next extension,
+//! data length and the data, and the payload. Zero extension type ends the chain.
+
+use error::PacketError;
+use std::convert::TryFrom;
+
+/// Size of the fixed part of uTP header, no packet is shorter.
+pub const HEADER_SIZE: usize = 20;
+/// The only uTP version there is.
+pub const VERSION: u8 = 1;
+/// Extension type of selective ACK.
+pub const SELECTIVE_ACK: u8 = 1;
+
+/// uTP packet type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PacketType {
+    /// Regular data packet, `ST_DATA`.
+    Data = 0,
+    /// The last packet of the connection, `ST_FIN`.
+    Fin = 1,
+    /// Acknowledgement without data, `ST_STATE`.
+    State = 2,
+    /// Forcibly terminates the connection, `ST_RESET`.
+    Reset = 3,
+    /// Initiates the connection, `ST_SYN`.
+    Syn = 4,
+}
+
+impl PacketType {
+    /// Returns `true`, if packets of this type must be acknowledged by remote peer.
+    pub fn is_reliable(self) -> bool {
+        match self {
+            PacketType::Data | PacketType::Fin | PacketType::Syn => true,
+            PacketType::State | PacketType::Reset => false,
+        }
+    }
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = PacketError;
+
+    fn try_from(packet_type: u8) -> Result<Self, PacketError> {
+        match packet_type {
+            0 => Ok(PacketType::Data),
+            1 => Ok(PacketType::Fin),
+            2 => Ok(PacketType::State),
+            3 => Ok(PacketType::Reset),
+            4 => Ok(PacketType::Syn),
+            _ => Err(PacketError::UnknownPacketType(packet_type)),
+        }
+    }
+}
+
+/// Fixed part of uTP header, except for the version and the extension type which are derived
+/// when the packet is serialized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketHeader {
+    /// Packet type.
+    pub packet_type: PacketType,
+    /// Identifies the connection together with the peer address.
+    pub connection_id: u16,
+    /// Microseconds part of the time the packet was sent at.
+    pub timestamp_micros: u32,
+    /// Difference between the local time and the timestamp of the last received packet.
+    pub timestamp_difference_micros: u32,
+    /// How many more bytes the sender can receive.
+    pub wnd_size: u32,
+    /// Sequence number of this packet.
+    pub seq_nr: u16,
+    /// Sequence number of the last packet the sender has received.
+    pub ack_nr: u16,
+}
+
+impl PacketHeader {
+    /// Serializes the packet made of this header, the given extensions and the payload.
+    pub fn encode(&self, extensions: &[Extension], payload: &[u8]) -> Result<Vec<u8>, PacketError> {
+        let mut buf = Vec::new();
+        self.encode_into(extensions, payload, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Appends the packet made of this header, the given extensions and the payload to `buf`.
+    /// Nothing is appended, if any of the extensions is invalid.
+    pub fn encode_into(
+        &self,
+        extensions: &[Extension],
+        payload: &[u8],
+        buf: &mut Vec<u8>,
+    ) -> Result<(), PacketError> {
+        for extension in extensions {
+            extension.validate()?;
+        }
+
+        buf.reserve(HEADER_SIZE + payload.len());
+        buf.push((self.packet_type as u8) << 4 | VERSION);
+        buf.push(extensions.first().map_or(0, |extension| extension.kind));
+        put_u16(buf, self.connection_id);
+        put_u32(buf, self.timestamp_micros);
+        put_u32(buf, self.timestamp_difference_micros);
+        put_u32(buf, self.wnd_size);
+        put_u16(buf, self.seq_nr);
+        put_u16(buf, self.ack_nr);
+        for (i, extension) in extensions.iter().enumerate() {
+            buf.push(extensions.get(i + 1).map_or(0, |next| next.kind));
+            buf.push(extension.data.len() as u8);
+            buf.extend_from_slice(extension.data);
+        }
+        buf.extend_from_slice(payload);
+        Ok(())
+    }
+}
+
+/// A single extension from packet's extension chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extension<'a> {
+    /// Extension type, never zero.
+    pub kind: u8,
+    /// Extension data, at most 255 bytes.
+    pub data: &'a [u8],
+}
+
+impl<'a> Extension<'a> {
+    /// Makes selective ACK extension with the given bitmask, see `SelectiveAck`.
+    pub fn selective_ack(bitmask: &'a [u8]) -> Self {
+        Extension {
+            kind: SELECTIVE_ACK,
+            data: bitmask,
+        }
+    }
+
+    fn validate(&self) -> Result<(), PacketError> {
+        if self.kind == 0 || self.data.len() > usize::from(u8::max_value()) {
+            return Err(PacketError::InvalidExtension(self.kind, self.data.len()));
+        }
+        check_selective_ack(self.kind, self.data.len())
+    }
+}
+
+/// Zero-copy view of a validated uTP packet.
+#[derive(Debug, Clone, Copy)]
+pub struct Packet<'a> {
+    buf: &'a [u8],
+    packet_type: PacketType,
+    payload_offset: usize,
+}
+
+impl<'a> Packet<'a> {
+    /// Validates the packet: it must hold the whole header of a known type and version and a
+    /// well-formed extension chain. Unknown extensions are accepted, selective ACK bitmask must
+    /// be a non-empty multiple of 4 bytes long.
+    pub fn parse(buf: &'a [u8]) -> Result<Packet<'a>, PacketError> {
+        if buf.len() < HEADER_SIZE {
+            return Err(PacketError::TooShort(buf.len()));
+        }
+        if buf[0] & 0x0f != VERSION {
+            return Err(PacketError::UnsupportedVersion(buf[0] & 0x0f));
+        }
+        let packet_type = PacketType::try_from(buf[0] >> 4)?;
+
+        let mut kind = buf[1];
+        let mut offset = HEADER_SIZE;
+        while kind != 0 {
+            if buf.len() < offset + 2 {
+                return Err(PacketError::TruncatedExtension(kind));
+            }
+            let len = usize::from(buf[offset + 1]);
+            if buf.len() < offset + 2 + len {
+                return Err(PacketError::TruncatedExtension(kind));
+            }
+            check_selective_ack(kind, len)?;
+            kind = buf[offset];
+            offset += 2 + len;
+        }
+
+        Ok(Packet {
+            buf,
+            packet_type,
+            payload_offset: offset,
+        })
+    }
+
+    /// Returns the packet type.
+    pub fn packet_type(&self) -> PacketType {
+        self.packet_type
+    }
+
+    /// Returns the protocol version, always `VERSION`.
+    pub fn version(&self) -> u8 {
+        self.buf[0] & 0x0f
+    }
+
+    /// Returns the connection id.
+    pub fn connection_id(&self) -> u16 {
+        get_u16(self.buf, 2)
+    }
+
+    /// Returns the microseconds part of the time the packet was sent at.
+    pub fn timestamp_micros(&self) -> u32 {
+        get_u32(self.buf, 4)
+    }
+
+    /// Returns the sender's delay estimate in microseconds.
+    pub fn timestamp_difference_micros(&self) -> u32 {
+        get_u32(self.buf, 8)
+    }
+
+    /// Returns the sender's receive window.
+    pub fn wnd_size(&self) -> u32 {
+        get_u32(self.buf, 12)
+    }
+
+    /// Returns the sequence number of this packet.
+    pub fn seq_nr(&self) -> u16 {
+        get_u16(self.buf, 16)
+    }
+
+    /// Returns the sequence number of the last packet the sender has received.
+    pub fn ack_nr(&self) -> u16 {
+        get_u16(self.buf, 18)
+    }
+
+    /// Returns the fixed part of the header.
+    pub fn header(&self) -> PacketHeader {
+        PacketHeader {
+            packet_type: self.packet_type,
+            connection_id: self.connection_id(),
+            timestamp_micros: self.timestamp_micros(),
+            timestamp_difference_micros: self.timestamp_difference_micros(),
+            wnd_size: self.wnd_size(),
+            seq_nr: self.seq_nr(),
+            ack_nr: self.ack_nr(),
+        }
+    }
+
+    /// Returns the extensions in the order they are chained.
+    pub fn extensions(&self) -> Extensions<'a> {
+        Extensions {
+            kind: self.buf[1],
+            rest: &self.buf[HEADER_SIZE..self.payload_offset],
+        }
+    }
+
+    /// Returns the first selective ACK extension, if the packet has one.
+    pub fn selective_ack(&self) -> Option<SelectiveAck<'a>> {
+        let ack_nr = self.ack_nr();
+        self.extensions()
+            .find(|extension| extension.kind == SELECTIVE_ACK)
+            .map(|extension| SelectiveAck {
+                ack_nr,
+                bitmask: extension.data,
+            })
+    }
+
+    /// Returns the data that follows the extensions.
+    pub fn payload(&self) -> &'a [u8] {
+        &self.buf[self.payload_offset..]
+    }
+
+    /// Returns the whole packet.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.buf
+    }
+}
+
+/// Iterator over packet's extensions, see `Packet::extensions()`.
+#[derive(Debug, Clone)]
+pub struct Extensions<'a> {
+    kind: u8,
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for Extensions<'a> {
+    type Item = Extension<'a>;
+
+    fn next(&mut self) -> Option<Extension<'a>> {
+        // the chain is validated by `Packet::parse()`
+        if self.kind == 0 || self.rest.len() < 2 {
+            return None;
+        }
+        let len = usize::from(self.rest[1]);
+        let extension = Extension {
+            kind: self.kind,
+            data: &self.rest[2..2 + len],
+        };
+        self.kind = self.rest[0];
+        self.rest = &self.rest[2 + len..];
+        Some(extension)
+    }
+}
+
+/// Selective ACK extension: bitmask of packets received after a gap. The least significant bit
+/// of the first byte stands for packet `ack_nr + 2`, since `ack_nr + 1` is the missing one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectiveAck<'a> {
+    ack_nr: u16,
+    bitmask: &'a [u8],
+}
+
+impl<'a> SelectiveAck<'a> {
+    /// Returns the raw bitmask.
+    pub fn bitmask(&self) -> &'a [u8] {
+        self.bitmask
+    }
+
+    /// Returns `true`, if the bitmask marks the packet with the given sequence number as received.
+    pub fn is_acked(&self, seq_nr: u16) -> bool {
+        let bit = usize::from(seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2));
+        self.bitmask
+            .get(bit / 8)
+            .map_or(false, |byte| byte & (1 << (bit % 8)) != 0)
+    }
+
+    /// Returns sequence numbers of all the packets the bitmask marks as received.
+    pub fn acked(&self) -> Vec<u16> {
+        let first = self.ack_nr.wrapping_add(2);
+        (0..self.bitmask.len() * 8)
+            .map(|bit| first.wrapping_add(bit as u16))
+            .filter(|seq_nr| self.is_acked(*seq_nr))
+            .collect()
+    }
+}
+
+fn check_selective_ack(kind: u8, len: usize) -> Result<(), PacketError> {
+    if kind == SELECTIVE_ACK && (len == 0 || len % 4 != 0) {
+        return Err(PacketError::InvalidSelectiveAck(len));
+    }
+    Ok(())
+}
+
+fn get_u16(buf: &[u8], i: usize) -> u16 {
+    u16::from(buf[i]) << 8 | u16::from(buf[i + 1])
+}
+
+fn get_u32(buf: &[u8], i: usize) -> u32 {
+    u32::from(get_u16(buf, i)) << 16 | u32::from(get_u16(buf, i + 2))
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.push((value >> 8) as u8);
+    buf.push(value as u8);
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    put_u16(buf, (value >> 16) as u16);
+    put_u16(buf, value as u16);
+}
@@ -0,0 +1,364 @@
+//! Writes UDP datagrams into pcap files that Wireshark and tcpdump open directly and reads them
+//! back.
+//!
+//! Packets are stored as raw IP packets, so IPv4 and IPv6 traffic can share a file. IP and UDP
+//! headers are synthesized from the given addresses, since the datagrams are captured above the
+//! socket layer.
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// pcap magic number for microsecond timestamps.
+pub const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+/// pcap magic number for nanosecond timestamps.
+pub const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+/// `LINKTYPE_NULL`: BSD loopback, packets start with 4 byte address family.
+pub const LINKTYPE_NULL: u32 = 0;
+/// `LINKTYPE_ETHERNET`: packets start with Ethernet header.
+pub const LINKTYPE_ETHERNET: u32 = 1;
+/// `LINKTYPE_RAW`: packets start with IPv4 or IPv6 header.
+pub const LINKTYPE_RAW: u32 = 101;
+/// `LINKTYPE_LINUX_SLL`: Linux "any" device captures, packets start with 16 byte header.
+pub const LINKTYPE_LINUX_SLL: u32 = 113;
+/// `LINKTYPE_IPV4`: packets start with IPv4 header.
+pub const LINKTYPE_IPV4: u32 = 228;
+/// `LINKTYPE_IPV6`: packets start with IPv6 header.
+pub const LINKTYPE_IPV6: u32 = 229;
+/// The longest packet stored in full. Like libpcap, the reader rejects longer records: their
+/// length is most likely garbage.
+const SNAPLEN: u32 = 262_144;
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const UDP_HEADER_SIZE: usize = 8;
+const IPPROTO_UDP: u8 = 17;
+const TTL: u8 = 64;
+
+/// Writes UDP datagrams into a pcap stream.
+pub struct PcapWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Writes pcap file header and returns the writer ready for packets.
+    pub fn new(mut out: W) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        // timezone offset and timestamp accuracy are always zero
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        out.write_all(&header)?;
+        Ok(Self { out })
+    }
+
+    /// Records UDP datagram sent from `src` to `dst` at the given time. IPv4 address paired with
+    /// IPv6 one is written as IPv4-mapped IPv6 address.
+    pub fn write_udp(
+        &mut self,
+        timestamp: SystemTime,
+        src: SocketAddr,
+        dst: SocketAddr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let packet = ip_packet(src, dst, payload)?;
+        let since_epoch = timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "time before epoch"))?;
+
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&(since_epoch.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&since_epoch.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet);
+        self.out.write_all(&record)
+    }
+
+    /// Flushes the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// UDP datagram read from a capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpRecord {
+    /// When the datagram was captured.
+    pub timestamp: SystemTime,
+    /// Sender address.
+    pub src: SocketAddr,
+    /// Recipient address.
+    pub dst: SocketAddr,
+    /// UDP payload.
+    pub payload: Vec<u8>,
+}
+
+/// Reads UDP datagrams from pcap stream, e.g. written by `PcapWriter` or tcpdump. Raw IP,
+/// Ethernet, BSD loopback and Linux cooked captures are understood. Records that don't hold a
+/// whole UDP datagram, e.g. other protocols, IP fragments or truncated packets, are skipped.
+pub struct PcapReader<R: Read> {
+    input: R,
+    big_endian: bool,
+    nanos: bool,
+    linktype: u32,
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Reads pcap file header. Fails with `io::ErrorKind::InvalidData`, if the stream is not a
+    /// pcap stream or its link type is not supported.
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0; 24];
+        input.read_exact(&mut header)?;
+        let magic_le = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let magic_be = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let (big_endian, nanos) = match (magic_le, magic_be) {
+            (PCAP_MAGIC, _) => (false, false),
+            (PCAP_MAGIC_NANOS, _) => (false, true),
+            (_, PCAP_MAGIC) => (true, false),
+            (_, PCAP_MAGIC_NANOS) => (true, true),
+            _ => return Err(invalid_data("not a pcap stream")),
+        };
+        let mut reader = Self {
+            input,
+            big_endian,
+            nanos,
+            linktype: 0,
+        };
+        reader.linktype = reader.u32_at(&header, 20);
+        match reader.linktype {
+            LINKTYPE_NULL | LINKTYPE_ETHERNET | LINKTYPE_RAW | LINKTYPE_LINUX_SLL
+            | LINKTYPE_IPV4 | LINKTYPE_IPV6 => Ok(reader),
+            _ => Err(invalid_data("unsupported pcap link type")),
+        }
+    }
+
+    /// Returns the next UDP datagram, `None` at the end of the stream. Fails with
+    /// `io::ErrorKind::InvalidData`, if a record claims to be longer than 256 KiB.
+    pub fn read_udp(&mut self) -> io::Result<Option<UdpRecord>> {
+        loop {
+            let mut header = [0; 16];
+            match self.input.read_exact(&mut header) {
+                Ok(()) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            let secs = self.u32_at(&header, 0);
+            let fraction = self.u32_at(&header, 4);
+            let captured_len = self.u32_at(&header, 8) as usize;
+            let original_len = self.u32_at(&header, 12) as usize;
+            if captured_len > SNAPLEN as usize {
+                return Err(invalid_data("pcap record is too long"));
+            }
+            let mut packet = vec![0; captured_len];
+            self.input.read_exact(&mut packet)?;
+            if captured_len < original_len {
+                continue;
+            }
+
+            let fraction = if self.nanos {
+                Duration::from_nanos(u64::from(fraction))
+            } else {
+                Duration::from_micros(u64::from(fraction))
+            };
+            let timestamp = UNIX_EPOCH + Duration::from_secs(u64::from(secs)) + fraction;
+            if let Some((src, dst, payload)) = self.udp_datagram(&packet) {
+                return Ok(Some(UdpRecord {
+                    timestamp,
+                    src,
+                    dst,
+                    payload: payload.to_vec(),
+                }));
+            }
+        }
+    }
+
+    /// Returns the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+
+    fn u32_at(&self, buf: &[u8], i: usize) -> u32 {
+        let bytes = [buf[i], buf[i + 1], buf[i + 2], buf[i + 3]];
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    /// Strips link layer header.
+    fn udp_datagram<'a>(&self, packet: &'a [u8]) -> Option<(SocketAddr, SocketAddr, &'a [u8])> {
+        let ip_packet = match self.linktype {
+            LINKTYPE_NULL => packet.get(4..)?,
+            LINKTYPE_ETHERNET => {
+                let mut ethertype_at = 12;
+                // skip VLAN tags
+                while packet.get(ethertype_at..ethertype_at + 2)? == [0x81, 0x00] {
+                    ethertype_at += 4;
+                }
+                match packet.get(ethertype_at..ethertype_at + 2)? {
+                    [0x08, 0x00] | [0x86, 0xdd] => packet.get(ethertype_at + 2..)?,
+                    _ => return None,
+                }
+            }
+            LINKTYPE_LINUX_SLL => match packet.get(14..16)? {
+                [0x08, 0x00] | [0x86, 0xdd] => packet.get(16..)?,
+                _ => return None,
+            },
+            _ => packet,
+        };
+        parse_ip_udp(ip_packet)
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = io::Result<UdpRecord>;
+
+    fn next(&mut self) -> Option<io::Result<UdpRecord>> {
+        self.read_udp().transpose()
+    }
+}
+
+/// Extracts UDP datagram from IPv4 or IPv6 packet.
+fn parse_ip_udp(packet: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let get_u16 = |buf: &[u8], i: usize| u16::from(buf[i]) << 8 | u16::from(buf[i + 1]);
+    let (src_ip, dst_ip, udp): (IpAddr, IpAddr, &[u8]) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            if packet.len() < IPV4_HEADER_SIZE || header_len < IPV4_HEADER_SIZE {
+                return None;
+            }
+            // more fragments flag or fragment offset
+            let is_fragment = get_u16(packet, 6) & 0x3fff != 0;
+            if packet[9] != IPPROTO_UDP || is_fragment {
+                return None;
+            }
+            let total_len = usize::from(get_u16(packet, 2));
+            let ip =
+                |i: usize| Ipv4Addr::new(packet[i], packet[i + 1], packet[i + 2], packet[i + 3]);
+            (
+                IpAddr::V4(ip(12)),
+                IpAddr::V4(ip(16)),
+                packet.get(header_len..total_len)?,
+            )
+        }
+        6 => {
+            if packet.len() < IPV6_HEADER_SIZE || packet[6] != IPPROTO_UDP {
+                return None;
+            }
+            let payload_len = usize::from(get_u16(packet, 4));
+            let mut ip = [[0; 16]; 2];
+            ip[0].copy_from_slice(&packet[8..24]);
+            ip[1].copy_from_slice(&packet[24..40]);
+            (
+                IpAddr::V6(Ipv6Addr::from(ip[0])),
+                IpAddr::V6(Ipv6Addr::from(ip[1])),
+                packet.get(IPV6_HEADER_SIZE..IPV6_HEADER_SIZE + payload_len)?,
+            )
+        }
+        _ => return None,
+    };
+    if udp.len() < UDP_HEADER_SIZE {
+        return None;
+    }
+    let udp_len = usize::from(get_u16(udp, 4));
+    let payload = udp.get(UDP_HEADER_SIZE..udp_len)?;
+    Some((
+        SocketAddr::new(src_ip, get_u16(udp, 0)),
+        SocketAddr::new(dst_ip, get_u16(udp, 2)),
+        payload,
+    ))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Builds IP packet carrying UDP datagram.
+fn ip_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let too_long = || io::Error::new(io::ErrorKind::InvalidInput, "UDP payload too long");
+    let udp_len = UDP_HEADER_SIZE + payload.len();
+    if udp_len > usize::from(u16::max_value()) {
+        return Err(too_long());
+    }
+
+    let (src_ip, dst_ip) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            (src_ip.octets().to_vec(), dst_ip.octets().to_vec())
+        }
+        (src_ip, dst_ip) => (
+            to_ipv6(src_ip).octets().to_vec(),
+            to_ipv6(dst_ip).octets().to_vec(),
+        ),
+    };
+    let mut packet = Vec::with_capacity(IPV6_HEADER_SIZE + udp_len);
+    if src_ip.len() == 4 {
+        let total_len = IPV4_HEADER_SIZE + udp_len;
+        if total_len > usize::from(u16::max_value()) {
+            return Err(too_long());
+        }
+        packet.extend_from_slice(&[0x45, 0]);
+        packet.extend_from_slice(&(total_len as u16).to_be_bytes());
+        // identification, don't fragment flag
+        packet.extend_from_slice(&[0, 0, 0x40, 0, TTL, IPPROTO_UDP, 0, 0]);
+        packet.extend_from_slice(&src_ip);
+        packet.extend_from_slice(&dst_ip);
+        let checksum = !ones_complement_sum(&packet, 0);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    } else {
+        packet.extend_from_slice(&[0x60, 0, 0, 0]);
+        packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+        packet.extend_from_slice(&[IPPROTO_UDP, TTL]);
+        packet.extend_from_slice(&src_ip);
+        packet.extend_from_slice(&dst_ip);
+    }
+
+    let udp_start = packet.len();
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+
+    // pseudo header: addresses, protocol and UDP length. IPv6 variant differs only by zero
+    // padding, which doesn't change the sum.
+    let mut pseudo_header = Vec::with_capacity(36);
+    pseudo_header.extend_from_slice(&src_ip);
+    pseudo_header.extend_from_slice(&dst_ip);
+    pseudo_header.extend_from_slice(&[0, IPPROTO_UDP]);
+    pseudo_header.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    let sum = ones_complement_sum(&pseudo_header, 0);
+    let checksum = match !ones_complement_sum(&packet[udp_start..], sum) {
+        // zero means no checksum, all ones is its equivalent
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    packet[udp_start + 6..udp_start + 8].copy_from_slice(&checksum.to_be_bytes());
+    Ok(packet)
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Internet checksum arithmetic, see RFC 1071.
+fn ones_complement_sum(data: &[u8], initial: u16) -> u16 {
+    let mut sum = u32::from(initial);
+    for chunk in data.chunks(2) {
+        let word = u16::from(chunk[0]) << 8 | u16::from(*chunk.get(1).unwrap_or(&0));
+        sum += u32::from(word);
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
@@ -0,0 +1,98 @@
+//! Received data kept until the application reads it.
+
+use socket::UtpSocketId;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::HashMap;
+
+/// Data received by a single socket.
+#[derive(Default)]
+struct ReadBuffer {
+    data: Vec<u8>,
+    /// Remote peer closed the connection, no more data will be received.
+    eof: bool,
+}
+
+/// Per socket receive buffers. Once enabled, libutp's receive window shrinks by the amount of data
+/// buffered, so a slow reader throttles the sender instead of exhausting memory.
+#[derive(Default)]
+pub struct ReadBuffers {
+    enabled: Cell<bool>,
+    buffers: RefCell<HashMap<UtpSocketId, ReadBuffer>>,
+}
+
+/// Outcome of reading from the buffer.
+pub enum ReadResult {
+    /// This many bytes were copied into the given buffer.
+    Data(usize),
+    /// All the data was read and no more will arrive.
+    Eof,
+    /// Nothing to read yet.
+    Empty,
+}
+
+impl ReadBuffers {
+    pub fn enable(&self) {
+        self.enabled.set(true);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    /// Appends data received by the socket.
+    pub fn push(&self, id: UtpSocketId, data: &[u8]) {
+        self.buffers
+            .borrow_mut()
+            .entry(id)
+            .or_default()
+            .data
+            .extend_from_slice(data);
+    }
+
+    /// Marks the end of the data stream.
+    pub fn set_eof(&self, id: UtpSocketId) {
+        self.buffers.borrow_mut().entry(id).or_default().eof = true;
+    }
+
+    /// Discards buffered data and marks the end of the data stream, once reads are shut down.
+    pub fn close(&self, id: UtpSocketId) {
+        let mut buffers = self.buffers.borrow_mut();
+        let buffer = buffers.entry(id).or_default();
+        buffer.data.clear();
+        buffer.eof = true;
+    }
+
+    /// Moves as much buffered data as fits into `buf`.
+    pub fn read(&self, id: UtpSocketId, buf: &mut [u8]) -> ReadResult {
+        let mut buffers = self.buffers.borrow_mut();
+        let buffer = match buffers.get_mut(&id) {
+            Some(buffer) => buffer,
+            None => return ReadResult::Empty,
+        };
+        if buffer.data.is_empty() {
+            return if buffer.eof {
+                ReadResult::Eof
+            } else {
+                ReadResult::Empty
+            };
+        }
+        let len = cmp::min(buf.len(), buffer.data.len());
+        buf[..len].copy_from_slice(&buffer.data[..len]);
+        let _ = buffer.data.drain(..len);
+        ReadResult::Data(len)
+    }
+
+    /// Returns how many bytes are buffered for the socket.
+    pub fn len(&self, id: UtpSocketId) -> usize {
+        self.buffers
+            .borrow()
+            .get(&id)
+            .map_or(0, |buffer| buffer.data.len())
+    }
+
+    /// Drops the socket's buffer.
+    pub fn forget(&self, id: UtpSocketId) {
+        let _ = self.buffers.borrow_mut().remove(&id);
+    }
+}
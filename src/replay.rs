@@ -0,0 +1,305 @@
+//! Replays captured traffic into a uTP context, e.g. to turn field captures into regression tests.
+
+use addr::normalize_addr;
+use clock::ManualClock;
+use ctx::UtpContext;
+use event::UtpEvent;
+use packet::{Packet, PacketType};
+use pcap::{PcapReader, UdpRecord};
+use socket::UtpSocket;
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::mem;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Event reported by the replayed context.
+#[derive(Debug)]
+pub struct ReplayEvent {
+    /// Time since the start of the capture.
+    pub at: Duration,
+    /// `UtpEvent::Transmit` holds a packet the context sent.
+    pub event: UtpEvent,
+}
+
+/// Feeds captured packets to a fresh context in event queue mode, driven by a `ManualClock` that
+/// follows the capture's timestamps. Timeouts due between the packets fire at the right moment,
+/// so the context behaves as if the capture was happening live, and everything it does is
+/// recorded as `ReplayEvent`s.
+///
+/// The capture is the traffic of a context bound to `local_addr`, e.g. recorded with
+/// `UtpContext::start_capture()`. Packets sent to `local_addr` are fed to `process_udp()`, while
+/// the packets it sent tell which connections were initiated locally: they are connected again
+/// when the original SYN was sent. Also, since the replayed context picks its own random
+/// connection ids and sequence numbers, the original ones are learnt from the sent packets and
+/// received packets are rewritten to match the replayed connections. Data written by the
+/// original application is not replayed.
+///
+/// Records can be read from pcap stream with `from_pcap()` or made from any other trace format.
+pub struct Replay {
+    events: Vec<ReplayEvent>,
+    sockets: Vec<UtpSocket>,
+    utp: UtpContext<()>,
+    clock: ManualClock,
+    local_addr: SocketAddr,
+    records: VecDeque<UdpRecord>,
+    flows: Vec<Flow>,
+}
+
+/// Maps a single connection in the capture to the replayed one.
+struct Flow {
+    peer_addr: SocketAddr,
+    /// Connection id of the packets received in the capture.
+    orig_recv_id: u16,
+    /// Connection id of the packets sent in the capture.
+    orig_send_id: u16,
+    /// Connection ids of the replayed connection, unknown until its SYN is sent.
+    ids: Option<(u16, u16)>,
+    /// Sequence number of the first packet sent in the capture.
+    orig_seq_nr: Option<u16>,
+    /// Sequence number of the first packet sent by the replayed connection.
+    seq_nr: Option<u16>,
+}
+
+impl Flow {
+    /// Returns how much acknowledgements sent by remote peer must be shifted.
+    fn ack_offset(&self) -> u16 {
+        match (self.orig_seq_nr, self.seq_nr) {
+            (Some(orig_seq_nr), Some(seq_nr)) => seq_nr.wrapping_sub(orig_seq_nr),
+            _ => 0,
+        }
+    }
+}
+
+impl Replay {
+    /// Prepares the records for replay. They are replayed in the given order, starting at the
+    /// time of the first one.
+    pub fn new(local_addr: SocketAddr, records: Vec<UdpRecord>) -> Self {
+        let start = records
+            .first()
+            .map_or(UNIX_EPOCH, |record| record.timestamp);
+        let clock = ManualClock::new(start);
+        let mut utp = UtpContext::new(());
+        utp.set_clock(clock.clone());
+        utp.enable_events();
+        Self {
+            events: Vec::new(),
+            sockets: Vec::new(),
+            utp,
+            clock,
+            local_addr: normalize_addr(local_addr),
+            records: records.into(),
+            flows: Vec::new(),
+        }
+    }
+
+    /// Reads UDP datagrams from pcap stream, see `PcapReader`.
+    pub fn from_pcap<R: Read>(local_addr: SocketAddr, pcap: R) -> io::Result<Self> {
+        let records = PcapReader::new(pcap)?.collect::<io::Result<Vec<_>>>()?;
+        Ok(Self::new(local_addr, records))
+    }
+
+    /// Replays all the remaining records.
+    pub fn run(&mut self) {
+        while self.step() {}
+    }
+
+    /// Replays the next record. Returns `false`, if there are no more records.
+    pub fn step(&mut self) -> bool {
+        let record = match self.records.pop_front() {
+            Some(record) => record,
+            None => return false,
+        };
+        self.advance_to(record.timestamp);
+        let src = normalize_addr(record.src);
+        let dst = normalize_addr(record.dst);
+        if self.is_local(dst) {
+            self.receive(src, record.payload);
+        } else if self.is_local(src) {
+            self.learn_sent(dst, &record.payload);
+        }
+        true
+    }
+
+    /// Lets the time run, e.g. after the last record to see what timeouts do.
+    pub fn advance(&mut self, duration: Duration) {
+        let until = self.clock.system_time() + duration;
+        self.advance_to(until);
+    }
+
+    /// Returns the events recorded so far.
+    pub fn events(&self) -> &[ReplayEvent] {
+        &self.events
+    }
+
+    /// Takes the events recorded so far.
+    pub fn take_events(&mut self) -> Vec<ReplayEvent> {
+        mem::take(&mut self.events)
+    }
+
+    /// Returns the sockets of the connections initiated by the replay.
+    pub fn connected_sockets(&self) -> &[UtpSocket] {
+        &self.sockets
+    }
+
+    /// Returns the replayed context, e.g. to write data over the replayed connections.
+    pub fn utp(&mut self) -> &mut UtpContext<()> {
+        &mut self.utp
+    }
+
+    /// Returns the clock that drives the replayed context.
+    pub fn clock(&self) -> &ManualClock {
+        &self.clock
+    }
+
+    fn is_local(&self, addr: SocketAddr) -> bool {
+        if self.local_addr.ip().is_unspecified() {
+            addr.port() == self.local_addr.port()
+        } else {
+            addr == self.local_addr
+        }
+    }
+
+    /// Fires timeouts that are due until the given time.
+    fn advance_to(&mut self, time: SystemTime) {
+        while let Some(timeout) = self.utp.next_timeout() {
+            if self.clock.system_time() + timeout > time {
+                break;
+            }
+            self.clock.advance(timeout);
+            self.utp.check_timeouts();
+            self.collect_events();
+        }
+        self.clock.advance_to(time);
+    }
+
+    fn receive(&mut self, sender_addr: SocketAddr, mut packet: Vec<u8>) {
+        self.translate_received(sender_addr, &mut packet);
+        // illegal packets are ignored by the original context too
+        let _ = self.utp.process_udp(&packet, sender_addr);
+        self.collect_events();
+        self.utp.ack_packets();
+        self.collect_events();
+    }
+
+    /// Rewrites the packet received in the capture to match the replayed connection.
+    fn translate_received(&mut self, sender_addr: SocketAddr, packet: &mut [u8]) {
+        let (packet_type, connection_id, ack_nr) = match Packet::parse(packet) {
+            Ok(packet) => (
+                packet.packet_type(),
+                packet.connection_id(),
+                packet.ack_nr(),
+            ),
+            Err(_) => return,
+        };
+        if packet_type == PacketType::Syn {
+            let orig_recv_id = connection_id.wrapping_add(1);
+            if self.find_flow(sender_addr, orig_recv_id).is_some() {
+                return;
+            }
+            // remote peer picks the ids of incoming connections
+            self.flows.push(Flow {
+                peer_addr: sender_addr,
+                orig_recv_id,
+                orig_send_id: connection_id,
+                ids: Some((connection_id.wrapping_add(1), connection_id)),
+                orig_seq_nr: None,
+                seq_nr: None,
+            });
+            return;
+        }
+        let flow = match self.find_flow(sender_addr, connection_id) {
+            Some(flow) => flow,
+            None => return,
+        };
+        if let Some((recv_id, _)) = flow.ids {
+            packet[2..4].copy_from_slice(&recv_id.to_be_bytes());
+        }
+        let ack_nr = ack_nr.wrapping_add(flow.ack_offset());
+        packet[18..20].copy_from_slice(&ack_nr.to_be_bytes());
+    }
+
+    /// Learns about the connection from the packet sent in the capture.
+    fn learn_sent(&mut self, addr: SocketAddr, packet: &[u8]) {
+        let packet = match Packet::parse(packet) {
+            Ok(packet) => packet,
+            Err(_) => return,
+        };
+        if packet.packet_type() == PacketType::Syn {
+            // retransmitted SYN
+            if self.find_flow(addr, packet.connection_id()).is_some() {
+                return;
+            }
+            self.flows.push(Flow {
+                peer_addr: addr,
+                orig_recv_id: packet.connection_id(),
+                orig_send_id: packet.connection_id().wrapping_add(1),
+                ids: None,
+                orig_seq_nr: Some(packet.seq_nr()),
+                seq_nr: None,
+            });
+            // connection failures are reported as events
+            if let Ok(sock) = self.utp.connect(addr) {
+                self.sockets.push(sock);
+            }
+            self.collect_events();
+            return;
+        }
+        let flow = self
+            .flows
+            .iter_mut()
+            .rev()
+            .find(|flow| flow.peer_addr == addr && flow.orig_send_id == packet.connection_id());
+        if let Some(flow) = flow {
+            if flow.orig_seq_nr.is_none() {
+                flow.orig_seq_nr = Some(packet.seq_nr());
+            }
+        }
+    }
+
+    /// Finds the connection by the id of the packets received in the capture.
+    fn find_flow(&self, peer_addr: SocketAddr, orig_recv_id: u16) -> Option<&Flow> {
+        self.flows
+            .iter()
+            .rev()
+            .find(|flow| flow.peer_addr == peer_addr && flow.orig_recv_id == orig_recv_id)
+    }
+
+    /// Learns about the replayed connection from the packet it sent.
+    fn learn_replayed(&mut self, addr: SocketAddr, packet: &[u8]) {
+        let packet = match Packet::parse(packet) {
+            Ok(packet) => packet,
+            Err(_) => return,
+        };
+        let connection_id = packet.connection_id();
+        let flow = if packet.packet_type() == PacketType::Syn {
+            self.flows
+                .iter_mut()
+                .find(|flow| flow.peer_addr == addr && flow.ids.is_none())
+                .map(|flow| {
+                    flow.ids = Some((connection_id, connection_id.wrapping_add(1)));
+                    flow
+                })
+        } else {
+            self.flows.iter_mut().rev().find(|flow| {
+                flow.peer_addr == addr
+                    && flow.ids.map(|(_, send_id)| send_id) == Some(connection_id)
+            })
+        };
+        if let Some(flow) = flow {
+            if flow.seq_nr.is_none() {
+                flow.seq_nr = Some(packet.seq_nr());
+            }
+        }
+    }
+
+    fn collect_events(&mut self) {
+        let at = self.clock.elapsed();
+        for event in self.utp.poll_events() {
+            if let UtpEvent::Transmit(ref packet, addr) = event {
+                self.learn_replayed(addr, packet);
+            }
+            self.events.push(ReplayEvent { at, event });
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! Thread-safe uTP context.
+
+#![allow(unsafe_code)]
+
+use super::{
+    ManualClock, UtpCallbackArgs, UtpCallbackType, UtpContext, UtpError, UtpSocket, UtpSocketId,
+};
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Function type that will be called when some uTP event happens. Unlike `UtpCallback` it must be
+/// `Send`, because the callback might be called from any thread that uses the context.
+pub type SharedUtpCallback<T> = Box<dyn Fn(UtpCallbackArgs<T>) -> u64 + Send>;
+
+/// `UtpContext` handle that can be shared between threads. All libutp calls are serialized
+/// behind a single lock, so one thread can drive the context (`process_udp()`,
+/// `check_timeouts()`, etc.) while others connect and write data.
+///
+/// Callbacks are called while the lock is held, hence they must not use this context or its
+/// sockets - that would deadlock.
+pub struct SharedUtpContext<T> {
+    inner: Arc<Mutex<SendableContext<T>>>,
+}
+
+impl<T: Send> SharedUtpContext<T> {
+    /// Construct uTP context with given user data.
+    pub fn new(user_data: T) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(SendableContext(UtpContext::new(user_data)))),
+        }
+    }
+
+    /// Set uTP callback. See `UtpContext::set_callback()`.
+    pub fn set_callback(&self, cb_type: UtpCallbackType, cb: SharedUtpCallback<T>) {
+        self.lock().set_callback(cb_type, cb);
+    }
+
+    /// Sets some internal uTP context options.
+    pub fn set_option(&self, opt: u32, val: i32) {
+        self.lock().set_option(opt, val);
+    }
+
+    /// Buffers received data until it's read. See `UtpContext::enable_read_buffers()`.
+    pub fn enable_read_buffers(&self) {
+        self.lock().enable_read_buffers();
+    }
+
+    /// Records packets into pcap stream. See `UtpContext::start_capture()`.
+    pub fn start_capture<W>(&self, local_addr: SocketAddr, out: W) -> io::Result<()>
+    where
+        W: Write + Send + 'static,
+    {
+        self.lock().start_capture(local_addr, out)
+    }
+
+    /// Stops the capture and flushes the stream. See `UtpContext::stop_capture()`.
+    pub fn stop_capture(&self) -> io::Result<()> {
+        self.lock().stop_capture()
+    }
+
+    /// Drives the context by the given clock. See `UtpContext::set_clock()`.
+    pub fn set_clock(&self, clock: ManualClock) {
+        self.lock().set_clock(clock);
+    }
+
+    /// Seeds random numbers libutp uses. See `UtpContext::set_random_seed()`.
+    pub fn set_random_seed(&self, seed: u64) {
+        self.lock().set_random_seed(seed);
+    }
+
+    /// Enables or disables debug logging.
+    pub fn set_debug_log(&self, debug_log: bool) {
+        self.lock().set_debug_log(debug_log);
+    }
+
+    /// Runs given function with mutable reference to user data stored in uTP context.
+    pub fn with_user_data<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        f(self.lock().user_data_mut())
+    }
+
+    /// Feed UDP packet to underlying uTP library. See `UtpContext::process_udp()`.
+    pub fn process_udp(&self, packet: &[u8], sender_addr: SocketAddr) -> Result<(), UtpError> {
+        self.lock().process_udp(packet, sender_addr)
+    }
+
+    /// Attempt to make a uTP connection to a given address.
+    pub fn connect(&self, addr: SocketAddr) -> Result<SharedUtpSocket<T>, UtpError> {
+        let sock = self.lock().connect(addr)?;
+        Ok(SharedUtpSocket {
+            ctx: self.clone(),
+            sock: Some(sock),
+        })
+    }
+
+    /// Returns a handle for the socket that is owned by the context.
+    /// See `UtpContext::take_socket()`.
+    pub fn take_socket(&self, id: UtpSocketId) -> Option<SharedUtpSocket<T>> {
+        let sock = self.lock().take_socket(id)?;
+        Some(SharedUtpSocket {
+            ctx: self.clone(),
+            sock: Some(sock),
+        })
+    }
+
+    /// Sends all deferred ACK packets. See `UtpContext::ack_packets()`.
+    pub fn ack_packets(&self) {
+        self.lock().ack_packets();
+    }
+
+    /// Checks for timedout connections, etc. See `UtpContext::check_timeouts()`.
+    pub fn check_timeouts(&self) {
+        self.lock().check_timeouts();
+    }
+
+    /// Returns how long to wait before calling `check_timeouts()`.
+    /// See `UtpContext::next_timeout()`.
+    pub fn next_timeout(&self) -> Option<Duration> {
+        self.lock().next_timeout()
+    }
+
+    /// Locks the context. A panic in some callback poisons the lock, but libutp state stays
+    /// consistent, because panics are only resumed once libutp returns.
+    fn lock(&self) -> MutexGuard<SendableContext<T>> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<T> Clone for SharedUtpContext<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+/// `UtpSocket` handle that can be sent to and shared between threads.
+pub struct SharedUtpSocket<T: Send> {
+    ctx: SharedUtpContext<T>,
+    /// Only touched while the context lock is held. `None` only while being dropped.
+    sock: Option<UtpSocket>,
+}
+
+impl<T: Send> SharedUtpSocket<T> {
+    /// Returns the id of this socket. See `UtpSocket::id()`.
+    pub fn id(&self) -> UtpSocketId {
+        // the id never changes, hence no need to lock
+        self.sock().id()
+    }
+
+    /// Write some data to uTP socket. See `UtpSocket::send()`.
+    pub fn send(&self, buf: &[u8]) -> Result<usize, UtpError> {
+        let _guard = self.ctx.lock();
+        self.sock().send(buf)
+    }
+
+    /// Queues all the given data to be written to the socket. See `UtpSocket::write_all()`.
+    pub fn write_all(&self, buf: &[u8]) -> Result<bool, UtpError> {
+        let _guard = self.ctx.lock();
+        self.sock().write_all(buf)
+    }
+
+    /// Sets the limits of the socket's write queue. See `UtpSocket::set_write_queue_limits()`.
+    pub fn set_write_queue_limits(
+        &self,
+        high_water: usize,
+        low_water: usize,
+    ) -> Result<(), UtpError> {
+        let _guard = self.ctx.lock();
+        self.sock().set_write_queue_limits(high_water, low_water)
+    }
+
+    /// Returns how many queued bytes are not handed to libutp yet.
+    pub fn queued_len(&self) -> usize {
+        let _guard = self.ctx.lock();
+        self.sock().queued_len()
+    }
+
+    /// Reads received data into `buf`. See `UtpSocket::read()`.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, UtpError> {
+        let _guard = self.ctx.lock();
+        self.sock().read(buf)
+    }
+
+    /// Returns how many received bytes are waiting to be read.
+    pub fn buffered_len(&self) -> usize {
+        let _guard = self.ctx.lock();
+        self.sock().buffered_len()
+    }
+
+    /// Shutdown reads and/or writes on the socket.
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), UtpError> {
+        let _guard = self.ctx.lock();
+        self.sock().shutdown(how)
+    }
+
+    /// Closes the socket gracefully. See `UtpSocket::close()`.
+    pub fn close(&self) {
+        let _guard = self.ctx.lock();
+        self.sock().close()
+    }
+
+    /// Returns the address of remote peer this socket is connected to.
+    pub fn peer_addr(&self) -> Result<SocketAddr, UtpError> {
+        let _guard = self.ctx.lock();
+        self.sock().peer_addr()
+    }
+
+    fn sock(&self) -> &UtpSocket {
+        self.sock
+            .as_ref()
+            .expect("Socket is only taken out when it's dropped.")
+    }
+}
+
+impl<T: Send> Drop for SharedUtpSocket<T> {
+    fn drop(&mut self) {
+        let _guard = self.ctx.lock();
+        let _ = self.sock.take();
+    }
+}
+
+// `UtpSocket` shares non thread-safe state with its `UtpContext`. This is fine, because we only
+// touch the socket while holding the context lock.
+unsafe impl<T: Send> Send for SharedUtpSocket<T> {}
+unsafe impl<T: Send> Sync for SharedUtpSocket<T> {}
+
+/// `UtpContext` is not `Send` because it holds raw pointers to libutp structures and state shared
+/// with its sockets. Once wrapped into `SharedUtpContext`, the context and all its sockets are only
+/// accessed while holding the lock and all the callbacks are `Send`, hence it's safe to move the
+/// context between threads.
+struct SendableContext<T>(UtpContext<T>);
+
+unsafe impl<T: Send> Send for SendableContext<T> {}
+
+impl<T> Deref for SendableContext<T> {
+    type Target = UtpContext<T>;
+
+    fn deref(&self) -> &UtpContext<T> {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for SendableContext<T> {
+    fn deref_mut(&mut self) -> &mut UtpContext<T> {
+        &mut self.0
+    }
+}
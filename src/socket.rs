@@ -3,40 +3,244 @@
 #![allow(unsafe_code)]
 
 use super::UtpError;
+use addr::from_raw_sockaddr;
+use bytes::Bytes;
+use command::Command;
+use ctx::CtxShared;
 use libutp_sys::*;
-use std::net::Shutdown;
+use read_buffer::ReadResult;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::net::{Shutdown, SocketAddr};
+use std::rc::Rc;
 
 const MAX_SIZE: isize = isize::max_value();
 
 /// Handle to virtual uTP socket that is not connected with a real socket.
 /// Note, `UtpSocket` has no read, you will receive `CallbackType::OnRead` when data arrives.
+///
+/// The socket shares ownership of the bookkeeping with the `UtpContext` that created it. Once
+/// the context is dropped, the socket is closed and all further operations on it fail with
+/// `UtpError::ContextDestroyed`. If the socket is closed by a deferred command or destroyed by
+/// libutp, operations fail with `UtpError::SocketClosed`.
 pub struct UtpSocket {
-    inner: *mut utp_socket,
+    id: UtpSocketId,
+    shared: Rc<CtxShared>,
 }
 
+/// Identifies uTP socket within its `UtpContext`. Unlike libutp socket handles, ids are never
+/// reused, so it's safe to keep the id around after the socket is destroyed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct UtpSocketId(usize);
+
 impl UtpSocket {
+    /// Returns the id of this socket. The same id is reported by `UtpEvent`s and
+    /// `UtpCallbackArgs::socket_id()` for this socket.
+    pub fn id(&self) -> UtpSocketId {
+        self.id
+    }
+
     /// Write some data to uTP socket and return the result.
     /// Partial write is possible - uTP might not accept all the given buffer. In such case it's
     /// up to you to make sure the rest of the data is sent.
+    ///
+    /// While data queued by `write_all()` or `UtpCallbackArgs::defer_write()` is not written yet,
+    /// `UtpError::WouldBlock` is returned, so that the data is not sent out of order.
+    /// `UtpState::Writable` is reported once the queue drains. If libutp failed to write the
+    /// queued data, `UtpError::SendFailed` is returned from then on. Once writes are shut down,
+    /// `UtpError::SocketClosed` is returned.
     pub fn send(&self, buf: &[u8]) -> Result<usize, UtpError> {
-        let res = unsafe { utp_write(self.inner, buf.as_ptr() as *mut _, buf.len()) };
+        let sock = self.raw_writer()?;
+        if self.shared.commands.writes_failed(self.id) {
+            return Err(UtpError::SendFailed);
+        }
+        if self.shared.commands.has_pending(self.id) {
+            self.shared.commands.block_send(self.id);
+            return Err(UtpError::WouldBlock);
+        }
+        let res = unsafe { utp_write(sock, buf.as_ptr() as *mut _, buf.len()) };
+        self.shared.finish_call();
         match res {
             -1 => Err(UtpError::SendFailed),
             0 => Err(UtpError::WouldBlock),
-            bytes_sent @ 1...MAX_SIZE => Ok(bytes_sent as usize),
+            bytes_sent @ 1..=MAX_SIZE => Ok(bytes_sent as usize),
             unknown => Err(UtpError::UnexpectedResult(unknown as i64)),
         }
     }
 
-    /// Shutdown reads and/or writes on the socket.
-    pub fn shutdown(&self, how: Shutdown) {
-        let how = match how {
-            Shutdown::Read => SHUT_RD,
-            Shutdown::Write => SHUT_WR,
-            Shutdown::Both => SHUT_RDWR,
-        } as i32;
-        unsafe {
-            utp_shutdown(self.inner, how);
+    /// Queues all the given data to be written to the socket. Unlike `send()`, this never
+    /// accepts only part of the data: what libutp can't take right away is written once the socket
+    /// becomes writable. Data queued with `UtpCallbackArgs::defer_write()` shares the same queue.
+    ///
+    /// Returns `false`, if the queued data exceeds the high-water mark set with
+    /// `set_write_queue_limits()`. Further data is still accepted, but the caller should wait until
+    /// `UtpState::Writable` is reported: libutp's own reports are held back and `Writable` is
+    /// reported once the queue drains to the low-water mark.
+    ///
+    /// If libutp fails to write the queued data, the rest of the queue is dropped and
+    /// `UtpError::SendFailed` is returned from then on. The failure is also reported to `OnError`
+    /// callback, or recorded as `UtpEvent::Error` in event queue mode. Fails with `UtpError::SocketClosed` once writes are shut
+    /// down.
+    pub fn write_all(&self, buf: &[u8]) -> Result<bool, UtpError> {
+        let _ = self.raw_writer()?;
+        if self.shared.commands.writes_failed(self.id) {
+            return Err(UtpError::SendFailed);
+        }
+        self.shared
+            .commands
+            .push(Command::Write(self.id, Bytes::from(buf)));
+        self.shared.finish_call();
+        Ok(!self.shared.commands.check_full(self.id))
+    }
+
+    /// Sets the high and low-water marks of the socket's write queue, see `write_all()`. Without
+    /// them the queue grows unbounded and `write_all()` always returns `true`. Low-water mark
+    /// greater than the high-water mark is rejected with `UtpError::InvalidWaterMarks`.
+    pub fn set_write_queue_limits(
+        &self,
+        high_water: usize,
+        low_water: usize,
+    ) -> Result<(), UtpError> {
+        let _ = self.raw()?;
+        if low_water > high_water {
+            return Err(UtpError::InvalidWaterMarks);
+        }
+        self.shared
+            .commands
+            .set_water_marks(self.id, high_water, low_water);
+        Ok(())
+    }
+
+    /// Returns how many bytes queued by `write_all()` are not handed to libutp yet.
+    pub fn queued_len(&self) -> usize {
+        self.shared.commands.queued_len(self.id)
+    }
+
+    /// Reads received data into `buf`. Only available once the context buffers received data, see
+    /// `UtpContext::enable_read_buffers()`, otherwise fails with
+    /// `UtpError::ReadBuffersDisabled`.
+    ///
+    /// Returns the number of bytes read: zero means remote peer closed the connection and all the
+    /// data was read, or reads were shut down, which discards the data not read yet. If there's
+    /// nothing to read yet, fails with `UtpError::WouldBlock`. The data received before libutp
+    /// destroys the socket can still be read afterwards.
+    ///
+    /// Reading lets remote peer send more, libutp might defer the window update until
+    /// `UtpContext::ack_packets()` is called.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, UtpError> {
+        if !self.shared.read_buffers.is_enabled() {
+            return Err(UtpError::ReadBuffersDisabled);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.shared.read_buffers.read(self.id, buf) {
+            ReadResult::Data(len) => {
+                // the receive window grew, libutp tells remote peer if it was closed
+                if let Ok(sock) = self.raw() {
+                    unsafe {
+                        utp_read_drained(sock);
+                    }
+                    self.shared.finish_call();
+                }
+                Ok(len)
+            }
+            ReadResult::Eof => Ok(0),
+            ReadResult::Empty => {
+                let _ = self.raw()?;
+                Err(UtpError::WouldBlock)
+            }
+        }
+    }
+
+    /// Returns how many received bytes are waiting to be read with `read()`.
+    pub fn buffered_len(&self) -> usize {
+        self.shared.read_buffers.len(self.id)
+    }
+
+    /// Shutdown reads and/or writes on the socket. If there's queued data, the socket is shut
+    /// down once all of it is written.
+    ///
+    /// Once writes are shut down, libutp sends FIN after the data it has buffered and remote peer
+    /// gets `UtpState::ConnectionClosed` after all that data. The connection stays open, so
+    /// incoming data is still delivered until remote peer closes its half too or reads are shut
+    /// down. Once reads are shut down, `OnRead` is not called anymore.
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), UtpError> {
+        let _ = self.raw()?;
+        if how != Shutdown::Read {
+            // data written from now on would follow FIN
+            self.shared.sockets.close_write(self.id);
+        }
+        if self.shared.commands.has_pending(self.id) {
+            self.shared.commands.push(Command::Shutdown(self.id, how));
+        } else {
+            shutdown_socket(&self.shared, self.id, how);
+        }
+        self.shared.finish_call();
+        Ok(())
+    }
+
+    /// Closes the socket gracefully: libutp sends the data it has buffered followed by FIN and
+    /// destroys the socket once remote peer acknowledges everything or the connection fails.
+    /// `UtpState::Destroying` is reported then, see `UtpCallbackArgs::delivered()`.
+    ///
+    /// Further operations on the socket fail with `UtpError::SocketClosed`. Dropping the socket
+    /// closes it the same way. Data queued by `write_all()` is written before the socket is
+    /// closed.
+    pub fn close(&self) {
+        self.close_raw();
+        self.shared.finish_call();
+    }
+
+    /// Returns the address of remote peer this socket is connected to.
+    pub fn peer_addr(&self) -> Result<SocketAddr, UtpError> {
+        let sock = self.raw()?;
+        let mut addr: sockaddr_storage = unsafe { mem::zeroed() };
+        let mut addr_len = mem::size_of::<sockaddr_storage>() as socklen_t;
+        let addr_ptr: *mut sockaddr_storage = &mut addr;
+        let res = unsafe { utp_getpeername(sock, addr_ptr as *mut sockaddr, &mut addr_len) };
+        if res != 0 {
+            return Err(UtpError::UnexpectedResult(i64::from(res)));
+        }
+        unsafe { from_raw_sockaddr(addr_ptr as *const sockaddr, addr_len as usize) }
+            .ok_or(UtpError::UnexpectedResult(i64::from(res)))
+    }
+
+    fn close_raw(&self) {
+        self.shared.read_buffers.forget(self.id);
+        // queued data is written first
+        if self.shared.commands.has_pending(self.id) {
+            if !self.shared.commands.is_closing(self.id) {
+                self.shared.commands.push(Command::Close(self.id));
+            }
+            return;
+        }
+        if let Some(sock) = self.shared.sockets.remove(self.id) {
+            self.shared.timers.on_close(self.id);
+            unsafe {
+                utp_close(sock);
+            }
+        }
+    }
+
+    /// Returns raw libutp socket handle, if the socket is still open for writing.
+    fn raw_writer(&self) -> Result<*mut utp_socket, UtpError> {
+        let sock = self.raw()?;
+        if self.shared.sockets.is_write_closed(self.id) {
+            return Err(UtpError::SocketClosed);
+        }
+        Ok(sock)
+    }
+
+    /// Returns raw libutp socket handle, if the socket is still open.
+    fn raw(&self) -> Result<*mut utp_socket, UtpError> {
+        match self.shared.sockets.get(self.id) {
+            Some(_) if self.shared.commands.is_closing(self.id) => Err(UtpError::SocketClosed),
+            Some(sock) => Ok(sock),
+            None if self.shared.is_destroyed() => Err(UtpError::ContextDestroyed),
+            None => Err(UtpError::SocketClosed),
         }
     }
 
@@ -44,14 +248,173 @@ impl UtpSocket {
     // uTP context
 }
 
-pub fn make_utp_socket(inner: *mut utp_socket) -> UtpSocket {
-    UtpSocket { inner }
+impl fmt::Debug for UtpSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UtpSocket({:?})", self.id)
+    }
+}
+
+/// Wraps raw libutp socket and registers it so that it's closed when either `UtpSocket` or
+/// `UtpContext` is dropped, whichever happens first. Sockets that are already registered, e.g.
+/// accepted ones, keep their ids.
+pub fn make_utp_socket(inner: *mut utp_socket, shared: Rc<CtxShared>) -> UtpSocket {
+    if let Some(sock) = socket_id(inner).and_then(|id| take_utp_socket(id, Rc::clone(&shared))) {
+        return sock;
+    }
+    let id = shared.sockets.next_id();
+    shared.sockets.insert(id, inner);
+    let _ = shared.sockets.take_handle(id);
+    UtpSocket { id, shared }
+}
+
+/// Makes a handle for the socket that is owned by the context. `None` is returned, if the socket
+/// is not open or already has a handle.
+pub fn take_utp_socket(id: UtpSocketId, shared: Rc<CtxShared>) -> Option<UtpSocket> {
+    if shared.sockets.take_handle(id) {
+        Some(UtpSocket { id, shared })
+    } else {
+        None
+    }
+}
+
+/// Shuts down reads and/or writes on the socket with the given id, if it's still open.
+///
+/// Shutting down writes makes libutp send FIN after the buffered data, but unlike `utp_close()`
+/// it doesn't close the connection: the socket keeps receiving until remote peer sends its FIN
+/// and is only destroyed after it's closed.
+pub fn shutdown_socket(shared: &CtxShared, id: UtpSocketId, how: Shutdown) {
+    if how != Shutdown::Write && shared.read_buffers.is_enabled() {
+        shared.read_buffers.close(id);
+    }
+    let sock = match shared.sockets.get(id) {
+        Some(sock) => sock,
+        None => return,
+    };
+    let how = match how {
+        Shutdown::Read => SHUT_RD,
+        Shutdown::Write => SHUT_WR,
+        Shutdown::Both => SHUT_RDWR,
+    };
+    if how != SHUT_RD {
+        shared.sockets.close_write(id);
+    }
+    unsafe {
+        utp_shutdown(sock, how as i32);
+    }
+}
+
+/// Returns the id assigned to given libutp socket, if any.
+pub fn socket_id(sock: *mut utp_socket) -> Option<UtpSocketId> {
+    if sock.is_null() {
+        return None;
+    }
+    match unsafe { utp_get_userdata(sock) } as usize {
+        0 => None,
+        id => Some(UtpSocketId(id)),
+    }
 }
 
 impl Drop for UtpSocket {
+    // NOTE, if some callback panics while the socket is being closed, the panic is resumed by the
+    // next `UtpContext` call rather than from within `drop()`.
     fn drop(&mut self) {
+        self.close_raw();
+    }
+}
+
+/// Keeps track of open libutp sockets: the ones owned by `UtpSocket` handles and the ones
+/// owned by the context itself, e.g. accepted in `OnAccept` callback or connected by a deferred
+/// command. The registry is shared between `UtpContext` and all the sockets it created.
+#[derive(Default)]
+pub struct SocketRegistry {
+    sockets: RefCell<HashMap<UtpSocketId, SocketEntry>>,
+    last_id: Cell<usize>,
+}
+
+struct SocketEntry {
+    sock: *mut utp_socket,
+    /// `true`, if the socket is owned by `UtpSocket` handle.
+    has_handle: bool,
+    /// `true`, if writes were shut down.
+    write_closed: bool,
+}
+
+impl SocketRegistry {
+    /// Allocates new socket id.
+    pub fn next_id(&self) -> UtpSocketId {
+        let id = self.last_id.get() + 1;
+        self.last_id.set(id);
+        UtpSocketId(id)
+    }
+
+    /// Registers the socket with the given id. The id is stored as libutp socket user data, so
+    /// that we could identify the socket in callbacks.
+    pub fn insert(&self, id: UtpSocketId, sock: *mut utp_socket) {
         unsafe {
-            utp_close(self.inner);
+            let _ = utp_set_userdata(sock, id.0 as *mut _);
+        }
+        let entry = SocketEntry {
+            sock,
+            has_handle: false,
+            write_closed: false,
+        };
+        let _ = self.sockets.borrow_mut().insert(id, entry);
+    }
+
+    /// Returns libutp socket with the given id, if it's still open.
+    pub fn get(&self, id: UtpSocketId) -> Option<*mut utp_socket> {
+        self.sockets.borrow().get(&id).map(|entry| entry.sock)
+    }
+
+    /// Marks the socket as owned by `UtpSocket` handle. Returns `false`, if the socket is not
+    /// open or already has a handle.
+    pub fn take_handle(&self, id: UtpSocketId) -> bool {
+        match self.sockets.borrow_mut().get_mut(&id) {
+            Some(ref mut entry) if !entry.has_handle => {
+                entry.has_handle = true;
+                true
+            }
+            _ => false,
         }
     }
+
+    /// Returns `true`, if the socket is owned by `UtpSocket` handle.
+    pub fn has_handle(&self, id: UtpSocketId) -> bool {
+        self.sockets
+            .borrow()
+            .get(&id)
+            .map_or(false, |entry| entry.has_handle)
+    }
+
+    /// Marks the socket's writes as shut down.
+    pub fn close_write(&self, id: UtpSocketId) {
+        if let Some(entry) = self.sockets.borrow_mut().get_mut(&id) {
+            entry.write_closed = true;
+        }
+    }
+
+    /// Returns `true`, if the socket's writes were shut down.
+    pub fn is_write_closed(&self, id: UtpSocketId) -> bool {
+        self.sockets
+            .borrow()
+            .get(&id)
+            .map_or(false, |entry| entry.write_closed)
+    }
+
+    /// Unregisters the socket. Returns it, if it was still registered.
+    pub fn remove(&self, id: UtpSocketId) -> Option<*mut utp_socket> {
+        self.sockets
+            .borrow_mut()
+            .remove(&id)
+            .map(|entry| entry.sock)
+    }
+
+    /// Unregisters all sockets and returns them, so that the caller could close them.
+    pub fn drain(&self) -> Vec<*mut utp_socket> {
+        self.sockets
+            .borrow_mut()
+            .drain()
+            .map(|(_, entry)| entry.sock)
+            .collect()
+    }
 }
@@ -0,0 +1,374 @@
+//! Blocking uTP stream.
+
+#[cfg(feature = "mio")]
+use super::UtpSocketId;
+use super::{SharedUtpSocket, UtpError};
+use driver::{Driver, StreamState};
+use endpoint::UtpEndpoint;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// uTP connection between a local and a remote socket, comparable to `std::net::TcpStream`.
+///
+/// Data is read and written with `Read` and `Write` traits. Streams opened with `connect()` own
+/// UDP socket and uTP context that are driven by a background thread, hence no callbacks are
+/// involved. Streams made by `UtpEndpoint` share them with the endpoint.
+pub struct UtpStream {
+    sock: SharedUtpSocket<()>,
+    driver: Arc<Driver>,
+    read_timeout: Mutex<Option<Duration>>,
+    write_timeout: Mutex<Option<Duration>>,
+    linger: Mutex<Option<Duration>>,
+    nonblocking: AtomicBool,
+}
+
+impl UtpStream {
+    /// Opens uTP connection to a remote host. If `addr` yields multiple addresses, connection is
+    /// attempted with each of them until it succeeds.
+    ///
+    /// The call blocks until the connection is established or libutp gives up.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<UtpStream> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            let res = UtpEndpoint::bind_with_backlog(any_addr_for(&addr), 0)
+                .and_then(|endpoint| endpoint.connect(addr));
+            match res {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any addresses",
+            )
+        }))
+    }
+
+    /// Opens uTP connection to a remote host, giving up once the timeout elapses. Unlike
+    /// `connect()`, it takes a single address.
+    ///
+    /// If the connection is not established in time, the call fails with
+    /// `io::ErrorKind::TimedOut` holding `UtpError::ConnectTimedOut` and the socket is closed.
+    /// Zero timeout is rejected with `io::ErrorKind::InvalidInput`.
+    pub fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> io::Result<UtpStream> {
+        UtpEndpoint::bind_with_backlog(any_addr_for(addr), 0)?.connect_timeout(*addr, timeout)
+    }
+
+    /// Returns the socket address of the remote peer of this connection.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.sock.peer_addr()?)
+    }
+
+    /// Returns the socket address of the local half of this connection, i.e. the address
+    /// underlying UDP socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.driver.local_addr()
+    }
+
+    /// Shuts down the read, write, or both halves of this connection. Reading from shut down
+    /// stream returns `Ok(0)`, writing fails with `io::ErrorKind::BrokenPipe`.
+    ///
+    /// Once the write half is shut down, remote peer reads all the data written so far followed
+    /// by end of stream, while this side can keep reading until the peer shuts down its write
+    /// half too. Data received after the read half is shut down is discarded.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        // mark the state first, so that it outlives the socket if libutp destroys it right away
+        self.update(|stream| {
+            if how != Shutdown::Write {
+                stream.read_closed = true;
+            }
+            if how != Shutdown::Read {
+                stream.write_closed = true;
+            }
+        });
+        self.sock.shutdown(how)?;
+        Ok(())
+    }
+
+    /// Closes the connection gracefully and waits until remote peer acknowledges all the data
+    /// written to the stream. Unlike dropping the stream, which closes the socket in the
+    /// background, this lets the caller know the data was delivered, e.g. before the process
+    /// exits.
+    ///
+    /// libutp sends the data it has buffered followed by FIN and the call blocks until the
+    /// socket is destroyed, even in nonblocking mode. If the linger timeout elapses first, the
+    /// call fails with `io::ErrorKind::TimedOut` and libutp keeps trying in the background for
+    /// as long as the socket's uTP context is alive. If the connection fails or the peer doesn't
+    /// acknowledge everything, the connection error or `UtpError::NotDelivered` is returned.
+    pub fn close(self) -> io::Result<()> {
+        let deadline = deadline(*lock(&self.linger));
+        self.update(|stream| stream.closing = true);
+        self.sock.close();
+        let delivered = self.wait(deadline, |stream| stream.delivered)?;
+        if delivered {
+            return Ok(());
+        }
+        Err(self.update(|stream| match stream.error {
+            Some(error) => error.into(),
+            None => UtpError::NotDelivered.into(),
+        }))
+    }
+
+    /// Sets how long `close()` waits for the data to be delivered. If `None` is given, `close()`
+    /// waits until libutp either delivers everything or gives up. Zero duration is rejected
+    /// with `io::ErrorKind::InvalidInput`.
+    pub fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        *lock(&self.linger) = check_timeout(linger)?;
+        Ok(())
+    }
+
+    /// Returns the linger timeout.
+    pub fn linger(&self) -> io::Result<Option<Duration>> {
+        Ok(*lock(&self.linger))
+    }
+
+    /// Sets the read timeout. If `None` is given, `read()` blocks indefinitely. Otherwise, if
+    /// no data arrives in time, `read()` fails with `io::ErrorKind::TimedOut`.
+    ///
+    /// Zero duration is rejected with `io::ErrorKind::InvalidInput`, just like
+    /// `TcpStream::set_read_timeout()` does.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *lock(&self.read_timeout) = check_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// Sets the write timeout. If libutp doesn't accept any data in time, `write()` fails with
+    /// `io::ErrorKind::TimedOut`. See `set_read_timeout()`.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *lock(&self.write_timeout) = check_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// Returns the read timeout.
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*lock(&self.read_timeout))
+    }
+
+    /// Returns the write timeout.
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*lock(&self.write_timeout))
+    }
+
+    /// Moves this stream into or out of nonblocking mode. In nonblocking mode `read()` and
+    /// `write()` fail with `io::ErrorKind::WouldBlock` instead of waiting for data or buffer
+    /// space.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::SeqCst);
+        Ok(())
+    }
+
+    #[cfg(feature = "mio")]
+    pub(crate) fn id(&self) -> UtpSocketId {
+        self.sock.id()
+    }
+
+    #[cfg(feature = "mio")]
+    pub(crate) fn driver(&self) -> &Arc<Driver> {
+        &self.driver
+    }
+
+    fn read_data(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = deadline(*lock(&self.read_timeout));
+        loop {
+            if let Some(res) = self.driver.streams().try_read(&self.sock, buf) {
+                return res;
+            }
+            self.poll(deadline, |stream| {
+                if stream.readable || stream.read_closed || stream.error.is_some() {
+                    Some(())
+                } else {
+                    None
+                }
+            })?;
+        }
+    }
+
+    fn write_data(&self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = deadline(*lock(&self.write_timeout));
+        loop {
+            // if libutp becomes writable while we're sending, the flag is set again and we don't
+            // miss the wakeup
+            self.update(|stream| {
+                stream.writable = false;
+                stream_error(stream)
+            })?;
+            match self.sock.send(buf) {
+                Ok(bytes_sent) => {
+                    // libutp took everything, so it probably takes more
+                    if bytes_sent == buf.len() {
+                        self.update(|stream| stream.writable = true);
+                    }
+                    return Ok(bytes_sent);
+                }
+                Err(UtpError::WouldBlock) => (),
+                Err(e) => return Err(e.into()),
+            }
+            self.poll(deadline, |stream| match stream_error(stream) {
+                Err(e) => Some(Err(e)),
+                Ok(()) if stream.writable => Some(Ok(())),
+                Ok(()) => None,
+            })??;
+        }
+    }
+
+    fn update<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut StreamState) -> R,
+    {
+        self.driver.streams().update(self.sock.id(), f)
+    }
+
+    fn wait<F, R>(&self, deadline: Option<Instant>, f: F) -> io::Result<R>
+    where
+        F: FnMut(&mut StreamState) -> Option<R>,
+    {
+        self.driver.streams().wait(self.sock.id(), deadline, f)
+    }
+
+    /// Same as `wait()`, except in nonblocking mode `f` is only called once.
+    fn poll<F, R>(&self, deadline: Option<Instant>, mut f: F) -> io::Result<R>
+    where
+        F: FnMut(&mut StreamState) -> Option<R>,
+    {
+        if self.nonblocking.load(Ordering::SeqCst) {
+            self.update(|stream| f(stream))
+                .ok_or_else(|| io::ErrorKind::WouldBlock.into())
+        } else {
+            self.wait(deadline, f)
+        }
+    }
+}
+
+/// Wraps the socket driven by the given driver.
+pub fn from_socket(sock: SharedUtpSocket<()>, driver: Arc<Driver>) -> UtpStream {
+    UtpStream {
+        sock,
+        driver,
+        read_timeout: Mutex::new(None),
+        write_timeout: Mutex::new(None),
+        linger: Mutex::new(None),
+        nonblocking: AtomicBool::new(false),
+    }
+}
+
+/// Starts opening uTP connection using the given driver. The returned stream is nonblocking and
+/// becomes writable once the connection is established.
+#[cfg(feature = "mio")]
+pub fn connect_nonblocking(driver: Arc<Driver>, addr: SocketAddr) -> io::Result<UtpStream> {
+    let sock = driver.utp().connect(addr)?;
+    let stream = from_socket(sock, driver);
+    stream.set_nonblocking(true)?;
+    Ok(stream)
+}
+
+/// Opens uTP connection using the given driver and waits until it's established or the timeout
+/// elapses.
+pub fn connect_with(
+    driver: Arc<Driver>,
+    addr: SocketAddr,
+    timeout: Option<Duration>,
+) -> io::Result<UtpStream> {
+    let deadline = deadline(check_timeout(timeout)?);
+    let sock = driver.utp().connect(addr)?;
+    let stream = from_socket(sock, driver);
+    let res = stream.wait(deadline, |stream| match stream.error {
+        Some(error) => Some(Err(io::Error::from(error))),
+        None if stream.connected => Some(Ok(())),
+        None => None,
+    });
+    match res {
+        Ok(res) => res?,
+        // dropping the stream closes the socket, libutp destroys it on the next timeout check
+        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+            return Err(UtpError::ConnectTimedOut.into())
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(stream)
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.driver.streams().remove(self.sock.id());
+    }
+}
+
+impl Read for UtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_data(buf)
+    }
+}
+
+impl<'a> Read for &'a UtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_data(buf)
+    }
+}
+
+impl Write for UtpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_data(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Write for &'a UtpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_data(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns an error, if the stream can't be written to anymore.
+pub fn stream_error(stream: &StreamState) -> io::Result<()> {
+    match stream.error {
+        Some(error) => Err(error.into()),
+        None if stream.write_closed => Err(io::ErrorKind::BrokenPipe.into()),
+        None => Ok(()),
+    }
+}
+
+/// Returns the address to bind UDP socket to, so that it could reach the given address.
+pub fn any_addr_for(addr: &SocketAddr) -> SocketAddr {
+    match *addr {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0).into(), 0),
+    }
+}
+
+/// Rejects zero timeout, just like `std::net::TcpStream` does.
+pub fn check_timeout(timeout: Option<Duration>) -> io::Result<Option<Duration>> {
+    if timeout == Some(Duration::from_secs(0)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        ));
+    }
+    Ok(timeout)
+}
+
+fn deadline(timeout: Option<Duration>) -> Option<Instant> {
+    timeout.map(|timeout| Instant::now() + timeout)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
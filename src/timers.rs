@@ -0,0 +1,192 @@
+//! Figures out when libutp needs `utp_check_timeouts()` to be called.
+//!
+//! libutp doesn't expose its timers, so we follow BEP 29 headers of the packets that go through
+//! the context: a socket that has sent SYN, DATA or FIN packets that are not acknowledged yet
+//! needs retransmit timers, an idle connection only needs keep-alive packets.
+
+use packet::{Packet, PacketType};
+use socket::UtpSocketId;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// libutp ignores `utp_check_timeouts()` calls made more often than this.
+const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+/// libutp sends keep-alive packets over connections that were idle this long.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(29_000);
+
+/// What we know about a single libutp socket.
+#[derive(Default)]
+struct SocketTimers {
+    /// Remote peer address and the connection id it puts into packets sent to this socket.
+    recv_key: Option<(SocketAddr, u16)>,
+    /// Sequence number of the last packet that must be acknowledged.
+    last_reliable_seq: Option<u16>,
+    /// The last sequence number remote peer has acknowledged.
+    acked_seq: Option<u16>,
+    last_sent: Option<Instant>,
+    /// Remote peer can't receive more data, libutp will probe its window.
+    peer_window_closed: bool,
+    /// Socket is closed and waits to be destroyed by libutp.
+    closing: bool,
+    /// Connection failed, libutp won't retransmit anything.
+    failed: bool,
+}
+
+impl SocketTimers {
+    fn has_unacked_packets(&self) -> bool {
+        match (self.last_reliable_seq, self.acked_seq) {
+            (Some(_), None) => true,
+            (Some(seq), Some(acked)) => seq.wrapping_sub(acked) as i16 > 0,
+            (None, _) => false,
+        }
+    }
+
+    /// Returns when libutp needs to check timeouts for this socket. libutp ignores checks made
+    /// before `next_check`, hence there's no point to report earlier deadlines.
+    fn deadline(&self, next_check: Instant) -> Option<Instant> {
+        // closed sockets are destroyed by `utp_check_timeouts()`
+        if self.closing {
+            return Some(next_check);
+        }
+        if self.failed {
+            return None;
+        }
+        if self.peer_window_closed || self.has_unacked_packets() {
+            return Some(next_check);
+        }
+        self.last_sent
+            .map(|last_sent| cmp::max(last_sent + KEEPALIVE_INTERVAL, next_check))
+    }
+}
+
+/// Tracks packets sent and received by the context.
+#[derive(Default)]
+pub struct TimeoutTracker {
+    sockets: RefCell<HashMap<UtpSocketId, SocketTimers>>,
+    /// Maps incoming packets to sockets.
+    recv_ids: RefCell<HashMap<(SocketAddr, u16), UtpSocketId>>,
+    last_check: Cell<Option<Instant>>,
+}
+
+impl TimeoutTracker {
+    /// Records the packet libutp sent over the given socket.
+    pub fn on_send(&self, id: UtpSocketId, peer_addr: SocketAddr, packet: &[u8], now: Instant) {
+        let packet = match Packet::parse(packet) {
+            Ok(packet) => packet,
+            Err(_) => return,
+        };
+        let mut sockets = self.sockets.borrow_mut();
+        let sock = sockets.entry(id).or_default();
+        if sock.recv_key.is_none() {
+            // SYN carries the id the initiator receives packets with and the initiator sends
+            // packets with id + 1. The other side receives packets with id + 1 and sends them
+            // with the id of SYN.
+            let recv_id = if packet.packet_type() == PacketType::Syn {
+                packet.connection_id()
+            } else {
+                packet.connection_id().wrapping_add(1)
+            };
+            sock.recv_key = Some((peer_addr, recv_id));
+            let _ = self.recv_ids.borrow_mut().insert((peer_addr, recv_id), id);
+        }
+        sock.last_sent = Some(now);
+        if packet.packet_type().is_reliable() {
+            sock.last_reliable_seq = Some(packet.seq_nr());
+        }
+    }
+
+    /// Records the packet received from remote peer.
+    pub fn on_receive(&self, sender_addr: SocketAddr, packet: &[u8]) {
+        let packet = match Packet::parse(packet) {
+            Ok(packet) => packet,
+            Err(_) => return,
+        };
+        let id = match self
+            .recv_ids
+            .borrow()
+            .get(&(sender_addr, packet.connection_id()))
+        {
+            Some(id) => *id,
+            None => return,
+        };
+        if let Some(sock) = self.sockets.borrow_mut().get_mut(&id) {
+            let newer_ack = match sock.acked_seq {
+                Some(acked) => packet.ack_nr().wrapping_sub(acked) as i16 > 0,
+                None => true,
+            };
+            if newer_ack {
+                sock.acked_seq = Some(packet.ack_nr());
+            }
+            sock.peer_window_closed = packet.wnd_size() == 0;
+        }
+    }
+
+    /// Records that the socket was closed and libutp will destroy it later.
+    pub fn on_close(&self, id: UtpSocketId) {
+        self.sockets.borrow_mut().entry(id).or_default().closing = true;
+    }
+
+    /// Records that the connection failed.
+    pub fn on_error(&self, id: UtpSocketId) {
+        if let Some(sock) = self.sockets.borrow_mut().get_mut(&id) {
+            sock.failed = true;
+        }
+    }
+
+    /// Forgets the socket destroyed by libutp.
+    pub fn on_destroy(&self, id: UtpSocketId) {
+        let recv_key = self
+            .sockets
+            .borrow_mut()
+            .remove(&id)
+            .and_then(|sock| sock.recv_key);
+        if let Some(recv_key) = recv_key {
+            let _ = self.recv_ids.borrow_mut().remove(&recv_key);
+        }
+    }
+
+    /// Returns `true`, if all reliable packets sent over the socket were acknowledged and the
+    /// connection didn't fail. Only relies on the packets seen, not on the timer estimates.
+    pub fn is_delivered(&self, id: UtpSocketId) -> bool {
+        self.sockets
+            .borrow()
+            .get(&id)
+            .map_or(false, |sock| !sock.failed && !sock.has_unacked_packets())
+    }
+
+    /// Records `utp_check_timeouts()` call. libutp returns right away from calls made within
+    /// `TIMEOUT_CHECK_INTERVAL` of the last check it did, so those don't postpone the next one.
+    pub fn on_check(&self, now: Instant) {
+        let handled = match self.last_check.get() {
+            Some(last_check) => now >= last_check + TIMEOUT_CHECK_INTERVAL,
+            None => true,
+        };
+        if handled {
+            self.last_check.set(Some(now));
+        }
+    }
+
+    /// Returns how long to wait until `utp_check_timeouts()` should be called next time.
+    /// `None` means no timers are pending. This is our best guess of libutp timers, not what
+    /// libutp reports.
+    pub fn next_timeout(&self, now: Instant) -> Option<Duration> {
+        let next_check = match self.last_check.get() {
+            Some(last_check) => last_check + TIMEOUT_CHECK_INTERVAL,
+            None => now,
+        };
+        let deadline = self
+            .sockets
+            .borrow()
+            .values()
+            .filter_map(|sock| sock.deadline(next_check))
+            .min()?;
+        if deadline > now {
+            Some(deadline - now)
+        } else {
+            Some(Duration::from_secs(0))
+        }
+    }
+}
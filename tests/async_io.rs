@@ -0,0 +1,91 @@
+#![cfg(feature = "tokio")]
+
+extern crate tokio;
+extern crate utp;
+#[macro_use]
+extern crate unwrap;
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::{Builder, Runtime};
+use utp::{AsyncUtpListener, AsyncUtpStream};
+
+fn runtime() -> Runtime {
+    unwrap!(Builder::new_current_thread().enable_all().build())
+}
+
+fn localhost() -> SocketAddr {
+    unwrap!("127.0.0.1:0".parse())
+}
+
+/// Connects to the listener and returns both ends of the connection.
+fn connect(rt: &Runtime, listener: &AsyncUtpListener) -> (AsyncUtpStream, AsyncUtpStream) {
+    let client = unwrap!(rt.block_on(AsyncUtpStream::connect(unwrap!(listener.local_addr()))));
+    let (server, client_addr) = unwrap!(rt.block_on(listener.accept()));
+    assert_eq!(client_addr.port(), unwrap!(client.local_addr()).port());
+    (client, server)
+}
+
+#[test]
+fn streams_exchange_data() {
+    let rt = runtime();
+    let _guard = rt.enter();
+    let listener = unwrap!(AsyncUtpListener::bind(localhost()));
+    let (mut client, mut server) = connect(&rt, &listener);
+
+    let out_data: Vec<u8> = (0..256 * 1024).map(|i| i as u8).collect();
+    unwrap!(rt.block_on(client.write_all(&out_data)));
+    let mut in_data = vec![0; out_data.len()];
+    let _ = unwrap!(rt.block_on(server.read_exact(&mut in_data)));
+
+    assert_eq!(in_data, out_data);
+}
+
+#[test]
+fn read_returns_eof_after_peer_closes_connection() {
+    let rt = runtime();
+    let _guard = rt.enter();
+    let listener = unwrap!(AsyncUtpListener::bind(localhost()));
+    let (mut client, mut server) = connect(&rt, &listener);
+
+    unwrap!(rt.block_on(client.write_all(b"bye")));
+    drop(client);
+    let mut in_data = Vec::new();
+    let _ = unwrap!(rt.block_on(server.read_to_end(&mut in_data)));
+
+    assert_eq!(in_data, b"bye".to_vec());
+}
+
+#[test]
+fn accepts_multiple_connections() {
+    let rt = runtime();
+    let _guard = rt.enter();
+    let listener = unwrap!(AsyncUtpListener::bind(localhost()));
+
+    for i in 0..3u8 {
+        let (mut client, mut server) = connect(&rt, &listener);
+        unwrap!(rt.block_on(server.write_all(&[i])));
+        let mut buf = [0];
+        let _ = unwrap!(rt.block_on(client.read_exact(&mut buf)));
+        assert_eq!(buf, [i]);
+    }
+}
+
+#[test]
+fn connect_times_out_when_peer_does_not_respond() {
+    let rt = runtime();
+    let _guard = rt.enter();
+    // never replies to connection requests
+    let silent_peer = unwrap!(UdpSocket::bind(localhost()));
+    let start = Instant::now();
+
+    let res = rt.block_on(AsyncUtpStream::connect_timeout(
+        unwrap!(silent_peer.local_addr()),
+        Duration::from_millis(200),
+    ));
+
+    assert_eq!(unwrap!(res.err()).kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
@@ -0,0 +1,169 @@
+//! Deferred command tests. Contexts exchange packets directly, without real UDP sockets.
+
+extern crate utp;
+#[macro_use]
+extern crate net_literals;
+#[macro_use]
+extern crate unwrap;
+extern crate rand;
+
+mod common;
+
+use common::{random_vec, run_network, Peer};
+use std::cell::{Cell, RefCell};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use utp::{UtpCallbackType, UtpError, UtpEvent, UtpState};
+
+/// Server that echoes received data back.
+fn echo_server(addr: SocketAddr) -> Peer {
+    let mut peer = Peer::new(addr);
+    peer.utp.set_callback(
+        UtpCallbackType::OnRead,
+        Box::new(|mut args| {
+            let id = unwrap!(args.socket_id());
+            args.defer_write(id, args.buf());
+            args.ack_data();
+            0
+        }),
+    );
+    peer
+}
+
+#[test]
+fn echo_server_replies_from_read_callback() {
+    let mut server = echo_server(addr!("10.0.0.1:1000"));
+    let mut client = Peer::new(addr!("10.0.0.2:2000"));
+    let client_sock = unwrap!(client.utp.connect(server.addr));
+    run_network(&mut client, &mut server);
+
+    // big enough for libutp not to accept all the echoed data at once
+    let out_data = random_vec(512 * 1024);
+    let mut bytes_sent = 0;
+    while bytes_sent < out_data.len() {
+        match client_sock.send(&out_data[bytes_sent..]) {
+            Ok(count) => bytes_sent += count,
+            Err(UtpError::WouldBlock) => {}
+            Err(e) => panic!("Failed to send data: {}", e),
+        }
+        run_network(&mut client, &mut server);
+    }
+
+    assert_eq!(client.received_data(), out_data);
+}
+
+#[test]
+fn deferred_close_is_executed_after_deferred_write() {
+    let mut server = Peer::new(addr!("10.0.0.1:1000"));
+    server.utp.set_callback(
+        UtpCallbackType::OnRead,
+        Box::new(|mut args| {
+            let id = unwrap!(args.socket_id());
+            args.defer_write(id, b"bye");
+            args.defer_close(id);
+            args.ack_data();
+            0
+        }),
+    );
+    let mut client = Peer::new(addr!("10.0.0.2:2000"));
+    let client_sock = unwrap!(client.utp.connect(server.addr));
+    run_network(&mut client, &mut server);
+
+    let _ = unwrap!(client_sock.send(b"hello"));
+    run_network(&mut client, &mut server);
+
+    assert_eq!(client.received_data(), b"bye".to_vec());
+    let eof = client.events.iter().any(|ev| match *ev {
+        UtpEvent::Eof(id) => id == client_sock.id(),
+        _ => false,
+    });
+    assert!(eof);
+}
+
+#[test]
+fn connect_can_be_deferred() {
+    let mut server = Peer::new(addr!("10.0.0.1:1000"));
+    let accepted = Rc::new(Cell::new(0));
+    let accepted2 = Rc::clone(&accepted);
+    server.utp.set_callback(
+        UtpCallbackType::OnAccept,
+        Box::new(move |_| {
+            accepted2.set(accepted2.get() + 1);
+            0
+        }),
+    );
+
+    let mut client = Peer::new(addr!("10.0.0.2:2000"));
+    let deferred_id = Rc::new(Cell::new(None));
+    let deferred_id2 = Rc::clone(&deferred_id);
+    let connected = Rc::new(RefCell::new(Vec::new()));
+    let connected2 = Rc::clone(&connected);
+    let server_addr = server.addr;
+    client.utp.set_callback(
+        UtpCallbackType::OnStateChange,
+        Box::new(move |args| {
+            if unwrap!(args.state()) != UtpState::Connected {
+                return 0;
+            }
+            connected2.borrow_mut().push(unwrap!(args.socket_id()));
+            if deferred_id2.get().is_none() {
+                deferred_id2.set(Some(args.defer_connect(server_addr)));
+            }
+            0
+        }),
+    );
+    let client_sock = unwrap!(client.utp.connect(server.addr));
+    run_network(&mut client, &mut server);
+
+    assert_eq!(accepted.get(), 2);
+    let deferred_id = unwrap!(deferred_id.get());
+    assert_ne!(deferred_id, client_sock.id());
+    assert!(connected.borrow().contains(&deferred_id));
+}
+
+#[test]
+fn socket_closed_by_deferred_command_is_not_usable() {
+    let mut server = Peer::new(addr!("10.0.0.1:1000"));
+    let mut client = Peer::new(addr!("10.0.0.2:2000"));
+    client.utp.set_callback(
+        UtpCallbackType::OnStateChange,
+        Box::new(|args| {
+            if unwrap!(args.state()) == UtpState::Connected {
+                args.defer_close(unwrap!(args.socket_id()));
+            }
+            0
+        }),
+    );
+    let client_sock = unwrap!(client.utp.connect(server.addr));
+    run_network(&mut client, &mut server);
+
+    assert_eq!(client_sock.send(b"hello"), Err(UtpError::SocketClosed));
+}
+
+#[test]
+fn commands_for_closed_sockets_are_ignored() {
+    let mut server = Peer::new(addr!("10.0.0.1:1000"));
+    let reads = Rc::new(Cell::new(0));
+    let reads2 = Rc::clone(&reads);
+    server.utp.set_callback(
+        UtpCallbackType::OnRead,
+        Box::new(move |mut args| {
+            let id = unwrap!(args.socket_id());
+            args.defer_close(id);
+            // the socket is closed by the time these are executed
+            args.defer_write(id, args.buf());
+            args.defer_close(id);
+            args.ack_data();
+            reads2.set(reads2.get() + 1);
+            0
+        }),
+    );
+    let mut client = Peer::new(addr!("10.0.0.2:2000"));
+    let client_sock = unwrap!(client.utp.connect(server.addr));
+    run_network(&mut client, &mut server);
+    let _ = unwrap!(client_sock.send(b"hello"));
+    run_network(&mut client, &mut server);
+
+    assert_eq!(reads.get(), 1);
+    assert!(client.received_data().is_empty());
+}
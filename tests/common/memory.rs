@@ -0,0 +1,73 @@
+//! In-memory UDP network for the async stream tests.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use utp::AsyncUdpSocket;
+
+/// Datagrams waiting to be received by a single socket.
+#[derive(Default)]
+struct Inbox {
+    packets: VecDeque<(Vec<u8>, SocketAddr)>,
+    waker: Option<Waker>,
+}
+
+/// Delivers datagrams between `MemorySocket`s instantly and in order.
+#[derive(Default)]
+pub struct Network {
+    inboxes: Mutex<HashMap<SocketAddr, Inbox>>,
+}
+
+impl Network {
+    pub fn bind(self: &Arc<Self>, addr: SocketAddr) -> MemorySocket {
+        let _ = unwrap!(self.inboxes.lock()).insert(addr, Inbox::default());
+        MemorySocket {
+            addr,
+            network: Arc::clone(self),
+        }
+    }
+}
+
+pub struct MemorySocket {
+    addr: SocketAddr,
+    network: Arc<Network>,
+}
+
+impl AsyncUdpSocket for MemorySocket {
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        let mut inboxes = unwrap!(self.network.inboxes.lock());
+        let inbox = unwrap!(inboxes.get_mut(&self.addr));
+        match inbox.packets.pop_front() {
+            Some((packet, sender_addr)) => {
+                buf[..packet.len()].copy_from_slice(&packet);
+                Poll::Ready(Ok((packet.len(), sender_addr)))
+            }
+            None => {
+                inbox.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    fn try_send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let mut inboxes = unwrap!(self.network.inboxes.lock());
+        // just like UDP, datagrams to nowhere are lost
+        if let Some(inbox) = inboxes.get_mut(&addr) {
+            inbox.packets.push_back((buf.to_vec(), self.addr));
+            if let Some(waker) = inbox.waker.take() {
+                waker.wake();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
@@ -0,0 +1,114 @@
+//! Fixtures shared by the integration tests. Every test crate uses only some of them.
+
+#![allow(dead_code)]
+
+#[cfg(feature = "futures-io")]
+pub mod memory;
+
+use rand::RngCore;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use utp::{UtpContext, UtpEvent, UtpSocket};
+
+/// Pcap stream that can be inspected while the context writes to it.
+#[derive(Clone, Default)]
+pub struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuf {
+    pub fn contents(&self) -> Vec<u8> {
+        unwrap!(self.0.lock()).clone()
+    }
+}
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        unwrap!(self.0.lock()).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn random_vec(size: usize) -> Vec<u8> {
+    let mut vec = vec![0; size];
+    rand::thread_rng().fill_bytes(&mut vec[..]);
+    vec
+}
+
+/// uTP context with event queue enabled and a made up network address.
+pub struct Peer {
+    pub utp: UtpContext<()>,
+    pub addr: SocketAddr,
+    /// All events except `Transmit`.
+    pub events: Vec<UtpEvent>,
+}
+
+impl Peer {
+    pub fn new(addr: SocketAddr) -> Self {
+        let mut utp = UtpContext::new(());
+        utp.enable_events();
+        Self {
+            utp,
+            addr,
+            events: Vec::new(),
+        }
+    }
+
+    /// Takes the first accepted socket out of collected events.
+    pub fn take_accepted(&mut self) -> Option<(UtpSocket, SocketAddr)> {
+        let pos = self
+            .events
+            .iter()
+            .position(|ev| matches!(*ev, UtpEvent::Accepted(..)))?;
+        match self.events.remove(pos) {
+            UtpEvent::Accepted(sock, addr) => Some((sock, addr)),
+            _ => None,
+        }
+    }
+
+    /// Returns the data received over all sockets so far.
+    pub fn received_data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for event in &self.events {
+            if let UtpEvent::Data(_, ref bytes) = *event {
+                data.extend_from_slice(bytes);
+            }
+        }
+        data
+    }
+}
+
+/// Passes packets between peers until there's nothing left to deliver.
+pub fn run_network(peer1: &mut Peer, peer2: &mut Peer) {
+    while deliver_packets(peer1, peer2) + deliver_packets(peer2, peer1) > 0 {}
+}
+
+/// Returns the number of packets delivered.
+pub fn deliver_packets(from: &mut Peer, to: &mut Peer) -> usize {
+    let mut delivered = 0;
+    for event in from.utp.poll_events() {
+        match event {
+            UtpEvent::Transmit(packet, addr) => {
+                assert_eq!(addr, to.addr);
+                unwrap!(to.utp.process_udp(&packet, from.addr));
+                delivered += 1;
+            }
+            event => from.events.push(event),
+        }
+    }
+    if delivered > 0 {
+        to.utp.ack_packets();
+    }
+    delivered
+}
+
+/// Connects the client to the server. Returns client and server sockets.
+pub fn connect(client: &mut Peer, server: &mut Peer) -> (UtpSocket, UtpSocket) {
+    let client_sock = unwrap!(client.utp.connect(server.addr));
+    run_network(client, server);
+    let (server_sock, client_addr) = unwrap!(server.take_accepted());
+    assert_eq!(client_addr, client.addr);
+    (client_sock, server_sock)
+}
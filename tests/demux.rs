@@ -0,0 +1,122 @@
+extern crate utp;
+#[macro_use]
+extern crate unwrap;
+
+use std::io::{Read, Write};
+use std::net::UdpSocket;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use utp::{is_utp_packet, PacketFilter, PacketRoute, UdpDemux, UtpEndpoint};
+
+const STUN_COOKIE: [u8; 4] = [0x21, 0x12, 0xa4, 0x42];
+
+/// Returns a 20 byte packet starting with the given byte.
+fn packet_starting_with(first: u8) -> Vec<u8> {
+    let mut packet = vec![0; 20];
+    packet[0] = first;
+    packet
+}
+
+/// STUN binding request which happens to pass uTP header sniff.
+fn stun_request() -> Vec<u8> {
+    let mut packet = packet_starting_with(0x01);
+    packet[4..8].copy_from_slice(&STUN_COOKIE);
+    packet
+}
+
+#[test]
+fn utp_header_sniff_accepts_known_packet_types_of_version_1() {
+    for packet_type in 0..5 {
+        assert!(is_utp_packet(&packet_starting_with(packet_type << 4 | 1)));
+    }
+    assert!(!is_utp_packet(&packet_starting_with(5 << 4 | 1)));
+    assert!(!is_utp_packet(&packet_starting_with(2 << 4)));
+    assert!(!is_utp_packet(&packet_starting_with(b'd')));
+    assert!(!is_utp_packet(&[0x41; 19]));
+    assert!(!is_utp_packet(&[]));
+}
+
+#[test]
+fn packets_are_routed_to_first_matching_handler_before_utp() {
+    let socket = unwrap!(UdpSocket::bind("127.0.0.1:0"));
+    let addr = unwrap!(socket.local_addr());
+    let (tx, rx) = mpsc::channel();
+    let mut demux = UdpDemux::new();
+    let tx2 = tx.clone();
+    demux.add_handler(
+        PacketFilter::MagicCookie {
+            offset: 4,
+            cookie: STUN_COOKIE.to_vec(),
+        },
+        Box::new(move |_: &[u8], _, _: &UdpSocket| unwrap!(tx2.send("stun"))),
+    );
+    let tx2 = tx.clone();
+    demux.add_handler(
+        PacketFilter::FirstByte(b'd'),
+        Box::new(move |_: &[u8], _, _: &UdpSocket| unwrap!(tx2.send("dht"))),
+    );
+    demux.add_handler(
+        PacketFilter::Predicate(Box::new(|packet| packet.len() == 1)),
+        Box::new(move |_: &[u8], _, _: &UdpSocket| unwrap!(tx.send("tiny"))),
+    );
+
+    let route = demux.dispatch(&stun_request(), addr, &socket);
+    assert_eq!(route, PacketRoute::Handler);
+    assert_eq!(unwrap!(rx.try_recv()), "stun");
+    let route = demux.dispatch(b"d1:q4:pinge", addr, &socket);
+    assert_eq!(route, PacketRoute::Handler);
+    assert_eq!(unwrap!(rx.try_recv()), "dht");
+    let route = demux.dispatch(b"d", addr, &socket);
+    assert_eq!(route, PacketRoute::Handler);
+    assert_eq!(unwrap!(rx.try_recv()), "dht");
+    let route = demux.dispatch(b"x", addr, &socket);
+    assert_eq!(route, PacketRoute::Handler);
+    assert_eq!(unwrap!(rx.try_recv()), "tiny");
+
+    assert_eq!(
+        demux.dispatch(&packet_starting_with(0x41), addr, &socket),
+        PacketRoute::Utp
+    );
+    assert_eq!(demux.dispatch(b"junk", addr, &socket), PacketRoute::Unknown);
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn endpoint_routes_other_protocols_to_handlers_and_replies_over_same_port() {
+    let server = unwrap!(UtpEndpoint::bind("127.0.0.1:0"));
+    let server_addr = unwrap!(server.local_addr());
+    server.add_packet_handler(PacketFilter::FirstByte(b'd'), |packet, addr, socket| {
+        assert_eq!(packet, b"d1:q4:pinge");
+        let _ = unwrap!(socket.send_to(b"d1:r4:ponge", addr));
+    });
+
+    let dht_node = unwrap!(UdpSocket::bind("127.0.0.1:0"));
+    unwrap!(dht_node.set_read_timeout(Some(Duration::from_secs(10))));
+    let _ = unwrap!(dht_node.send_to(b"d1:q4:pinge", server_addr));
+    let mut buf = [0; 64];
+    let (len, addr) = unwrap!(dht_node.recv_from(&mut buf));
+    assert_eq!(&buf[..len], b"d1:r4:ponge");
+    assert_eq!(addr, server_addr);
+
+    // the endpoint can start conversations of its own
+    let _ = unwrap!(server.send_to(b"d1:q4:finde", unwrap!(dht_node.local_addr())));
+    let (len, _) = unwrap!(dht_node.recv_from(&mut buf));
+    assert_eq!(&buf[..len], b"d1:q4:finde");
+
+    // uTP keeps working over the same port, junk doesn't disturb it
+    let _ = unwrap!(dht_node.send_to(b"junk", server_addr));
+    let server_thread = thread::spawn(move || {
+        let (mut stream, _) = unwrap!(server.accept());
+        let mut buf = [0; 5];
+        unwrap!(stream.read_exact(&mut buf));
+        unwrap!(stream.write_all(&buf));
+    });
+    let client = unwrap!(UtpEndpoint::bind("127.0.0.1:0"));
+    let mut stream = unwrap!(client.connect(server_addr));
+    unwrap!(stream.write_all(b"hello"));
+    let mut reply = [0; 5];
+    unwrap!(stream.read_exact(&mut reply));
+    assert_eq!(&reply, b"hello");
+    unwrap!(server_thread.join());
+}
@@ -0,0 +1,214 @@
+extern crate utp;
+#[macro_use]
+extern crate unwrap;
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, UdpSocket};
+use std::thread;
+use std::time::Duration;
+use utp::{UtpEndpoint, UtpStream};
+
+fn send_and_receive(stream: &mut UtpStream, msg: &[u8]) -> Vec<u8> {
+    unwrap!(stream.write_all(msg));
+    let mut reply = vec![0; msg.len()];
+    unwrap!(stream.read_exact(&mut reply));
+    reply
+}
+
+fn echo(stream: &mut UtpStream, byte_count: usize) {
+    let mut buf = vec![0; byte_count];
+    unwrap!(stream.read_exact(&mut buf));
+    unwrap!(stream.write_all(&buf));
+}
+
+/// Returns both ends of a new connection between two endpoints, client first.
+fn connect() -> (UtpStream, UtpStream) {
+    let server = unwrap!(UtpEndpoint::bind("127.0.0.1:0"));
+    let client = unwrap!(UtpEndpoint::bind("127.0.0.1:0"));
+    let client_stream = unwrap!(client.connect(unwrap!(server.local_addr())));
+    let (server_stream, _) = unwrap!(server.accept());
+    (client_stream, server_stream)
+}
+
+#[test]
+fn endpoints_connect_to_each_other_over_single_socket() {
+    let endpoint1 = unwrap!(UtpEndpoint::bind("127.0.0.1:0"));
+    let endpoint2 = unwrap!(UtpEndpoint::bind("127.0.0.1:0"));
+    let addr1 = unwrap!(endpoint1.local_addr());
+    let addr2 = unwrap!(endpoint2.local_addr());
+
+    let (reply1, reply2) = {
+        let accept1 = thread::spawn(move || {
+            let (mut stream, peer_addr) = unwrap!(endpoint1.accept());
+            echo(&mut stream, 5);
+            let mut stream = unwrap!(endpoint1.connect(peer_addr));
+            send_and_receive(&mut stream, b"hello")
+        });
+        let mut stream = unwrap!(endpoint2.connect(addr1));
+        let reply2 = send_and_receive(&mut stream, b"world");
+        let (mut stream, peer_addr) = unwrap!(endpoint2.accept());
+        assert_eq!(peer_addr, addr1);
+        echo(&mut stream, 5);
+        (unwrap!(accept1.join()), reply2)
+    };
+
+    assert_eq!(reply1, b"hello".to_vec());
+    assert_eq!(reply2, b"world".to_vec());
+    assert_ne!(addr1, addr2);
+}
+
+#[test]
+fn multiple_connections_share_endpoint() {
+    let server = unwrap!(UtpEndpoint::bind("127.0.0.1:0"));
+    let server_addr = unwrap!(server.local_addr());
+    let server_thread = thread::spawn(move || {
+        let mut streams: Vec<_> = server.incoming().take(3).map(|s| unwrap!(s)).collect();
+        for stream in &mut streams {
+            echo(stream, 4);
+        }
+    });
+
+    let client = unwrap!(UtpEndpoint::bind("127.0.0.1:0"));
+    let mut streams: Vec<_> = (0..3)
+        .map(|_| unwrap!(client.connect(server_addr)))
+        .collect();
+    for (i, stream) in streams.iter_mut().enumerate() {
+        assert_eq!(send_and_receive(stream, &[i as u8; 4]), vec![i as u8; 4]);
+    }
+    for stream in &streams {
+        assert_eq!(unwrap!(stream.local_addr()), unwrap!(client.local_addr()));
+    }
+
+    unwrap!(server_thread.join());
+}
+
+#[test]
+fn streams_keep_working_after_endpoint_is_dropped() {
+    let server = unwrap!(UtpEndpoint::bind("127.0.0.1:0"));
+    let server_addr = unwrap!(server.local_addr());
+    let server_thread = thread::spawn(move || {
+        let (mut stream, _) = unwrap!(server.accept());
+        drop(server);
+        echo(&mut stream, 5);
+    });
+
+    let client = unwrap!(UtpEndpoint::bind("127.0.0.1:0"));
+    let mut stream = unwrap!(client.connect(server_addr));
+    drop(client);
+
+    assert_eq!(send_and_receive(&mut stream, b"hello"), b"hello".to_vec());
+    unwrap!(server_thread.join());
+}
+
+#[test]
+fn endpoint_keeps_working_after_connect_times_out() {
+    let silent_peer = unwrap!(UdpSocket::bind("127.0.0.1:0"));
+    let server = unwrap!(UtpEndpoint::bind("127.0.0.1:0"));
+    let server_addr = unwrap!(server.local_addr());
+    let server_thread = thread::spawn(move || {
+        let (mut stream, _) = unwrap!(server.accept());
+        echo(&mut stream, 5);
+    });
+
+    let client = unwrap!(UtpEndpoint::bind("127.0.0.1:0"));
+    let res = client.connect_timeout(
+        unwrap!(silent_peer.local_addr()),
+        Duration::from_millis(200),
+    );
+    assert_eq!(unwrap!(res.err()).kind(), io::ErrorKind::TimedOut);
+
+    let mut stream = unwrap!(client.connect_timeout(server_addr, Duration::from_secs(10)));
+    assert_eq!(send_and_receive(&mut stream, b"hello"), b"hello".to_vec());
+    unwrap!(server_thread.join());
+}
+
+#[test]
+fn peer_reads_eof_after_all_data_once_write_half_is_shut_down() {
+    let (mut client, mut server) = connect();
+    let out_data: Vec<u8> = (0..256 * 1024).map(|i| i as u8).collect();
+
+    unwrap!(client.write_all(&out_data));
+    unwrap!(client.shutdown(Shutdown::Write));
+    let mut in_data = Vec::new();
+    let _ = unwrap!(server.read_to_end(&mut in_data));
+    assert_eq!(in_data, out_data);
+
+    // the other direction is still open
+    unwrap!(server.write_all(b"hello"));
+    let mut buf = [0; 5];
+    unwrap!(client.read_exact(&mut buf));
+    assert_eq!(&buf, b"hello");
+    let res = client.write(b"more");
+    assert_eq!(unwrap!(res.err()).kind(), io::ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn half_closed_stream_keeps_receiving_until_peer_shuts_down_too() {
+    let (mut client, mut server) = connect();
+
+    unwrap!(server.shutdown(Shutdown::Write));
+    let mut buf = [0; 16];
+    assert_eq!(unwrap!(client.read(&mut buf)), 0);
+
+    unwrap!(client.write_all(b"hello"));
+    unwrap!(client.shutdown(Shutdown::Write));
+    let mut in_data = Vec::new();
+    let _ = unwrap!(server.read_to_end(&mut in_data));
+    assert_eq!(in_data, b"hello".to_vec());
+}
+
+#[test]
+fn data_received_after_read_half_is_shut_down_is_discarded() {
+    let (mut client, mut server) = connect();
+
+    unwrap!(client.shutdown(Shutdown::Read));
+    unwrap!(client.write_all(b"hello"));
+    let mut buf = [0; 5];
+    unwrap!(server.read_exact(&mut buf));
+    assert_eq!(&buf, b"hello");
+
+    unwrap!(server.write_all(b"ignored"));
+    // returns once the client has received the data
+    unwrap!(server.close());
+    assert_eq!(unwrap!(client.read(&mut buf)), 0);
+}
+
+#[test]
+fn writer_is_throttled_while_peer_does_not_read() {
+    let (mut client, mut server) = connect();
+    unwrap!(client.set_write_timeout(Some(Duration::from_secs(2))));
+
+    // received data is buffered until read, so the server's receive window closes
+    let chunk = [7; 64 * 1024];
+    let mut written = 0;
+    loop {
+        match client.write(&chunk) {
+            Ok(len) => written += len,
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+                break;
+            }
+        }
+        assert!(written < 64 * 1024 * 1024, "the writer must be throttled");
+    }
+
+    let mut buf = vec![0; written];
+    unwrap!(server.read_exact(&mut buf));
+    assert!(buf.iter().all(|&byte| byte == 7));
+}
+
+#[test]
+fn shutting_down_both_halves_delivers_written_data_followed_by_eof() {
+    let (mut client, mut server) = connect();
+
+    unwrap!(client.write_all(b"bye"));
+    unwrap!(client.shutdown(Shutdown::Both));
+    let mut in_data = Vec::new();
+    let _ = unwrap!(server.read_to_end(&mut in_data));
+
+    assert_eq!(in_data, b"bye".to_vec());
+    let mut buf = [0; 16];
+    assert_eq!(unwrap!(client.read(&mut buf)), 0);
+    let res = client.write(b"more");
+    assert_eq!(unwrap!(res.err()).kind(), io::ErrorKind::BrokenPipe);
+}
@@ -0,0 +1,118 @@
+#![cfg(feature = "mio")]
+
+extern crate mio;
+extern crate utp;
+#[macro_use]
+extern crate unwrap;
+
+#[cfg(unix)]
+use mio::unix::UnixReady;
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use std::io::{self, Read, Write};
+use std::time::Duration;
+use utp::{EventedUtpListener, EventedUtpStream};
+
+const LISTENER: Token = Token(0);
+const CLIENT: Token = Token(1);
+const SERVER: Token = Token(2);
+
+/// Readiness streams are registered for.
+#[cfg(unix)]
+fn stream_interest() -> Ready {
+    Ready::readable() | Ready::writable() | UnixReady::hup()
+}
+
+#[cfg(not(unix))]
+fn stream_interest() -> Ready {
+    Ready::readable() | Ready::writable()
+}
+
+/// Returns `true`, if the readiness reports that remote peer closed the connection. Elsewhere than
+/// on Unix the stream only becomes readable.
+#[cfg(unix)]
+fn is_closed(readiness: Ready) -> bool {
+    UnixReady::from(readiness).is_hup()
+}
+
+#[cfg(not(unix))]
+fn is_closed(readiness: Ready) -> bool {
+    readiness.is_readable()
+}
+
+/// Polls until an event for the given token arrives.
+fn wait_for(poll: &Poll, token: Token) -> Ready {
+    let mut events = Events::with_capacity(16);
+    loop {
+        let _ = unwrap!(poll.poll(&mut events, Some(Duration::from_secs(10))));
+        assert!(!events.is_empty(), "Timed out waiting for {:?}", token);
+        if let Some(event) = events.iter().find(|event| event.token() == token) {
+            return event.readiness();
+        }
+    }
+}
+
+fn read_exact_nonblocking(
+    poll: &Poll,
+    token: Token,
+    stream: &mut EventedUtpStream,
+    len: usize,
+) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut buf = [0; 1024];
+    while data.len() < len {
+        match stream.read(&mut buf) {
+            Ok(0) => panic!("Unexpected EOF"),
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let _ = wait_for(poll, token);
+            }
+            Err(e) => panic!("Read failed: {}", e),
+        }
+    }
+    data
+}
+
+#[test]
+fn accept_would_block_without_incoming_connections() {
+    let listener = unwrap!(EventedUtpListener::bind("127.0.0.1:0"));
+    let res = listener.accept();
+    assert_eq!(unwrap!(res.err()).kind(), io::ErrorKind::WouldBlock);
+}
+
+#[test]
+fn streams_exchange_data_driven_by_poll() {
+    let poll = unwrap!(Poll::new());
+    let listener = unwrap!(EventedUtpListener::bind("127.0.0.1:0"));
+    unwrap!(poll.register(&listener, LISTENER, Ready::readable(), PollOpt::edge()));
+
+    let mut client = unwrap!(EventedUtpStream::connect(&unwrap!(listener.local_addr())));
+    let interest = stream_interest();
+    unwrap!(poll.register(&client, CLIENT, interest, PollOpt::edge()));
+    assert!(wait_for(&poll, CLIENT).is_writable());
+    assert_eq!(unwrap!(client.write(b"hello")), 5);
+
+    let _ = wait_for(&poll, LISTENER);
+    let (mut server, client_addr) = unwrap!(listener.accept());
+    assert_eq!(client_addr.port(), unwrap!(client.local_addr()).port());
+    unwrap!(poll.register(&server, SERVER, interest, PollOpt::edge()));
+
+    assert_eq!(
+        read_exact_nonblocking(&poll, SERVER, &mut server, 5),
+        b"hello"
+    );
+    assert_eq!(unwrap!(server.write(b"world")), 5);
+    assert_eq!(
+        read_exact_nonblocking(&poll, CLIENT, &mut client, 5),
+        b"world"
+    );
+
+    drop(server);
+    loop {
+        let readiness = wait_for(&poll, CLIENT);
+        if is_closed(readiness) {
+            break;
+        }
+    }
+    let mut buf = [0; 16];
+    assert_eq!(unwrap!(client.read(&mut buf)), 0);
+}
@@ -0,0 +1,321 @@
+//! Event queue API tests. Contexts exchange packets directly, without real UDP sockets.
+
+extern crate utp;
+#[macro_use]
+extern crate net_literals;
+#[macro_use]
+extern crate unwrap;
+extern crate rand;
+
+mod common;
+
+use common::{connect, random_vec, run_network, Peer};
+use std::io;
+use std::net::Shutdown;
+use utp::{UtpError, UtpEvent, UtpSocket};
+
+#[test]
+fn connect_is_reported_as_accepted_and_connected_events() {
+    let mut client = Peer::new(addr!("10.0.0.1:1000"));
+    let mut server = Peer::new(addr!("10.0.0.2:2000"));
+
+    let (client_sock, _server_sock) = connect(&mut client, &mut server);
+
+    let connected = client.events.iter().any(|ev| match *ev {
+        UtpEvent::Connected(id) => id == client_sock.id(),
+        _ => false,
+    });
+    assert!(connected);
+}
+
+#[test]
+fn received_data_is_reported_as_data_events() {
+    let mut client = Peer::new(addr!("10.0.0.1:1000"));
+    let mut server = Peer::new(addr!("10.0.0.2:2000"));
+    let (client_sock, server_sock) = connect(&mut client, &mut server);
+
+    let out_data = random_vec(256 * 1024);
+    let mut bytes_sent = 0;
+    while bytes_sent < out_data.len() {
+        match client_sock.send(&out_data[bytes_sent..]) {
+            Ok(count) => bytes_sent += count,
+            Err(UtpError::WouldBlock) => {}
+            Err(e) => panic!("Failed to send data: {}", e),
+        }
+        run_network(&mut client, &mut server);
+    }
+
+    let mut in_data = Vec::new();
+    for event in server.events.drain(..) {
+        if let UtpEvent::Data(id, data) = event {
+            assert_eq!(id, server_sock.id());
+            in_data.extend_from_slice(&data);
+        }
+    }
+    assert_eq!(in_data, out_data);
+}
+
+#[test]
+fn closed_connection_is_reported_as_eof_event() {
+    let mut client = Peer::new(addr!("10.0.0.1:1000"));
+    let mut server = Peer::new(addr!("10.0.0.2:2000"));
+    let (client_sock, server_sock) = connect(&mut client, &mut server);
+
+    drop(client_sock);
+    run_network(&mut client, &mut server);
+
+    let eof = server.events.iter().any(|ev| match *ev {
+        UtpEvent::Eof(id) => id == server_sock.id(),
+        _ => false,
+    });
+    assert!(eof);
+}
+
+#[test]
+fn socket_keeps_receiving_after_shutting_down_writes() {
+    let mut client = Peer::new(addr!("10.0.0.1:1000"));
+    let mut server = Peer::new(addr!("10.0.0.2:2000"));
+    let (client_sock, server_sock) = connect(&mut client, &mut server);
+
+    unwrap!(client_sock.shutdown(Shutdown::Write));
+    run_network(&mut client, &mut server);
+    let eof = server.events.iter().any(|ev| match *ev {
+        UtpEvent::Eof(id) => id == server_sock.id(),
+        _ => false,
+    });
+    assert!(eof);
+
+    let _ = unwrap!(server_sock.send(b"hello"));
+    run_network(&mut client, &mut server);
+    assert_eq!(client.received_data(), b"hello".to_vec());
+    assert_eq!(client_sock.send(b"more"), Err(UtpError::SocketClosed));
+}
+
+#[test]
+fn reset_connection_is_reported_as_error_event() {
+    let mut client = Peer::new(addr!("10.0.0.1:1000"));
+    let mut server = Peer::new(addr!("10.0.0.2:2000"));
+    let (client_sock, _server_sock) = connect(&mut client, &mut server);
+
+    // server restarts and doesn't know about the connection anymore
+    let mut server = Peer::new(server.addr);
+    let _ = unwrap!(client_sock.send(b"hello"));
+    run_network(&mut client, &mut server);
+
+    let reset = client.events.iter().any(|ev| match *ev {
+        UtpEvent::Error(id, ref e) => {
+            id == client_sock.id() && e.kind() == io::ErrorKind::ConnectionReset
+        }
+        _ => false,
+    });
+    assert!(reset);
+}
+
+#[test]
+fn events_are_drained_once() {
+    let mut client = Peer::new(addr!("10.0.0.1:1000"));
+    let _client_sock = unwrap!(client.utp.connect(addr!("10.0.0.2:2000")));
+
+    let syn_packets = client.utp.poll_events().count();
+
+    assert_eq!(syn_packets, 1);
+    assert_eq!(client.utp.poll_events().count(), 0);
+}
+
+/// Takes the data the peer received over the given socket out of collected events.
+fn take_data(peer: &mut Peer, sock: &UtpSocket) -> Vec<u8> {
+    let mut in_data = Vec::new();
+    for event in peer.events.drain(..) {
+        if let UtpEvent::Data(id, data) = event {
+            assert_eq!(id, sock.id());
+            in_data.extend_from_slice(&data);
+        }
+    }
+    in_data
+}
+
+#[test]
+fn queued_data_is_written_once_socket_becomes_writable() {
+    let mut client = Peer::new(addr!("10.0.0.1:1000"));
+    let mut server = Peer::new(addr!("10.0.0.2:2000"));
+    let (client_sock, server_sock) = connect(&mut client, &mut server);
+
+    let out_data = random_vec(256 * 1024);
+    assert!(unwrap!(client_sock.write_all(&out_data)));
+    assert!(client_sock.queued_len() > 0);
+    run_network(&mut client, &mut server);
+
+    assert_eq!(client_sock.queued_len(), 0);
+    assert_eq!(take_data(&mut server, &server_sock), out_data);
+}
+
+#[test]
+fn writable_is_reported_once_full_queue_drains_to_low_water_mark() {
+    let mut client = Peer::new(addr!("10.0.0.1:1000"));
+    let mut server = Peer::new(addr!("10.0.0.2:2000"));
+    let (client_sock, server_sock) = connect(&mut client, &mut server);
+    unwrap!(client_sock.set_write_queue_limits(64 * 1024, 16 * 1024));
+    client.events.clear();
+
+    let out_data = random_vec(256 * 1024);
+    assert!(!unwrap!(client_sock.write_all(&out_data)));
+    run_network(&mut client, &mut server);
+
+    let writable = client.events.iter().any(|ev| match *ev {
+        UtpEvent::Writable(id) => id == client_sock.id(),
+        _ => false,
+    });
+    assert!(writable);
+    assert_eq!(take_data(&mut server, &server_sock), out_data);
+    // the queue is below the high-water mark again
+    assert!(unwrap!(client_sock.write_all(b"hello")));
+}
+
+#[test]
+fn send_does_not_overtake_queued_data() {
+    let mut client = Peer::new(addr!("10.0.0.1:1000"));
+    let mut server = Peer::new(addr!("10.0.0.2:2000"));
+    let (client_sock, server_sock) = connect(&mut client, &mut server);
+    client.events.clear();
+
+    let out_data = random_vec(256 * 1024);
+    let _ = unwrap!(client_sock.write_all(&out_data));
+    assert!(client_sock.queued_len() > 0);
+    assert_eq!(client_sock.send(b"hello"), Err(UtpError::WouldBlock));
+    run_network(&mut client, &mut server);
+
+    let writable = client.events.iter().any(|ev| match *ev {
+        UtpEvent::Writable(id) => id == client_sock.id(),
+        _ => false,
+    });
+    assert!(writable);
+    assert_eq!(unwrap!(client_sock.send(b"hello")), 5);
+    run_network(&mut client, &mut server);
+    let mut expected = out_data;
+    expected.extend_from_slice(b"hello");
+    assert_eq!(take_data(&mut server, &server_sock), expected);
+}
+
+#[test]
+fn low_water_mark_above_high_water_mark_is_rejected() {
+    let mut client = Peer::new(addr!("10.0.0.1:1000"));
+    let mut server = Peer::new(addr!("10.0.0.2:2000"));
+    let (client_sock, _server_sock) = connect(&mut client, &mut server);
+
+    let res = client_sock.set_write_queue_limits(1024, 2048);
+
+    assert_eq!(res, Err(UtpError::InvalidWaterMarks));
+}
+
+#[test]
+fn queued_data_is_written_before_socket_is_closed() {
+    let mut client = Peer::new(addr!("10.0.0.1:1000"));
+    let mut server = Peer::new(addr!("10.0.0.2:2000"));
+    let (client_sock, server_sock) = connect(&mut client, &mut server);
+
+    let out_data = random_vec(256 * 1024);
+    let _ = unwrap!(client_sock.write_all(&out_data));
+    client_sock.close();
+    assert_eq!(client_sock.send(b"more"), Err(UtpError::SocketClosed));
+    run_network(&mut client, &mut server);
+
+    let eof = server.events.iter().any(|ev| match *ev {
+        UtpEvent::Eof(id) => id == server_sock.id(),
+        _ => false,
+    });
+    assert!(eof);
+    assert_eq!(take_data(&mut server, &server_sock), out_data);
+}
+
+/// Reads everything the socket has buffered.
+fn read_available(sock: &UtpSocket) -> Vec<u8> {
+    let mut in_data = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        match sock.read(&mut buf) {
+            Ok(0) | Err(UtpError::WouldBlock) => return in_data,
+            Ok(len) => in_data.extend_from_slice(&buf[..len]),
+            Err(e) => panic!("Failed to read data: {}", e),
+        }
+    }
+}
+
+#[test]
+fn buffered_data_is_read_in_requested_lengths() {
+    let mut client = Peer::new(addr!("10.0.0.1:1000"));
+    let mut server = Peer::new(addr!("10.0.0.2:2000"));
+    server.utp.enable_read_buffers();
+    let (client_sock, server_sock) = connect(&mut client, &mut server);
+
+    let out_data = random_vec(64 * 1024);
+    let _ = unwrap!(client_sock.write_all(&out_data));
+    run_network(&mut client, &mut server);
+
+    let readable = server.events.iter().any(|ev| match *ev {
+        UtpEvent::Readable(id) => id == server_sock.id(),
+        _ => false,
+    });
+    assert!(readable);
+    assert!(take_data(&mut server, &server_sock).is_empty());
+    assert_eq!(server_sock.buffered_len(), out_data.len());
+
+    let mut header = [0; 10];
+    assert_eq!(unwrap!(server_sock.read(&mut header)), 10);
+    assert_eq!(&header[..], &out_data[..10]);
+    assert_eq!(read_available(&server_sock), out_data[10..].to_vec());
+    assert_eq!(server_sock.read(&mut header), Err(UtpError::WouldBlock));
+}
+
+#[test]
+fn unread_data_throttles_sender() {
+    let mut client = Peer::new(addr!("10.0.0.1:1000"));
+    let mut server = Peer::new(addr!("10.0.0.2:2000"));
+    server.utp.enable_read_buffers();
+    let (client_sock, server_sock) = connect(&mut client, &mut server);
+
+    // more than libutp's default receive buffer
+    let out_data = random_vec(4 * 1024 * 1024);
+    let _ = unwrap!(client_sock.write_all(&out_data));
+    run_network(&mut client, &mut server);
+    assert!(client_sock.queued_len() > 0);
+
+    let mut in_data = Vec::new();
+    while in_data.len() < out_data.len() {
+        let chunk = read_available(&server_sock);
+        assert!(!chunk.is_empty());
+        in_data.extend_from_slice(&chunk);
+        // window updates might be deferred
+        server.utp.ack_packets();
+        run_network(&mut client, &mut server);
+    }
+    assert_eq!(in_data, out_data);
+}
+
+#[test]
+fn read_returns_zero_once_buffered_data_is_read_after_eof() {
+    let mut client = Peer::new(addr!("10.0.0.1:1000"));
+    let mut server = Peer::new(addr!("10.0.0.2:2000"));
+    server.utp.enable_read_buffers();
+    let (client_sock, server_sock) = connect(&mut client, &mut server);
+
+    let _ = unwrap!(client_sock.write_all(b"bye"));
+    drop(client_sock);
+    run_network(&mut client, &mut server);
+
+    let mut buf = [0; 16];
+    assert_eq!(unwrap!(server_sock.read(&mut buf)), 3);
+    assert_eq!(&buf[..3], b"bye");
+    assert_eq!(unwrap!(server_sock.read(&mut buf)), 0);
+}
+
+#[test]
+fn read_fails_unless_context_buffers_received_data() {
+    let mut client = Peer::new(addr!("10.0.0.1:1000"));
+    let mut server = Peer::new(addr!("10.0.0.2:2000"));
+    let (_client_sock, server_sock) = connect(&mut client, &mut server);
+
+    let mut buf = [0; 16];
+    let res = server_sock.read(&mut buf);
+
+    assert_eq!(res, Err(UtpError::ReadBuffersDisabled));
+}
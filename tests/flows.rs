@@ -0,0 +1,161 @@
+extern crate utp;
+#[macro_use]
+extern crate net_literals;
+#[macro_use]
+extern crate unwrap;
+
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use utp::flows::{CloseReason, FlowAnalyser};
+use utp::packet::PacketType::{self, Data, Fin, Reset, State, Syn};
+use utp::packet::{Extension, PacketHeader};
+use utp::pcap::UdpRecord;
+
+/// Captured datagrams of a made up conversation.
+struct Capture {
+    start: SystemTime,
+    records: Vec<UdpRecord>,
+}
+
+impl Capture {
+    fn new() -> Self {
+        Self {
+            start: UNIX_EPOCH + Duration::from_secs(1_000_000),
+            records: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, at_ms: u64, src: SocketAddr, dst: SocketAddr, payload: Vec<u8>) {
+        self.records.push(UdpRecord {
+            timestamp: self.start + Duration::from_millis(at_ms),
+            src,
+            dst,
+            payload,
+        });
+    }
+
+    fn analyse(&self) -> FlowAnalyser {
+        let mut analyser = FlowAnalyser::new();
+        for record in &self.records {
+            let _ = analyser.add(record);
+        }
+        analyser
+    }
+}
+
+/// Header with the given sequence number, ack number and window.
+fn header(packet_type: PacketType, connection_id: u16, numbers: (u16, u16, u32)) -> PacketHeader {
+    PacketHeader {
+        packet_type,
+        connection_id,
+        timestamp_micros: 0,
+        timestamp_difference_micros: 0,
+        wnd_size: numbers.2,
+        seq_nr: numbers.0,
+        ack_nr: numbers.1,
+    }
+}
+
+fn packet(
+    packet_type: PacketType,
+    connection_id: u16,
+    numbers: (u16, u16, u32),
+    payload: &[u8],
+) -> Vec<u8> {
+    unwrap!(header(packet_type, connection_id, numbers).encode(&[], payload))
+}
+
+#[test]
+fn connection_is_summarized_from_syn_to_fin() {
+    let client = addr!("10.0.0.1:1000");
+    let server = addr!("10.0.0.2:2000");
+    let mut capture = Capture::new();
+    capture.add(0, client, server, packet(Syn, 7, (1, 0, 1000), b""));
+    capture.add(30, client, server, packet(Syn, 7, (1, 0, 1000), b""));
+    capture.add(50, server, client, packet(State, 7, (100, 1, 800), b""));
+    capture.add(60, client, server, packet(Data, 8, (2, 99, 1000), b"abc"));
+    capture.add(61, client, server, packet(Data, 8, (3, 99, 1000), b"defg"));
+    capture.add(62, client, server, packet(Data, 8, (4, 99, 1000), b"hi"));
+    capture.add(110, server, client, packet(State, 7, (100, 2, 800), b""));
+    let sack = [Extension::selective_ack(&[0b0000_0001, 0, 0, 0])];
+    let sack = unwrap!(header(State, 7, (100, 2, 700)).encode(&sack, b""));
+    capture.add(111, server, client, sack);
+    capture.add(120, client, server, packet(Data, 8, (3, 99, 1000), b"defg"));
+    capture.add(170, server, client, packet(State, 7, (100, 4, 800), b""));
+    capture.add(180, client, server, packet(Fin, 8, (5, 99, 1000), b""));
+    // unrelated traffic
+    capture.add(190, client, server, packet(Data, 9, (0, 0, 1000), b"x"));
+    capture.add(200, client, server, b"not uTP".to_vec());
+
+    let analyser = capture.analyse();
+    assert_eq!(analyser.ignored(), 1);
+    let flows = analyser.into_flows();
+    assert_eq!(flows.len(), 2);
+
+    let flow = &flows[0];
+    assert_eq!((flow.src, flow.dst), (client, server));
+    assert_eq!(flow.connection_id, 7);
+    assert!(flow.syn_seen);
+    assert_eq!(flow.duration(), Duration::from_millis(180));
+    assert_eq!(flow.handshake_rtt, Some(Duration::from_millis(20)));
+    assert_eq!(flow.forward.packets, 7);
+    assert_eq!(flow.forward.bytes, 9);
+    assert_eq!(flow.forward.retransmissions, 2);
+    assert_eq!(flow.backward.packets, 4);
+    assert_eq!(flow.backward.duplicate_acks, 1);
+    assert_eq!(flow.backward.selective_acks, 1);
+    assert_eq!(
+        flow.backward.window,
+        vec![
+            (Duration::from_millis(50), 800),
+            (Duration::from_millis(111), 700),
+            (Duration::from_millis(170), 800),
+        ]
+    );
+    assert_eq!(flow.forward.window, vec![(Duration::from_millis(0), 1000)]);
+    assert_eq!(flow.close, CloseReason::Fin(client));
+
+    assert!(!flows[1].syn_seen);
+    assert_eq!(flows[1].close, CloseReason::Open);
+}
+
+#[test]
+fn both_directions_of_connection_without_syn_make_single_flow() {
+    let peer1 = addr!("[::1]:1000");
+    let peer2 = addr!("[::2]:2000");
+    let mut capture = Capture::new();
+    capture.add(0, peer2, peer1, packet(Data, 41, (10, 20, 500), b"abc"));
+    capture.add(10, peer1, peer2, packet(State, 40, (20, 10, 500), b""));
+    capture.add(20, peer1, peer2, packet(Data, 40, (21, 10, 500), b"de"));
+    capture.add(30, peer2, peer1, packet(Reset, 41, (11, 21, 500), b""));
+
+    let flows = capture.analyse().into_flows();
+
+    assert_eq!(flows.len(), 1);
+    let flow = &flows[0];
+    assert_eq!((flow.src, flow.dst, flow.connection_id), (peer2, peer1, 41));
+    assert_eq!(flow.handshake_rtt, None);
+    assert_eq!((flow.forward.bytes, flow.backward.bytes), (3, 2));
+    assert_eq!(flow.backward.duplicate_acks, 0);
+    assert_eq!(flow.close, CloseReason::Reset(peer2));
+}
+
+#[test]
+fn reordered_packets_are_not_counted_as_retransmissions() {
+    let peer1 = addr!("10.0.0.1:1000");
+    let peer2 = addr!("10.0.0.2:2000");
+    let mut capture = Capture::new();
+    // sequence numbers wrap around
+    capture.add(0, peer1, peer2, packet(Data, 5, (65534, 0, 500), b"a"));
+    capture.add(1, peer1, peer2, packet(Data, 5, (0, 0, 500), b"cc"));
+    capture.add(2, peer1, peer2, packet(Data, 5, (65535, 0, 500), b"bbb"));
+    capture.add(3, peer1, peer2, packet(Data, 5, (65535, 0, 500), b"bbb"));
+    capture.add(4, peer1, peer2, packet(Data, 5, (2, 0, 500), b"e"));
+    capture.add(5, peer1, peer2, packet(Data, 5, (1, 0, 500), b"d"));
+
+    let flows = capture.analyse().into_flows();
+
+    assert_eq!(flows.len(), 1);
+    assert_eq!(flows[0].forward.retransmissions, 1);
+    assert_eq!(flows[0].forward.bytes, 8);
+}
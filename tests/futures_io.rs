@@ -0,0 +1,236 @@
+//! Tests for `futures::io` streams. Packets are exchanged over an in-memory network and the
+//! driver tasks run on a single-threaded executor, no async runtime is involved.
+
+#![cfg(feature = "futures-io")]
+
+extern crate futures;
+extern crate utp;
+#[macro_use]
+extern crate net_literals;
+#[macro_use]
+extern crate unwrap;
+extern crate rand;
+
+mod common;
+
+use common::memory::Network;
+use futures::executor::LocalPool;
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::task::{ArcWake, LocalSpawnExt};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+use utp::{AsyncTimer, AsyncUdpSocket, AsyncUtpListener, AsyncUtpStream, UtpDriverTask};
+
+/// Fails every receive, like a socket closed underneath the driver.
+struct BrokenSocket;
+
+impl AsyncUdpSocket for BrokenSocket {
+    fn poll_recv_from(
+        &self,
+        _cx: &mut Context,
+        _buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+    }
+
+    fn try_send_to(&self, buf: &[u8], _addr: SocketAddr) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(addr!("10.0.0.1:5000"))
+    }
+}
+
+/// Remembers being woken up.
+#[derive(Default)]
+struct WakeFlag(AtomicBool);
+
+impl ArcWake for WakeFlag {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Deadline and the task to wake up once it passes.
+type TimerState = Arc<Mutex<(Instant, Option<Waker>)>>;
+
+/// Timer backed by a thread that checks the deadline every few milliseconds. The thread exits once
+/// the timer is dropped.
+struct ThreadTimer {
+    state: TimerState,
+}
+
+impl ThreadTimer {
+    fn new() -> Self {
+        let state: TimerState = Arc::new(Mutex::new((Instant::now(), None)));
+        let state2 = Arc::clone(&state);
+        let _ = thread::spawn(move || {
+            while Arc::strong_count(&state2) > 1 {
+                thread::sleep(Duration::from_millis(5));
+                let mut state = unwrap!(state2.lock());
+                if state.0 <= Instant::now() {
+                    if let Some(waker) = state.1.take() {
+                        waker.wake();
+                    }
+                }
+            }
+        });
+        Self { state }
+    }
+}
+
+impl AsyncTimer for ThreadTimer {
+    fn reset(&mut self, deadline: Instant) {
+        unwrap!(self.state.lock()).0 = deadline;
+    }
+
+    fn poll_elapsed(&mut self, cx: &mut Context) -> Poll<()> {
+        let mut state = unwrap!(self.state.lock());
+        if state.0 <= Instant::now() {
+            Poll::Ready(())
+        } else {
+            state.1 = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+fn spawn_driver(pool: &LocalPool, task: UtpDriverTask) {
+    unwrap!(pool.spawner().spawn_local(task));
+}
+
+/// Creates a listener and a stream connected to it over the given network. Returns the listener
+/// and both ends of the connection.
+fn connect(
+    pool: &mut LocalPool,
+    network: &Arc<Network>,
+) -> (AsyncUtpListener, AsyncUtpStream, AsyncUtpStream) {
+    let server_addr = addr!("10.0.0.1:5000");
+    let client_addr = addr!("10.0.0.2:6000");
+    let (listener, task) = unwrap!(AsyncUtpListener::with_socket(
+        network.bind(server_addr),
+        ThreadTimer::new(),
+        16,
+    ));
+    spawn_driver(pool, task);
+    let (connect, task) = unwrap!(AsyncUtpStream::connect_with_socket(
+        network.bind(client_addr),
+        ThreadTimer::new(),
+        server_addr,
+    ));
+    spawn_driver(pool, task);
+
+    let client = unwrap!(pool.run_until(connect));
+    let (server, addr) = unwrap!(pool.run_until(listener.accept()));
+    assert_eq!(addr, client_addr);
+    assert_eq!(unwrap!(client.peer_addr()), server_addr);
+    (listener, client, server)
+}
+
+#[test]
+fn streams_exchange_data() {
+    let mut pool = LocalPool::new();
+    let network = Arc::new(Network::default());
+    let (_listener, mut client, mut server) = connect(&mut pool, &network);
+
+    let out_data: Vec<u8> = (0..256 * 1024).map(|i| i as u8).collect();
+    unwrap!(pool.run_until(client.write_all(&out_data)));
+    let mut in_data = vec![0; out_data.len()];
+    unwrap!(pool.run_until(server.read_exact(&mut in_data)));
+    assert_eq!(in_data, out_data);
+
+    unwrap!(pool.run_until(server.write_all(b"thanks")));
+    let mut buf = [0; 6];
+    unwrap!(pool.run_until(client.read_exact(&mut buf)));
+    assert_eq!(&buf, b"thanks");
+}
+
+#[test]
+fn read_returns_eof_after_peer_closes_connection() {
+    let mut pool = LocalPool::new();
+    let network = Arc::new(Network::default());
+    let (_listener, mut client, mut server) = connect(&mut pool, &network);
+
+    unwrap!(pool.run_until(client.write_all(b"bye")));
+    drop(client);
+    let mut in_data = Vec::new();
+    let _ = unwrap!(pool.run_until(server.read_to_end(&mut in_data)));
+
+    assert_eq!(in_data, b"bye".to_vec());
+}
+
+#[test]
+fn driver_tasks_complete_once_streams_and_listeners_are_dropped() {
+    let mut pool = LocalPool::new();
+    let network = Arc::new(Network::default());
+    let (listener, client, server) = connect(&mut pool, &network);
+
+    drop((listener, client, server));
+    // returns once all spawned tasks complete
+    pool.run();
+}
+
+#[test]
+fn connect_times_out_when_peer_does_not_respond() {
+    let mut pool = LocalPool::new();
+    let network = Arc::new(Network::default());
+    // nothing is bound to the server address, connection requests are lost
+    let (connect, task) = unwrap!(AsyncUtpStream::connect_with_socket(
+        network.bind(addr!("10.0.0.2:6000")),
+        ThreadTimer::new(),
+        addr!("10.0.0.1:5000"),
+    ));
+    spawn_driver(&pool, task);
+    let start = Instant::now();
+
+    let res = pool.run_until(connect.timeout(Duration::from_millis(200)));
+
+    assert_eq!(unwrap!(res.err()).kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() < Duration::from_secs(2));
+    // the stream is gone, hence so is the driver task
+    pool.run();
+}
+
+#[test]
+fn closed_stream_keeps_reading_until_peer_closes_too() {
+    let mut pool = LocalPool::new();
+    let network = Arc::new(Network::default());
+    let (_listener, mut client, mut server) = connect(&mut pool, &network);
+
+    unwrap!(pool.run_until(client.write_all(b"hello")));
+    unwrap!(pool.run_until(client.close()));
+    let mut in_data = Vec::new();
+    let _ = unwrap!(pool.run_until(server.read_to_end(&mut in_data)));
+    assert_eq!(in_data, b"hello".to_vec());
+
+    unwrap!(pool.run_until(server.write_all(b"bye")));
+    unwrap!(pool.run_until(server.close()));
+    let mut in_data = Vec::new();
+    let _ = unwrap!(pool.run_until(client.read_to_end(&mut in_data)));
+    assert_eq!(in_data, b"bye".to_vec());
+}
+
+#[test]
+fn driver_task_yields_when_socket_keeps_failing() {
+    let (_listener, mut task) = unwrap!(AsyncUtpListener::with_socket(
+        BrokenSocket,
+        ThreadTimer::new(),
+        16,
+    ));
+    let woken = Arc::new(WakeFlag::default());
+    let waker = futures::task::waker(Arc::clone(&woken));
+
+    let res = Pin::new(&mut task).poll(&mut Context::from_waker(&waker));
+
+    assert!(res.is_pending());
+    // the socket is polled again later
+    assert!(woken.0.load(Ordering::SeqCst));
+}
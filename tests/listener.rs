@@ -0,0 +1,80 @@
+extern crate utp;
+#[macro_use]
+extern crate unwrap;
+extern crate rand;
+
+mod common;
+
+use common::random_vec;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::thread::{self, JoinHandle};
+use utp::{UtpListener, UtpStream};
+
+/// Connects to the given address on a separate thread, sends `out_data` and returns whatever
+/// the server sends back until it closes the connection.
+fn spawn_client(server_addr: SocketAddr, out_data: Vec<u8>) -> JoinHandle<(SocketAddr, Vec<u8>)> {
+    thread::spawn(move || {
+        let mut stream = unwrap!(UtpStream::connect(server_addr));
+        unwrap!(stream.write_all(&out_data));
+        let mut in_data = vec![0; out_data.len()];
+        unwrap!(stream.read_exact(&mut in_data));
+        (unwrap!(stream.local_addr()), in_data)
+    })
+}
+
+fn echo(stream: &mut UtpStream, byte_count: usize) {
+    let mut buf = vec![0; byte_count];
+    unwrap!(stream.read_exact(&mut buf));
+    unwrap!(stream.write_all(&buf));
+}
+
+#[test]
+fn accepted_stream_exchanges_data_with_client() {
+    let listener = unwrap!(UtpListener::bind("127.0.0.1:0"));
+    let out_data = random_vec(512 * 1024);
+    let client = spawn_client(unwrap!(listener.local_addr()), out_data.clone());
+
+    let (mut stream, client_addr) = unwrap!(listener.accept());
+    echo(&mut stream, out_data.len());
+
+    let (client_local_addr, in_data) = unwrap!(client.join());
+    assert_eq!(client_addr.port(), client_local_addr.port());
+    assert_eq!(in_data, out_data);
+}
+
+#[test]
+fn incoming_yields_all_connections() {
+    let listener = unwrap!(UtpListener::bind("127.0.0.1:0"));
+    let listener_addr = unwrap!(listener.local_addr());
+    let clients: Vec<_> = (0..3u8)
+        .map(|i| spawn_client(listener_addr, vec![i; 1024]))
+        .collect();
+
+    for stream in listener.incoming().take(clients.len()) {
+        echo(&mut unwrap!(stream), 1024);
+    }
+
+    let mut replies: Vec<_> = clients
+        .into_iter()
+        .map(|client| unwrap!(client.join()).1)
+        .collect();
+    replies.sort();
+    assert_eq!(replies, vec![vec![0; 1024], vec![1; 1024], vec![2; 1024]]);
+}
+
+#[test]
+fn connections_exceeding_backlog_are_accepted_later() {
+    let listener = unwrap!(UtpListener::bind_with_backlog("127.0.0.1:0", 1));
+    let listener_addr = unwrap!(listener.local_addr());
+    let client1 = spawn_client(listener_addr, vec![1; 16]);
+    let client2 = spawn_client(listener_addr, vec![2; 16]);
+
+    for _ in 0..2 {
+        let (mut stream, _) = unwrap!(listener.accept());
+        echo(&mut stream, 16);
+    }
+
+    assert_eq!(unwrap!(client1.join()).1, vec![1; 16]);
+    assert_eq!(unwrap!(client2.join()).1, vec![2; 16]);
+}
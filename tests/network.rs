@@ -0,0 +1,143 @@
+//! Tests over the emulated network: contexts exchange packets through `VirtualNetwork` links
+//! driven by a manual clock.
+
+extern crate utp;
+#[macro_use]
+extern crate net_literals;
+#[macro_use]
+extern crate unwrap;
+
+use std::time::Duration;
+use utp::{LinkConfig, NetworkStats, NodeId, UtpEvent, UtpSocket, VirtualNetwork};
+
+/// Client and server nodes connected to each other.
+struct Connection {
+    network: VirtualNetwork,
+    server: NodeId,
+    client_sock: UtpSocket,
+    server_sock: UtpSocket,
+    /// Data the server has received.
+    received: Vec<u8>,
+}
+
+impl Connection {
+    fn new(seed: u64, link: LinkConfig) -> Self {
+        let mut network = VirtualNetwork::new(seed);
+        network.set_default_link(link);
+        let client = network.add_node(addr!("10.0.0.1:1000"));
+        let server = network.add_node(addr!("10.0.0.2:2000"));
+
+        let server_addr = network.addr(server);
+        let client_sock = unwrap!(network.utp(client).connect(server_addr));
+        let client_sock_id = client_sock.id();
+        let mut server_sock = None;
+        let mut connected = false;
+        let established = network.run_until(Duration::from_secs(30), |network| {
+            for event in network.take_events(server) {
+                if let UtpEvent::Accepted(sock, _) = event {
+                    server_sock = Some(sock);
+                }
+            }
+            for event in network.take_events(client) {
+                if let UtpEvent::Connected(id) = event {
+                    connected = id == client_sock_id;
+                }
+            }
+            connected && server_sock.is_some()
+        });
+        assert!(established, "connection must be established");
+
+        Self {
+            network,
+            server,
+            client_sock,
+            server_sock: unwrap!(server_sock),
+            received: Vec::new(),
+        }
+    }
+
+    /// Sends data from client to server and waits until all of it arrives.
+    fn transfer(&mut self, data: &[u8]) {
+        let _ = unwrap!(self.client_sock.write_all(data));
+        let server = self.server;
+        let server_sock_id = self.server_sock.id();
+        let received = &mut self.received;
+        let done = self.network.run_until(Duration::from_secs(600), |network| {
+            for event in network.take_events(server) {
+                if let UtpEvent::Data(id, bytes) = event {
+                    assert_eq!(id, server_sock_id);
+                    received.extend_from_slice(&bytes);
+                }
+            }
+            received.len() >= data.len()
+        });
+        assert!(done, "data must be delivered");
+    }
+}
+
+fn test_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+fn bad_link() -> LinkConfig {
+    LinkConfig {
+        latency: Duration::from_millis(40),
+        jitter: Duration::from_millis(15),
+        loss: 0.05,
+        duplicate: 0.02,
+        reorder: 0.05,
+        bandwidth: Some(1_000_000),
+    }
+}
+
+/// Runs the same transfer over a bad link and reports how it went.
+fn lossy_transfer(seed: u64) -> (Duration, NetworkStats) {
+    let data = test_data(200_000);
+    let mut conn = Connection::new(seed, bad_link());
+    conn.transfer(&data);
+    assert_eq!(conn.received, data);
+    (conn.network.clock().elapsed(), conn.network.stats().clone())
+}
+
+#[test]
+fn handshake_takes_a_round_trip() {
+    let link = LinkConfig {
+        latency: Duration::from_millis(100),
+        ..LinkConfig::default()
+    };
+    let conn = Connection::new(1, link);
+
+    // SYN there and STATE back
+    assert_eq!(conn.network.clock().elapsed(), Duration::from_millis(200));
+    assert_eq!(conn.network.stats().lost, 0);
+}
+
+#[test]
+fn data_survives_lossy_reordering_link() {
+    let (_, stats) = lossy_transfer(7);
+
+    assert!(stats.lost > 0);
+    assert!(stats.duplicated > 0);
+    assert!(stats.delivered <= stats.sent - stats.lost + stats.duplicated);
+}
+
+#[test]
+fn runs_with_the_same_seed_are_identical() {
+    assert_eq!(lossy_transfer(42), lossy_transfer(42));
+}
+
+#[test]
+fn bandwidth_limits_throughput() {
+    let link = LinkConfig {
+        bandwidth: Some(100_000),
+        ..LinkConfig::default()
+    };
+    let mut conn = Connection::new(3, link);
+    let start = conn.network.clock().elapsed();
+
+    let data = test_data(50_000);
+    conn.transfer(&data);
+
+    assert_eq!(conn.received, data);
+    assert!(conn.network.clock().elapsed() - start >= Duration::from_millis(500));
+}
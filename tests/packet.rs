@@ -0,0 +1,147 @@
+extern crate utp;
+#[macro_use]
+extern crate unwrap;
+
+use utp::packet::{Extension, Packet, PacketHeader, PacketType, HEADER_SIZE, SELECTIVE_ACK};
+use utp::PacketError;
+
+fn header(packet_type: PacketType) -> PacketHeader {
+    PacketHeader {
+        packet_type,
+        connection_id: 0x1234,
+        timestamp_micros: 0x0102_0304,
+        timestamp_difference_micros: 0x0506_0708,
+        wnd_size: 0x0009_0000,
+        seq_nr: 0xfffe,
+        ack_nr: 7,
+    }
+}
+
+#[test]
+fn header_is_encoded_as_bep_29_describes() {
+    let packet = unwrap!(header(PacketType::Data).encode(&[], b"hi"));
+
+    assert_eq!(
+        packet,
+        vec![
+            0x01, 0, 0x12, 0x34, 1, 2, 3, 4, 5, 6, 7, 8, 0, 9, 0, 0, 0xff, 0xfe, 0, 7, b'h', b'i',
+        ]
+    );
+}
+
+#[test]
+fn encoded_packet_parses_back() {
+    let bitmask = [0b0000_0101, 0, 0, 0x80];
+    let extensions = [
+        Extension::selective_ack(&bitmask),
+        Extension {
+            kind: 77,
+            data: b"unknown",
+        },
+    ];
+    let buf = unwrap!(header(PacketType::State).encode(&extensions, b"payload"));
+
+    let packet = unwrap!(Packet::parse(&buf));
+    assert_eq!(packet.header(), header(PacketType::State));
+    assert_eq!(packet.version(), 1);
+    assert_eq!(packet.extensions().collect::<Vec<_>>(), extensions.to_vec());
+    assert_eq!(packet.payload(), b"payload");
+    assert_eq!(packet.as_bytes(), &buf[..]);
+    assert_eq!(
+        buf.len(),
+        HEADER_SIZE + 2 + bitmask.len() + 2 + 7 + b"payload".len()
+    );
+}
+
+#[test]
+fn selective_ack_bitmask_starts_two_packets_after_ack_nr() {
+    let bitmask = [0b0000_0101, 0, 0, 0x80];
+    let mut header = header(PacketType::State);
+    header.ack_nr = 0xfffe;
+    let buf = unwrap!(header.encode(&[Extension::selective_ack(&bitmask)], &[]));
+
+    let sack = unwrap!(unwrap!(Packet::parse(&buf)).selective_ack());
+    assert_eq!(sack.bitmask(), &bitmask);
+    assert!(sack.is_acked(0));
+    assert!(!sack.is_acked(1));
+    assert!(sack.is_acked(2));
+    assert!(!sack.is_acked(0xffff));
+    assert_eq!(sack.acked(), vec![0, 2, 31]);
+}
+
+#[test]
+fn packets_without_extensions_have_no_selective_ack() {
+    let buf = unwrap!(header(PacketType::Syn).encode(&[], &[]));
+    let packet = unwrap!(Packet::parse(&buf));
+
+    assert_eq!(packet.extensions().count(), 0);
+    assert!(packet.selective_ack().is_none());
+    assert!(packet.payload().is_empty());
+}
+
+#[test]
+fn malformed_packets_are_rejected() {
+    let valid = unwrap!(header(PacketType::Fin).encode(&[], &[]));
+    assert_eq!(
+        Packet::parse(&valid[..HEADER_SIZE - 1]).err(),
+        Some(PacketError::TooShort(HEADER_SIZE - 1))
+    );
+
+    let mut buf = valid.clone();
+    buf[0] = 0x12;
+    assert_eq!(
+        Packet::parse(&buf).err(),
+        Some(PacketError::UnsupportedVersion(2))
+    );
+
+    let mut buf = valid.clone();
+    buf[0] = 0x51;
+    assert_eq!(
+        Packet::parse(&buf).err(),
+        Some(PacketError::UnknownPacketType(5))
+    );
+
+    let mut buf = valid.clone();
+    buf[1] = 9;
+    assert_eq!(
+        Packet::parse(&buf).err(),
+        Some(PacketError::TruncatedExtension(9))
+    );
+    buf.extend_from_slice(&[0, 4, 1, 2]);
+    assert_eq!(
+        Packet::parse(&buf).err(),
+        Some(PacketError::TruncatedExtension(9))
+    );
+
+    let mut buf = valid;
+    buf[1] = SELECTIVE_ACK;
+    buf.extend_from_slice(&[0, 3, 1, 2, 3]);
+    assert_eq!(
+        Packet::parse(&buf).err(),
+        Some(PacketError::InvalidSelectiveAck(3))
+    );
+}
+
+#[test]
+fn invalid_extensions_are_not_encoded() {
+    let header = header(PacketType::Data);
+    let long = [0; 256];
+
+    let res = header.encode(&[Extension { kind: 0, data: &[] }], &[]);
+    assert_eq!(res, Err(PacketError::InvalidExtension(0, 0)));
+    let res = header.encode(
+        &[Extension {
+            kind: 2,
+            data: &long,
+        }],
+        &[],
+    );
+    assert_eq!(res, Err(PacketError::InvalidExtension(2, 256)));
+    let res = header.encode(&[Extension::selective_ack(&[0; 6])], &[]);
+    assert_eq!(res, Err(PacketError::InvalidSelectiveAck(6)));
+
+    let mut buf = b"prefix".to_vec();
+    let res = header.encode_into(&[Extension::selective_ack(&[])], &[], &mut buf);
+    assert_eq!(res, Err(PacketError::InvalidSelectiveAck(0)));
+    assert_eq!(buf, b"prefix".to_vec());
+}
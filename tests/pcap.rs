@@ -0,0 +1,147 @@
+extern crate utp;
+#[macro_use]
+extern crate net_literals;
+#[macro_use]
+extern crate unwrap;
+extern crate rand;
+
+mod common;
+
+use common::SharedBuf;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, UNIX_EPOCH};
+use utp::packet::{Packet, PacketType};
+use utp::pcap::{PcapReader, PcapWriter, LINKTYPE_RAW, PCAP_MAGIC};
+use utp::{UtpContext, UtpError};
+
+fn u32_le(buf: &[u8]) -> u32 {
+    u32::from(buf[0]) | u32::from(buf[1]) << 8 | u32::from(buf[2]) << 16 | u32::from(buf[3]) << 24
+}
+
+/// Splits pcap stream into timestamps in microseconds and IP packets.
+fn records(pcap: &[u8]) -> Vec<(u64, Vec<u8>)> {
+    assert_eq!(u32_le(&pcap[0..]), PCAP_MAGIC);
+    assert_eq!(u32_le(&pcap[20..]), LINKTYPE_RAW);
+    let mut records = Vec::new();
+    let mut rest = &pcap[24..];
+    while !rest.is_empty() {
+        let micros = u64::from(u32_le(rest)) * 1_000_000 + u64::from(u32_le(&rest[4..]));
+        let len = u32_le(&rest[8..]) as usize;
+        assert_eq!(u32_le(&rest[12..]) as usize, len);
+        records.push((micros, rest[16..16 + len].to_vec()));
+        rest = &rest[16 + len..];
+    }
+    records
+}
+
+/// Internet checksum over the data, valid checksummed data sums up to zero.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in data.chunks(2) {
+        sum += u32::from(chunk[0]) << 8 | u32::from(*chunk.get(1).unwrap_or(&0));
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Returns UDP ports and payload of IPv4 packet, checking both checksums.
+fn parse_udp_v4(packet: &[u8]) -> (u16, u16, Vec<u8>) {
+    assert_eq!(packet[0], 0x45);
+    assert_eq!(packet[9], 17);
+    assert_eq!(checksum(&packet[..20]), 0);
+    let mut pseudo = packet[12..20].to_vec();
+    pseudo.extend_from_slice(&[0, 17, packet[24], packet[25]]);
+    pseudo.extend_from_slice(&packet[20..]);
+    assert_eq!(checksum(&pseudo), 0);
+
+    let port = |i: usize| u16::from(packet[i]) << 8 | u16::from(packet[i + 1]);
+    (port(20), port(22), packet[28..].to_vec())
+}
+
+#[test]
+fn udp_datagrams_are_written_with_valid_ipv4_headers() {
+    let mut writer = unwrap!(PcapWriter::new(Vec::new()));
+    let timestamp = UNIX_EPOCH + Duration::from_micros(1_500_000_123);
+    unwrap!(writer.write_udp(
+        timestamp,
+        addr!("10.0.0.1:1000"),
+        addr!("10.0.0.2:2000"),
+        b"hello"
+    ));
+
+    let records = records(&writer.into_inner());
+    assert_eq!(records.len(), 1);
+    let (micros, ref packet) = records[0];
+    assert_eq!(micros, 1_500_000_123);
+    assert_eq!(&packet[12..16], &[10, 0, 0, 1]);
+    assert_eq!(&packet[16..20], &[10, 0, 0, 2]);
+    assert_eq!(parse_udp_v4(packet), (1000, 2000, b"hello".to_vec()));
+}
+
+#[test]
+fn ipv4_address_paired_with_ipv6_one_is_written_as_mapped_address() {
+    let mut writer = unwrap!(PcapWriter::new(Vec::new()));
+    let src: SocketAddr = addr!("[::1]:1000");
+    unwrap!(writer.write_udp(UNIX_EPOCH, src, addr!("10.0.0.2:2000"), b"odd"));
+
+    let records = records(&writer.into_inner());
+    let packet = &records[0].1;
+    assert_eq!(packet[0] >> 4, 6);
+    assert_eq!(packet[6], 17);
+    assert_eq!(
+        &packet[8..24],
+        &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]
+    );
+    assert_eq!(
+        &packet[24..40],
+        &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 10, 0, 0, 2]
+    );
+    let mut pseudo = packet[8..40].to_vec();
+    pseudo.extend_from_slice(&[0, 17, packet[44], packet[45]]);
+    pseudo.extend_from_slice(&packet[40..]);
+    assert_eq!(checksum(&pseudo), 0);
+    assert_eq!(&packet[48..], b"odd");
+}
+
+#[test]
+fn record_claiming_huge_length_is_rejected() {
+    let mut pcap = unwrap!(PcapWriter::new(Vec::new())).into_inner();
+    // timestamp followed by captured and original length of 4 GiB
+    pcap.extend_from_slice(&[0; 8]);
+    pcap.extend_from_slice(&[0xff; 8]);
+
+    let mut reader = unwrap!(PcapReader::new(&pcap[..]));
+    let res = reader.read_udp();
+
+    assert_eq!(unwrap!(res.err()).kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn context_captures_sent_and_received_packets() {
+    let local_addr = addr!("10.0.0.1:1000");
+    let remote_addr = addr!("10.0.0.2:2000");
+    let pcap = SharedBuf::default();
+    let mut utp = UtpContext::new(());
+    utp.enable_events();
+    unwrap!(utp.start_capture(local_addr, pcap.clone()));
+
+    let _sock = unwrap!(utp.connect(remote_addr));
+    let res = utp.process_udp(b"not uTP", remote_addr);
+    assert_eq!(res, Err(UtpError::IllegalPacket));
+    unwrap!(utp.stop_capture());
+    let _ = utp.process_udp(b"after capture", remote_addr);
+
+    let records = records(&pcap.contents());
+    assert_eq!(records.len(), 2);
+    let (src_port, dst_port, syn) = parse_udp_v4(&records[0].1);
+    assert_eq!((src_port, dst_port), (1000, 2000));
+    assert_eq!(unwrap!(Packet::parse(&syn)).packet_type(), PacketType::Syn);
+    assert_eq!(
+        parse_udp_v4(&records[1].1),
+        (2000, 1000, b"not uTP".to_vec())
+    );
+    assert!(records[0].0 <= records[1].0);
+}
@@ -0,0 +1,231 @@
+//! Manual clock, pcap reading and capture replay tests. Contexts exchange packets directly,
+//! without real UDP sockets.
+
+extern crate utp;
+#[macro_use]
+extern crate net_literals;
+#[macro_use]
+extern crate unwrap;
+extern crate rand;
+
+mod common;
+
+use common::{connect, run_network, Peer, SharedBuf};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use utp::packet::{Packet, PacketType};
+use utp::pcap::{PcapReader, PcapWriter, UdpRecord, LINKTYPE_ETHERNET, PCAP_MAGIC_NANOS};
+use utp::{ManualClock, Replay, ReplayEvent, UtpContext, UtpEvent};
+
+fn replayed_data(events: &[ReplayEvent]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for event in events {
+        if let UtpEvent::Data(_, ref bytes) = event.event {
+            data.extend_from_slice(bytes);
+        }
+    }
+    data
+}
+
+fn sent_packet_types(events: &[ReplayEvent]) -> Vec<PacketType> {
+    events
+        .iter()
+        .filter_map(|event| match event.event {
+            UtpEvent::Transmit(ref packet, _) => Some(unwrap!(Packet::parse(packet)).packet_type()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Connects the client to the server and sends data both ways, capturing traffic of one of them.
+fn capture_exchange(capture_server: bool) -> (SocketAddr, Vec<u8>) {
+    let mut client = Peer::new(addr!("10.0.0.1:1000"));
+    let mut server = Peer::new(addr!("10.0.0.2:2000"));
+    let pcap = SharedBuf::default();
+    let captured_addr = if capture_server {
+        unwrap!(server.utp.start_capture(server.addr, pcap.clone()));
+        server.addr
+    } else {
+        unwrap!(client.utp.start_capture(client.addr, pcap.clone()));
+        client.addr
+    };
+
+    let (client_sock, server_sock) = connect(&mut client, &mut server);
+    let _ = unwrap!(client_sock.send(b"hello from client"));
+    let _ = unwrap!(server_sock.send(b"hello from server"));
+    run_network(&mut client, &mut server);
+    assert_eq!(server.received_data(), b"hello from client".to_vec());
+    assert_eq!(client.received_data(), b"hello from server".to_vec());
+
+    unwrap!(server.utp.stop_capture());
+    unwrap!(client.utp.stop_capture());
+    (captured_addr, pcap.contents())
+}
+
+#[test]
+fn context_driven_by_manual_clock_retransmits_only_when_clock_advances() {
+    let clock = ManualClock::new(SystemTime::now());
+    let mut utp = UtpContext::new(());
+    utp.set_clock(clock.clone());
+    utp.enable_events();
+    let _sock = unwrap!(utp.connect(addr!("10.0.0.2:2000")));
+    let transmitted = |utp: &UtpContext<()>| {
+        utp.poll_events()
+            .filter(|event| matches!(*event, UtpEvent::Transmit(..)))
+            .count()
+    };
+    assert_eq!(transmitted(&utp), 1);
+
+    for _ in 0..10 {
+        utp.check_timeouts();
+    }
+    assert_eq!(transmitted(&utp), 0);
+
+    while clock.elapsed() < Duration::from_secs(60) {
+        clock.advance(unwrap!(utp.next_timeout()));
+        utp.check_timeouts();
+        if transmitted(&utp) > 0 {
+            break;
+        }
+    }
+    assert!(clock.elapsed() >= Duration::from_millis(500));
+    assert!(clock.elapsed() < Duration::from_secs(60));
+}
+
+#[test]
+fn manual_clock_never_goes_back() {
+    let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let clock = ManualClock::new(start);
+    let clone = clock.clone();
+
+    clock.advance(Duration::from_secs(2));
+    clone.advance_to(start + Duration::from_secs(1));
+    assert_eq!(clone.elapsed(), Duration::from_secs(2));
+    clone.advance_to(start + Duration::from_secs(5));
+    assert_eq!(clock.system_time(), start + Duration::from_secs(5));
+    assert_eq!(clock.now() - clone.now(), Duration::from_secs(0));
+}
+
+#[test]
+fn written_datagrams_are_read_back() {
+    let mut writer = unwrap!(PcapWriter::new(Vec::new()));
+    let records = vec![
+        UdpRecord {
+            timestamp: UNIX_EPOCH + Duration::from_micros(1_000_001),
+            src: addr!("10.0.0.1:1000"),
+            dst: addr!("10.0.0.2:2000"),
+            payload: b"first".to_vec(),
+        },
+        UdpRecord {
+            timestamp: UNIX_EPOCH + Duration::from_micros(2_000_002),
+            src: addr!("[::1]:2000"),
+            dst: addr!("[::2]:1000"),
+            payload: b"second".to_vec(),
+        },
+    ];
+    for record in &records {
+        unwrap!(writer.write_udp(record.timestamp, record.src, record.dst, &record.payload));
+    }
+
+    let pcap = writer.into_inner();
+    let reader = unwrap!(PcapReader::new(&pcap[..]));
+    let read = unwrap!(reader.collect::<io::Result<Vec<_>>>());
+    assert_eq!(read, records);
+}
+
+#[test]
+fn big_endian_ethernet_capture_with_nanosecond_timestamps_is_read() {
+    let mut pcap = Vec::new();
+    pcap.extend_from_slice(&PCAP_MAGIC_NANOS.to_be_bytes());
+    pcap.extend_from_slice(&[0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+    pcap.extend_from_slice(&LINKTYPE_ETHERNET.to_be_bytes());
+
+    let mut frame = vec![0; 12];
+    frame.extend_from_slice(&[0x08, 0x00]);
+    frame.extend_from_slice(&[0x45, 0, 0, 31, 0, 0, 0x40, 0, 64, 17, 0, 0]);
+    frame.extend_from_slice(&[192, 168, 0, 1, 192, 168, 0, 2]);
+    frame.extend_from_slice(&[0x03, 0xe8, 0x07, 0xd0, 0, 11, 0, 0]);
+    frame.extend_from_slice(b"abc");
+    // Ethernet padding
+    frame.extend_from_slice(&[0; 4]);
+    // not UDP
+    let mut tcp_frame = frame.clone();
+    tcp_frame[14 + 9] = 6;
+    for frame in &[tcp_frame, frame] {
+        pcap.extend_from_slice(&[0, 0, 0, 7, 0, 0, 0, 9]);
+        pcap.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        pcap.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        pcap.extend_from_slice(frame);
+    }
+
+    let mut reader = unwrap!(PcapReader::new(&pcap[..]));
+    let record = unwrap!(unwrap!(reader.read_udp()));
+    assert_eq!(record.timestamp, UNIX_EPOCH + Duration::new(7, 9));
+    assert_eq!(record.src, addr!("192.168.0.1:1000"));
+    assert_eq!(record.dst, addr!("192.168.0.2:2000"));
+    assert_eq!(record.payload, b"abc".to_vec());
+    assert!(unwrap!(reader.read_udp()).is_none());
+}
+
+#[test]
+fn garbage_is_not_read_as_pcap() {
+    let res = PcapReader::new(&[0; 24][..]);
+    assert_eq!(unwrap!(res.err()).kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn replayed_server_accepts_connection_and_receives_the_same_data() {
+    let (server_addr, pcap) = capture_exchange(true);
+
+    let mut replay = unwrap!(Replay::from_pcap(server_addr, &pcap[..]));
+    replay.run();
+
+    let events = replay.take_events();
+    let accepted = events.iter().any(|event| match event.event {
+        UtpEvent::Accepted(_, addr) => addr == addr!("10.0.0.1:1000"),
+        _ => false,
+    });
+    assert!(accepted);
+    assert_eq!(replayed_data(&events), b"hello from client".to_vec());
+    assert_eq!(sent_packet_types(&events)[0], PacketType::State);
+}
+
+#[test]
+fn replayed_client_reconnects_and_receives_the_same_data() {
+    let (client_addr, pcap) = capture_exchange(false);
+
+    let mut replay = unwrap!(Replay::from_pcap(client_addr, &pcap[..]));
+    replay.run();
+
+    assert_eq!(replay.connected_sockets().len(), 1);
+    let id = replay.connected_sockets()[0].id();
+    let events = replay.take_events();
+    let connected = events.iter().any(|event| match event.event {
+        UtpEvent::Connected(connected_id) => connected_id == id,
+        _ => false,
+    });
+    assert!(connected);
+    assert_eq!(replayed_data(&events), b"hello from server".to_vec());
+    assert_eq!(sent_packet_types(&events)[0], PacketType::Syn);
+}
+
+#[test]
+fn replay_fires_timeouts_after_the_last_record() {
+    let (client_addr, pcap) = capture_exchange(false);
+    let mut replay = unwrap!(Replay::from_pcap(client_addr, &pcap[..]));
+    replay.run();
+    let _ = replay.take_events();
+
+    let _ = unwrap!(replay.connected_sockets()[0].send(b"nobody is listening"));
+    replay.advance(Duration::from_secs(60));
+
+    let events = replay.take_events();
+    let data_packets = sent_packet_types(&events)
+        .into_iter()
+        .filter(|packet_type| *packet_type == PacketType::Data)
+        .count();
+    assert!(data_packets > 1, "data must be retransmitted");
+    let last = unwrap!(events.last());
+    assert!(last.at > Duration::from_secs(1));
+}
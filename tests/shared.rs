@@ -0,0 +1,254 @@
+extern crate utp;
+#[macro_use]
+extern crate net_literals;
+#[macro_use]
+extern crate unwrap;
+extern crate rand;
+
+mod common;
+
+use common::random_vec;
+use std::io;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Barrier};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use utp::{SharedUtpContext, SharedUtpSocket, UtpCallbackType, UtpError, UtpState};
+
+/// Runs the usual UDP -> uTP loop on a separate thread until `stop` is set.
+struct Driver {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Driver {
+    fn spawn(utp: SharedUtpContext<UdpSocket>) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop2 = Arc::clone(&stop);
+        let socket = utp.with_user_data(|sock| unwrap!(sock.try_clone()));
+        unwrap!(socket.set_read_timeout(Some(Duration::from_millis(20))));
+        let thread = thread::spawn(move || {
+            let mut buf = vec![0; 4096];
+            let mut last_timeout_check = Instant::now();
+            while !stop2.load(Ordering::SeqCst) {
+                match socket.recv_from(&mut buf) {
+                    Ok((bytes_read, sender_addr)) => {
+                        unwrap!(utp.process_udp(&buf[..bytes_read], sender_addr));
+                    }
+                    Err(ref e)
+                        if e.kind() == io::ErrorKind::WouldBlock
+                            || e.kind() == io::ErrorKind::TimedOut => {}
+                    Err(e) => panic!("Failed to read UDP socket: {}", e),
+                }
+                utp.ack_packets();
+                if last_timeout_check.elapsed() >= Duration::from_millis(500) {
+                    utp.check_timeouts();
+                    last_timeout_check = Instant::now();
+                }
+            }
+        });
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Driver {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            unwrap!(thread.join());
+        }
+    }
+}
+
+/// Creates uTP context that sends packets over given UDP socket and reports received data and
+/// writable sockets.
+fn make_utp_ctx(
+    bind_addr: &str,
+) -> (
+    SharedUtpContext<UdpSocket>,
+    Receiver<Vec<u8>>,
+    Receiver<UtpState>,
+) {
+    let socket = unwrap!(UdpSocket::bind(bind_addr));
+    let utp = SharedUtpContext::new(socket);
+    let (data_tx, data_rx) = mpsc::channel();
+    let (state_tx, state_rx) = mpsc::channel();
+    utp.set_callback(
+        UtpCallbackType::Sendto,
+        Box::new(|args| {
+            if let Some(addr) = args.address() {
+                let _ = args.user_data().send_to(args.buf(), addr);
+            }
+            0
+        }),
+    );
+    utp.set_callback(
+        UtpCallbackType::OnRead,
+        Box::new(move |mut args| {
+            let _ = data_tx.send(args.buf().to_vec());
+            args.ack_data();
+            0
+        }),
+    );
+    utp.set_callback(
+        UtpCallbackType::OnStateChange,
+        Box::new(move |args| {
+            if let Ok(state) = args.state() {
+                let _ = state_tx.send(state);
+            }
+            0
+        }),
+    );
+    (utp, data_rx, state_rx)
+}
+
+/// Writes all the data to the socket retrying when libutp's send buffer is full.
+fn send_all<T: Send>(sock: &SharedUtpSocket<T>, mut data: &[u8]) {
+    while !data.is_empty() {
+        match sock.send(data) {
+            Ok(bytes_sent) => data = &data[bytes_sent..],
+            Err(UtpError::WouldBlock) => thread::sleep(Duration::from_millis(1)),
+            Err(e) => panic!("Failed to send data: {}", e),
+        }
+    }
+}
+
+fn wait_for_connections(state_rx: &Receiver<UtpState>, count: usize) {
+    let mut connected = 0;
+    while connected < count {
+        let state = unwrap!(state_rx.recv_timeout(Duration::from_secs(10)));
+        if state == UtpState::Connected {
+            connected += 1;
+        }
+    }
+}
+
+#[test]
+fn shared_context_is_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<SharedUtpContext<UdpSocket>>();
+    assert_send_sync::<SharedUtpSocket<UdpSocket>>();
+}
+
+#[test]
+fn many_threads_write_to_their_own_connections() {
+    const WRITERS: usize = 8;
+    const BYTES_PER_WRITER: usize = 256 * 1024;
+
+    let (server_utp, server_data_rx, _server_state_rx) = make_utp_ctx("127.0.0.1:0");
+    let server_addr = server_utp.with_user_data(|sock| unwrap!(sock.local_addr()));
+    let (client_utp, _client_data_rx, client_state_rx) = make_utp_ctx("127.0.0.1:0");
+    let _server_driver = Driver::spawn(server_utp);
+    let _client_driver = Driver::spawn(client_utp.clone());
+
+    let sockets: Vec<_> = (0..WRITERS)
+        .map(|_| unwrap!(client_utp.connect(server_addr)))
+        .collect();
+    wait_for_connections(&client_state_rx, WRITERS);
+
+    let writers: Vec<_> = sockets
+        .into_iter()
+        .map(|sock| {
+            thread::spawn(move || {
+                send_all(&sock, &random_vec(BYTES_PER_WRITER));
+                // keep the socket open until the data is delivered
+                sock
+            })
+        })
+        .collect();
+
+    let mut bytes_received = 0;
+    while bytes_received < WRITERS * BYTES_PER_WRITER {
+        let data = unwrap!(server_data_rx.recv_timeout(Duration::from_secs(10)));
+        bytes_received += data.len();
+    }
+    assert_eq!(bytes_received, WRITERS * BYTES_PER_WRITER);
+
+    for writer in writers {
+        let _ = unwrap!(writer.join());
+    }
+}
+
+#[test]
+fn many_threads_share_single_connection() {
+    const WRITERS: usize = 8;
+    const CHUNKS_PER_WRITER: usize = 64;
+    const CHUNK_SIZE: usize = 1024;
+
+    let (server_utp, server_data_rx, _server_state_rx) = make_utp_ctx("127.0.0.1:0");
+    let server_addr = server_utp.with_user_data(|sock| unwrap!(sock.local_addr()));
+    let (client_utp, _client_data_rx, client_state_rx) = make_utp_ctx("127.0.0.1:0");
+    let _server_driver = Driver::spawn(server_utp);
+    let _client_driver = Driver::spawn(client_utp.clone());
+
+    let sock = Arc::new(unwrap!(client_utp.connect(server_addr)));
+    wait_for_connections(&client_state_rx, 1);
+
+    let start = Arc::new(Barrier::new(WRITERS));
+    let writers: Vec<_> = (0..WRITERS)
+        .map(|writer| {
+            let sock = Arc::clone(&sock);
+            let start = Arc::clone(&start);
+            thread::spawn(move || {
+                let _ = start.wait();
+                for _ in 0..CHUNKS_PER_WRITER {
+                    send_all(&sock, &[writer as u8; CHUNK_SIZE]);
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        unwrap!(writer.join());
+    }
+
+    let mut bytes_per_writer = [0; WRITERS];
+    let mut bytes_received = 0;
+    while bytes_received < WRITERS * CHUNKS_PER_WRITER * CHUNK_SIZE {
+        let data = unwrap!(server_data_rx.recv_timeout(Duration::from_secs(10)));
+        for byte in &data {
+            bytes_per_writer[*byte as usize] += 1;
+        }
+        bytes_received += data.len();
+    }
+    for bytes in &bytes_per_writer {
+        assert_eq!(*bytes, CHUNKS_PER_WRITER * CHUNK_SIZE);
+    }
+}
+
+#[test]
+fn threads_connect_and_drop_sockets_concurrently() {
+    const THREADS: usize = 8;
+    const CONNECTIONS_PER_THREAD: usize = 16;
+
+    let (utp, _data_rx, _state_rx) = make_utp_ctx("127.0.0.1:0");
+    let _driver = Driver::spawn(utp.clone());
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let utp = utp.clone();
+            thread::spawn(move || {
+                for _ in 0..CONNECTIONS_PER_THREAD {
+                    let sock = unwrap!(utp.connect(addr!("127.0.0.1:1")));
+                    let _ = sock.send(b"hello");
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        unwrap!(thread.join());
+    }
+}
+
+#[test]
+fn socket_can_outlive_context_handle() {
+    let (utp, _data_rx, _state_rx) = make_utp_ctx("127.0.0.1:0");
+    let sock = unwrap!(utp.connect(addr!("127.0.0.1:1")));
+    drop(utp);
+
+    unwrap!(thread::spawn(move || drop(sock)).join());
+}
@@ -0,0 +1,234 @@
+extern crate utp;
+#[macro_use]
+extern crate unwrap;
+extern crate rand;
+
+mod common;
+
+use common::random_vec;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use utp::{UtpCallbackArgs, UtpCallbackType, UtpContext, UtpError, UtpStream};
+
+/// Runs uTP server on a separate thread and returns its address. `on_read` is called for all
+/// data the server receives. The server thread runs until the test process exits.
+fn spawn_server(on_read: fn(&mut UtpCallbackArgs<UdpSocket>)) -> SocketAddr {
+    spawn_server_with_switch(on_read, Arc::new(AtomicBool::new(false)))
+}
+
+/// Same as `spawn_server()`, but once `deaf` is set, the server ignores all incoming packets.
+fn spawn_server_with_switch(
+    on_read: fn(&mut UtpCallbackArgs<UdpSocket>),
+    deaf: Arc<AtomicBool>,
+) -> SocketAddr {
+    let (addr_tx, addr_rx) = mpsc::channel();
+    let _ = thread::spawn(move || {
+        let socket = unwrap!(UdpSocket::bind((Ipv4Addr::new(127, 0, 0, 1), 0)));
+        unwrap!(socket.set_nonblocking(true));
+        unwrap!(addr_tx.send(unwrap!(socket.local_addr())));
+
+        let mut utp = UtpContext::new(unwrap!(socket.try_clone()));
+        utp.set_callback(
+            UtpCallbackType::Sendto,
+            Box::new(|args| {
+                if let Some(addr) = args.address() {
+                    let _ = args.user_data().send_to(args.buf(), addr);
+                }
+                0
+            }),
+        );
+        utp.set_callback(
+            UtpCallbackType::OnRead,
+            Box::new(move |mut args| {
+                on_read(&mut args);
+                args.ack_data();
+                0
+            }),
+        );
+
+        let mut buf = vec![0; 4096];
+        let mut last_timeout_check = Instant::now();
+        loop {
+            match socket.recv_from(&mut buf) {
+                Ok((bytes_read, sender_addr)) => {
+                    if !deaf.load(Ordering::SeqCst) {
+                        unwrap!(utp.process_udp(&buf[..bytes_read], sender_addr));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    utp.ack_packets();
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Err(e) => panic!("Failed to read UDP socket: {}", e),
+            }
+            if last_timeout_check.elapsed() >= Duration::from_millis(500) {
+                utp.check_timeouts();
+                last_timeout_check = Instant::now();
+            }
+        }
+    });
+    unwrap!(addr_rx.recv())
+}
+
+fn echo(args: &mut UtpCallbackArgs<UdpSocket>) {
+    let id = unwrap!(args.socket_id());
+    args.defer_write(id, args.buf());
+}
+
+fn reply_and_close(args: &mut UtpCallbackArgs<UdpSocket>) {
+    let id = unwrap!(args.socket_id());
+    args.defer_write(id, b"bye");
+    args.defer_close(id);
+}
+
+fn ignore(_args: &mut UtpCallbackArgs<UdpSocket>) {}
+
+static BYTES_COUNTED: AtomicUsize = AtomicUsize::new(0);
+
+fn count(args: &mut UtpCallbackArgs<UdpSocket>) {
+    let _ = BYTES_COUNTED.fetch_add(args.buf().len(), Ordering::SeqCst);
+}
+
+#[test]
+fn data_written_to_stream_is_echoed_back() {
+    let server_addr = spawn_server(echo);
+    let mut stream = unwrap!(UtpStream::connect(server_addr));
+
+    let out_data = random_vec(1024 * 1024);
+    unwrap!(stream.write_all(&out_data));
+    let mut in_data = vec![0; out_data.len()];
+    unwrap!(stream.read_exact(&mut in_data));
+
+    assert_eq!(in_data, out_data);
+}
+
+#[test]
+fn stream_can_be_written_and_read_from_different_threads() {
+    let server_addr = spawn_server(echo);
+    let stream = Arc::new(unwrap!(UtpStream::connect(server_addr)));
+    let out_data = random_vec(256 * 1024);
+
+    let writer = {
+        let stream = Arc::clone(&stream);
+        let out_data = out_data.clone();
+        thread::spawn(move || unwrap!((&*stream).write_all(&out_data)))
+    };
+    let mut in_data = vec![0; out_data.len()];
+    unwrap!((&*stream).read_exact(&mut in_data));
+    unwrap!(writer.join());
+
+    assert_eq!(in_data, out_data);
+}
+
+#[test]
+fn read_returns_zero_after_peer_closes_connection() {
+    let server_addr = spawn_server(reply_and_close);
+    let mut stream = unwrap!(UtpStream::connect(server_addr));
+
+    unwrap!(stream.write_all(b"hello"));
+    let mut in_data = Vec::new();
+    let _ = unwrap!(stream.read_to_end(&mut in_data));
+
+    assert_eq!(in_data, b"bye".to_vec());
+}
+
+#[test]
+fn read_times_out_when_no_data_arrives() {
+    let server_addr = spawn_server(ignore);
+    let mut stream = unwrap!(UtpStream::connect(server_addr));
+    unwrap!(stream.set_read_timeout(Some(Duration::from_millis(100))));
+
+    let mut buf = [0; 16];
+    let res = stream.read(&mut buf);
+
+    assert_eq!(unwrap!(res.err()).kind(), io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn zero_timeout_is_rejected() {
+    let server_addr = spawn_server(ignore);
+    let stream = unwrap!(UtpStream::connect(server_addr));
+
+    let res = stream.set_read_timeout(Some(Duration::from_secs(0)));
+
+    assert_eq!(unwrap!(res.err()).kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(unwrap!(stream.read_timeout()), None);
+}
+
+#[test]
+fn read_returns_zero_after_shutdown() {
+    let server_addr = spawn_server(echo);
+    let mut stream = unwrap!(UtpStream::connect(server_addr));
+
+    unwrap!(stream.shutdown(Shutdown::Read));
+    let mut buf = [0; 16];
+
+    assert_eq!(unwrap!(stream.read(&mut buf)), 0);
+}
+
+#[test]
+fn stream_knows_peer_and_local_addresses() {
+    let server_addr = spawn_server(ignore);
+    let stream = unwrap!(UtpStream::connect(server_addr));
+
+    assert_eq!(unwrap!(stream.peer_addr()), server_addr);
+    assert_ne!(unwrap!(stream.local_addr()).port(), 0);
+}
+
+#[test]
+fn connect_times_out_when_peer_does_not_respond() {
+    // never replies to connection requests
+    let silent_peer = unwrap!(UdpSocket::bind("127.0.0.1:0"));
+    let start = Instant::now();
+
+    let res = UtpStream::connect_timeout(
+        &unwrap!(silent_peer.local_addr()),
+        Duration::from_millis(200),
+    );
+
+    let err = unwrap!(res.err());
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert_eq!(
+        unwrap!(err.get_ref()).downcast_ref::<UtpError>(),
+        Some(&UtpError::ConnectTimedOut)
+    );
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn connect_with_zero_timeout_is_rejected() {
+    let server_addr = spawn_server(ignore);
+    let res = UtpStream::connect_timeout(&server_addr, Duration::from_secs(0));
+    assert_eq!(unwrap!(res.err()).kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn close_returns_once_data_is_delivered() {
+    let server_addr = spawn_server(count);
+    let mut stream = unwrap!(UtpStream::connect(server_addr));
+
+    unwrap!(stream.write_all(&random_vec(64 * 1024)));
+    unwrap!(stream.close());
+
+    assert_eq!(BYTES_COUNTED.load(Ordering::SeqCst), 64 * 1024);
+}
+
+#[test]
+fn close_times_out_when_peer_stops_responding() {
+    let deaf = Arc::new(AtomicBool::new(false));
+    let server_addr = spawn_server_with_switch(ignore, Arc::clone(&deaf));
+    let mut stream = unwrap!(UtpStream::connect(server_addr));
+    unwrap!(stream.set_linger(Some(Duration::from_millis(300))));
+    let start = Instant::now();
+
+    deaf.store(true, Ordering::SeqCst);
+    unwrap!(stream.write_all(b"hello"));
+    let res = stream.close();
+
+    assert_eq!(unwrap!(res.err()).kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
@@ -0,0 +1,124 @@
+//! Tests for `UtpContext::next_timeout()`. Contexts exchange packets directly, without real UDP
+//! sockets.
+
+extern crate utp;
+#[macro_use]
+extern crate net_literals;
+#[macro_use]
+extern crate unwrap;
+extern crate rand;
+
+mod common;
+
+use common::{run_network, Peer};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use utp::{ManualClock, UtpContext, UtpEvent};
+
+fn is_busy(timeout: Option<Duration>) -> bool {
+    unwrap!(timeout) <= Duration::from_millis(500)
+}
+
+fn destroyed(peer: &Peer) -> usize {
+    peer.events
+        .iter()
+        .filter(|ev| matches!(*ev, UtpEvent::Destroyed(_)))
+        .count()
+}
+
+#[test]
+fn idle_context_needs_no_timeout_checks() {
+    let utp = UtpContext::new(());
+    assert_eq!(utp.next_timeout(), None);
+}
+
+#[test]
+fn pending_connection_needs_frequent_timeout_checks() {
+    let mut client = Peer::new(addr!("1.2.3.4:5000"));
+    let _sock = unwrap!(client.utp.connect(addr!("5.6.7.8:6000")));
+
+    assert_eq!(client.utp.next_timeout(), Some(Duration::from_secs(0)));
+
+    client.utp.check_timeouts();
+    assert!(is_busy(client.utp.next_timeout()));
+}
+
+#[test]
+fn idle_connection_only_needs_keepalive_checks() {
+    let mut client = Peer::new(addr!("1.2.3.4:5000"));
+    let mut server = Peer::new(addr!("5.6.7.8:6000"));
+    let sock = unwrap!(client.utp.connect(server.addr));
+    run_network(&mut client, &mut server);
+    let _ = unwrap!(sock.send(b"hello"));
+    run_network(&mut client, &mut server);
+
+    client.utp.check_timeouts();
+    server.utp.check_timeouts();
+
+    assert!(unwrap!(client.utp.next_timeout()) > Duration::from_secs(10));
+    assert!(unwrap!(server.utp.next_timeout()) > Duration::from_secs(10));
+}
+
+#[test]
+fn checks_ignored_by_libutp_do_not_postpone_next_check() {
+    let clock = ManualClock::new(SystemTime::now());
+    let mut client = Peer::new(addr!("1.2.3.4:5000"));
+    client.utp.set_clock(clock.clone());
+    let _sock = unwrap!(client.utp.connect(addr!("5.6.7.8:6000")));
+    client.utp.check_timeouts();
+
+    clock.advance(Duration::from_millis(300));
+    // too early, libutp skips it
+    client.utp.check_timeouts();
+
+    assert_eq!(client.utp.next_timeout(), Some(Duration::from_millis(200)));
+}
+
+#[test]
+fn unacknowledged_data_needs_frequent_timeout_checks() {
+    let mut client = Peer::new(addr!("1.2.3.4:5000"));
+    let mut server = Peer::new(addr!("5.6.7.8:6000"));
+    let sock = unwrap!(client.utp.connect(server.addr));
+    run_network(&mut client, &mut server);
+
+    let _ = unwrap!(sock.send(b"hello"));
+    // the packet is lost
+    let _ = client.utp.poll_events().count();
+    client.utp.check_timeouts();
+
+    assert!(is_busy(client.utp.next_timeout()));
+}
+
+#[test]
+fn closed_connections_need_timeout_checks_until_destroyed() {
+    let mut client = Peer::new(addr!("1.2.3.4:5000"));
+    let mut server = Peer::new(addr!("5.6.7.8:6000"));
+    let sock = unwrap!(client.utp.connect(server.addr));
+    run_network(&mut client, &mut server);
+
+    drop(sock);
+    // closes accepted socket
+    server.events.clear();
+    run_network(&mut client, &mut server);
+
+    for _ in 0..20 {
+        let timeout = client
+            .utp
+            .next_timeout()
+            .into_iter()
+            .chain(server.utp.next_timeout())
+            .min();
+        if timeout.is_none() {
+            break;
+        }
+        assert!(is_busy(timeout));
+        thread::sleep(unwrap!(timeout));
+        client.utp.check_timeouts();
+        server.utp.check_timeouts();
+        run_network(&mut client, &mut server);
+    }
+
+    assert_eq!(client.utp.next_timeout(), None);
+    assert_eq!(server.utp.next_timeout(), None);
+    assert_eq!((destroyed(&client), destroyed(&server)), (1, 1));
+}
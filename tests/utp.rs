@@ -7,31 +7,64 @@ extern crate mio;
 extern crate mio_extras;
 extern crate rand;
 
+mod common;
+
+use common::random_vec;
 use mio::net::UdpSocket;
 use mio::{Events, Poll, PollOpt, Ready, Token};
 use mio_extras::channel::{channel as async_channel, Sender as AsyncSender};
 use mio_extras::timer::Timer;
-use rand::RngCore;
+use std::cell::Cell;
 use std::io;
-use std::sync::Arc;
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::sync::{mpsc, Arc};
 use std::time::Duration;
-use utp::{UtpCallbackType, UtpContext, UtpError, UtpState};
+use utp::{addr_for_socket, UtpCallbackType, UtpContext, UtpError, UtpState};
 
 mod connect {
     use super::*;
 
     #[test]
     fn client_receives_connected_state() {
+        client_receives_connected_state_with(addr!("127.0.0.1:0"), addr!("0.0.0.0:0"), None);
+    }
+
+    #[test]
+    fn client_receives_connected_state_over_ipv6() {
+        client_receives_connected_state_with(addr!("[::1]:0"), addr!("[::1]:0"), None);
+    }
+
+    #[test]
+    fn ipv4_client_connects_to_dual_stack_server() {
+        client_receives_connected_state_with(
+            addr!("[::]:0"),
+            addr!("127.0.0.1:0"),
+            Some(Ipv4Addr::new(127, 0, 0, 1).into()),
+        );
+    }
+
+    /// Connects client to server bound to given addresses. If `server_ip` is given, client
+    /// connects to this IP instead of the one server is bound to.
+    fn client_receives_connected_state_with(
+        server_bind_addr: SocketAddr,
+        client_bind_addr: SocketAddr,
+        server_ip: Option<IpAddr>,
+    ) {
         const SERVER_SOCKET_TOKEN: Token = Token(0);
         const CLIENT_SOCKET_TOKEN: Token = Token(1);
         const CONNECTED_RX_TOKEN: Token = Token(2);
         let (connected_tx, connected_rx) = async_channel();
 
-        let server_udp_socket = Arc::new(unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"))));
-        let server_addr = unwrap!(server_udp_socket.local_addr());
+        let server_udp_socket = Arc::new(unwrap!(UdpSocket::bind(&server_bind_addr)));
+        let mut server_addr = unwrap!(server_udp_socket.local_addr());
+        if let Some(ip) = server_ip {
+            server_addr.set_ip(ip);
+        }
         let server_utp = make_utp_ctx(Arc::clone(&server_udp_socket), None, None, None);
 
-        let client_udp_socket = Arc::new(unwrap!(UdpSocket::bind(&addr!("0.0.0.0:0"))));
+        let client_udp_socket = Arc::new(unwrap!(UdpSocket::bind(&client_bind_addr)));
         let mut client_utp = make_utp_ctx(
             Arc::clone(&client_udp_socket),
             Some(connected_tx),
@@ -76,12 +109,21 @@ mod connect {
 
     #[test]
     fn two_clients_issueing_connect_are_able_to_connect_with_each_other() {
+        two_clients_connect_with_each_other(addr!("127.0.0.1:0"));
+    }
+
+    #[test]
+    fn two_clients_issueing_connect_are_able_to_connect_with_each_other_over_ipv6() {
+        two_clients_connect_with_each_other(addr!("[::1]:0"));
+    }
+
+    fn two_clients_connect_with_each_other(bind_addr: SocketAddr) {
         const CLIENT1_SOCKET_TOKEN: Token = Token(0);
         const CLIENT2_SOCKET_TOKEN: Token = Token(1);
         const CONNECTED_RX_TOKEN: Token = Token(2);
         let (connected_tx, connected_rx) = async_channel();
 
-        let udp_socket1 = Arc::new(unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"))));
+        let udp_socket1 = Arc::new(unwrap!(UdpSocket::bind(&bind_addr)));
         let addr1 = unwrap!(udp_socket1.local_addr());
         let mut utp1 = make_utp_ctx(
             Arc::clone(&udp_socket1),
@@ -90,7 +132,7 @@ mod connect {
             None,
         );
 
-        let udp_socket2 = Arc::new(unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"))));
+        let udp_socket2 = Arc::new(unwrap!(UdpSocket::bind(&bind_addr)));
         let addr2 = unwrap!(udp_socket2.local_addr());
         let mut utp2 = make_utp_ctx(Arc::clone(&udp_socket2), Some(connected_tx), None, None);
 
@@ -188,7 +230,113 @@ mod connect {
     }
 }
 
+mod lifetime {
+    use super::*;
+
+    #[test]
+    fn socket_outliving_context_is_closed() {
+        let udp_socket = Arc::new(unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"))));
+        let mut utp = make_utp_ctx(udp_socket, None, None, None);
+        let utp_socket = unwrap!(utp.connect(addr!("127.0.0.1:1234")));
+
+        drop(utp);
+
+        assert_eq!(utp_socket.send(b"hello"), Err(UtpError::ContextDestroyed));
+        assert_eq!(
+            utp_socket.shutdown(Shutdown::Both),
+            Err(UtpError::ContextDestroyed)
+        );
+    }
+
+    #[test]
+    fn dropping_context_closes_all_live_sockets() {
+        let udp_socket = Arc::new(unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"))));
+        let mut utp = make_utp_ctx(udp_socket, None, None, None);
+        let sockets: Vec<_> = (0..10)
+            .map(|i| {
+                let mut addr = addr!("127.0.0.1:0");
+                addr.set_port(1234 + i);
+                unwrap!(utp.connect(addr))
+            })
+            .collect();
+
+        drop(utp);
+
+        for sock in &sockets {
+            assert_eq!(sock.send(b"hello"), Err(UtpError::ContextDestroyed));
+            assert_eq!(
+                sock.shutdown(Shutdown::Both),
+                Err(UtpError::ContextDestroyed)
+            );
+        }
+        // sockets are already closed, dropping them now must be a no-op
+        drop(sockets);
+    }
+}
+
+mod panic_safety {
+    use super::*;
+
+    #[test]
+    fn callback_panic_is_resumed_from_the_call_that_triggered_it() {
+        let mut utp = UtpContext::new(());
+        utp.set_callback(
+            UtpCallbackType::Sendto,
+            Box::new(|_| panic!("failed to send packet")),
+        );
+
+        // connect sends SYN packet right away
+        let res = panic::catch_unwind(AssertUnwindSafe(|| utp.connect(addr!("127.0.0.1:1234"))));
+
+        let payload = unwrap!(res.err());
+        assert_eq!(
+            payload.downcast_ref::<&str>(),
+            Some(&"failed to send packet")
+        );
+    }
+
+    #[test]
+    fn context_is_usable_after_callback_panic() {
+        let (packets_tx, packets_rx) = mpsc::channel();
+        let panicked = Rc::new(Cell::new(false));
+        let mut utp = UtpContext::new(());
+        let panicked2 = Rc::clone(&panicked);
+        utp.set_callback(
+            UtpCallbackType::Sendto,
+            Box::new(move |args| {
+                if !panicked2.get() {
+                    panicked2.set(true);
+                    panic!("failed to send packet");
+                }
+                unwrap!(packets_tx.send(args.buf().to_vec()));
+                0
+            }),
+        );
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| utp.connect(addr!("127.0.0.1:1234"))));
+        assert!(res.is_err());
+
+        let _utp_socket = unwrap!(utp.connect(addr!("127.0.0.1:1235")));
+        assert!(packets_rx.try_recv().is_ok());
+    }
+}
+
 fn exchange_data(byte_count: usize) {
+    exchange_data_with(addr!("127.0.0.1:0"), addr!("0.0.0.0:0"), None, byte_count);
+}
+
+fn exchange_data_over_ipv6(byte_count: usize) {
+    exchange_data_with(addr!("[::1]:0"), addr!("[::1]:0"), None, byte_count);
+}
+
+/// Sends random data from client to server bound to given addresses. If `server_ip` is given,
+/// client connects to this IP instead of the one server is bound to.
+fn exchange_data_with(
+    server_bind_addr: SocketAddr,
+    client_bind_addr: SocketAddr,
+    server_ip: Option<IpAddr>,
+    byte_count: usize,
+) {
     const SERVER_SOCKET_TOKEN: Token = Token(0);
     const CLIENT_SOCKET_TOKEN: Token = Token(1);
     const CLIENT_WRITABLE_RX_TOKEN: Token = Token(2);
@@ -197,8 +345,11 @@ fn exchange_data(byte_count: usize) {
     let (writable_tx, writable_rx) = async_channel();
     let (received_data_tx, received_data_rx) = async_channel();
 
-    let server_udp_socket = Arc::new(unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"))));
-    let server_addr = unwrap!(server_udp_socket.local_addr());
+    let server_udp_socket = Arc::new(unwrap!(UdpSocket::bind(&server_bind_addr)));
+    let mut server_addr = unwrap!(server_udp_socket.local_addr());
+    if let Some(ip) = server_ip {
+        server_addr.set_ip(ip);
+    }
     let mut server_utp = make_utp_ctx(
         Arc::clone(&server_udp_socket),
         None,
@@ -206,7 +357,7 @@ fn exchange_data(byte_count: usize) {
         None,
     );
 
-    let client_udp_socket = Arc::new(unwrap!(UdpSocket::bind(&addr!("0.0.0.0:0"))));
+    let client_udp_socket = Arc::new(unwrap!(UdpSocket::bind(&client_bind_addr)));
     let mut client_utp = make_utp_ctx(
         Arc::clone(&client_udp_socket),
         Some(writable_tx),
@@ -298,6 +449,31 @@ fn transfer_data_over_udp_datagram_size() {
     exchange_data(1024 * 1024 * 2); // 2 MB
 }
 
+#[test]
+fn transfer_data_eth_mtu_size_over_ipv6() {
+    exchange_data_over_ipv6(1350);
+}
+
+#[test]
+fn transfer_data_over_eth_mtu_size_over_ipv6() {
+    exchange_data_over_ipv6(4300);
+}
+
+#[test]
+fn transfer_data_over_udp_datagram_size_over_ipv6() {
+    exchange_data_over_ipv6(1024 * 1024 * 2); // 2 MB
+}
+
+#[test]
+fn transfer_data_from_ipv4_client_to_dual_stack_server() {
+    exchange_data_with(
+        addr!("[::]:0"),
+        addr!("127.0.0.1:0"),
+        Some(Ipv4Addr::new(127, 0, 0, 1).into()),
+        1024 * 1024,
+    );
+}
+
 fn handle_udp_packet<T>(sock: &UdpSocket, utp: &UtpContext<T>) {
     // NOTE, if `buf.len()` will be smaller than the packet sent, the rest data will be discarded.
     // Anyway, that shouldn't happen since libutp sends datagrams of ~1400 bytes.
@@ -330,6 +506,7 @@ fn make_utp_ctx(
         Box::new(|args| {
             if let Some(addr) = args.address() {
                 let sock = args.user_data();
+                let addr = addr_for_socket(addr, &unwrap!(sock.local_addr()));
                 sock.send_to(args.buf(), &addr).unwrap();
             }
             0
@@ -348,21 +525,16 @@ fn make_utp_ctx(
     utp.set_callback(
         UtpCallbackType::OnStateChange,
         Box::new(move |args| {
-            if args.state() == UtpState::Connected || args.state() == UtpState::Writable {
-                if let Some(ref tx) = connected_tx {
-                    unwrap!(tx.send(()));
+            match unwrap!(args.state()) {
+                UtpState::Connected | UtpState::Writable => {
+                    if let Some(ref tx) = connected_tx {
+                        unwrap!(tx.send(()));
+                    }
                 }
+                _ => (),
             }
             0
         }),
     );
     utp
 }
-
-#[allow(unsafe_code)]
-pub fn random_vec(size: usize) -> Vec<u8> {
-    let mut ret = Vec::with_capacity(size);
-    unsafe { ret.set_len(size) };
-    rand::thread_rng().fill_bytes(&mut ret[..]);
-    ret
-}